@@ -1,6 +1,15 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 use ts_rs::TS;
 
+pub mod template;
+
+/// Bumped when older clients can't work with the server anymore, they are refused with an error
+/// asking them to update
+/// 2: devices register with a key and start as viewers
+pub const PROTOCOL_VERSION: u32 = 2;
+
 #[derive(TS, Serialize, Deserialize, Clone, Debug)]
 #[ts(export)]
 pub struct ConnectRequest {
@@ -8,6 +17,8 @@ pub struct ConnectRequest {
     pub state_token: String,
     /// The version of the client, used for the version check
     pub client_version: Option<String>,
+    /// The `PROTOCOL_VERSION` the client was built with, missing for clients older than 2
+    pub protocol_version: Option<u32>,
 }
 
 #[derive(TS, Serialize, Deserialize, Clone, Debug)]
@@ -15,6 +26,8 @@ pub struct ConnectRequest {
 pub struct CodeRequest {
     /// The version of the client, used for the version check
     pub client_version: Option<String>,
+    /// The `PROTOCOL_VERSION` the client was built with, missing for clients older than 2
+    pub protocol_version: Option<u32>,
 }
 
 #[derive(TS, Serialize, Deserialize, Clone, Debug)]
@@ -24,6 +37,31 @@ pub struct CodeResponse {
     pub state_token: String,
 }
 
+#[derive(TS, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[ts(export)]
+pub enum AuthProvider {
+    Twitch,
+    Streamlabs,
+}
+
+#[derive(TS, Serialize, Deserialize, Clone, Debug)]
+#[ts(export)]
+pub struct AuthorizeRequest {
+    pub provider: AuthProvider,
+    /// Extra Twitch scopes to ask for on top of the ones already granted, ignored for Streamlabs
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+#[derive(TS, Serialize, Deserialize, Clone, Debug)]
+#[ts(export)]
+pub struct AuthorizeResponse {
+    pub provider: AuthProvider,
+    /// The signed state to open `/twitch/auth/{state}` or `/streamlabs/auth/{state}` with
+    /// It can only be used once and is only valid for a few minutes
+    pub state: String,
+}
+
 #[derive(TS, Serialize, Deserialize, Clone, Debug)]
 #[ts(export)]
 pub struct ConnectResponse {
@@ -33,6 +71,8 @@ pub struct ConnectResponse {
     pub twitch_name: Option<String>,
     pub streamlabs_name: Option<String>,
     pub streamlabs_id: Option<String>,
+    /// The scopes the Twitch user has granted, actions needing other scopes will fail
+    pub twitch_scopes: Vec<String>,
 }
 
 #[derive(TS, Serialize, Deserialize, Clone, Debug)]
@@ -46,6 +86,44 @@ pub struct CustomReward {
     pub global_cooldown_seconds: u32,
 }
 
+#[derive(TS, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[ts(export)]
+pub enum AnnouncementColor {
+    Blue,
+    Green,
+    Orange,
+    Purple,
+    Primary,
+}
+
+/// The permission level of a chatter, taken from their chat badges
+#[derive(TS, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[ts(export)]
+pub enum ChatRole {
+    Everyone,
+    Subscriber,
+    Vip,
+    Moderator,
+    Broadcaster,
+}
+
+#[derive(TS, Serialize, Deserialize, Clone, Debug)]
+#[ts(export)]
+pub struct ChatCommand {
+    /// The name of the command without the prefix, matched case-insensitively
+    pub name: String,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    /// The lowest role that is allowed to use the command
+    pub permission: ChatRole,
+    /// How long a chatter has to wait before using the command again
+    #[serde(default)]
+    pub user_cooldown_seconds: u32,
+    /// How long anyone has to wait after the command was used
+    #[serde(default)]
+    pub global_cooldown_seconds: u32,
+}
+
 #[derive(TS, Serialize, Deserialize, Clone, Debug)]
 #[ts(export)]
 pub enum TwitchTriggerRequest {
@@ -59,6 +137,27 @@ pub enum TwitchTriggerRequest {
         reward_id: String,
         redemption_id: String,
     },
+    /// Fulfill several redemptions of the same reward in one request
+    ChannelPointsFulfillMany {
+        request_id: i32,
+        reward_id: String,
+        redemption_ids: Vec<String>,
+    },
+    /// Cancel (refund) several redemptions of the same reward in one request
+    ChannelPointsCancelMany {
+        request_id: i32,
+        reward_id: String,
+        redemption_ids: Vec<String>,
+    },
+    /// List the unfulfilled redemptions of a reward, answered with a `Redemptions` message
+    GetUnfulfilledRedemptions {
+        request_id: i32,
+        reward_id: String,
+        /// The cursor returned by a previous `Redemptions` message, to fetch the next page
+        after: Option<String>,
+        /// The maximum number of redemptions to return (at most 50)
+        first: Option<u32>,
+    },
     UpdateCustomRewards {
         request_id: i32,
         rewards: Vec<CustomReward>,
@@ -66,6 +165,103 @@ pub enum TwitchTriggerRequest {
     GetCustomRewards {
         request_id: i32,
     },
+    /// Pause or unpause a reward, rapid changes are merged by the server
+    SetRewardPaused {
+        request_id: i32,
+        reward_id: String,
+        is_paused: bool,
+    },
+    /// Enable or disable a reward, rapid changes are merged by the server
+    SetRewardEnabled {
+        request_id: i32,
+        reward_id: String,
+        is_enabled: bool,
+    },
+    /// Set the cost of a reward
+    SetRewardCost {
+        request_id: i32,
+        reward_id: String,
+        cost: u32,
+    },
+    /// Change the cost of a reward relative to its current cost, it won't go below 1
+    AdjustRewardCost {
+        request_id: i32,
+        reward_id: String,
+        delta: i32,
+    },
+    /// Send a chat message as the broadcaster, `{name}` placeholders are filled from `variables`
+    SendChatMessage {
+        request_id: i32,
+        message: String,
+        #[serde(default)]
+        variables: HashMap<String, String>,
+    },
+    /// Reply to a chat message, `{name}` placeholders are filled from `variables`
+    ReplyToMessage {
+        request_id: i32,
+        /// The id of the message being replied to
+        message_id: String,
+        message: String,
+        #[serde(default)]
+        variables: HashMap<String, String>,
+    },
+    /// Send a highlighted announcement, `{name}` placeholders are filled from `variables`
+    SendAnnouncement {
+        request_id: i32,
+        message: String,
+        color: Option<AnnouncementColor>,
+        #[serde(default)]
+        variables: HashMap<String, String>,
+    },
+    /// Give a shoutout to another channel
+    Shoutout {
+        request_id: i32,
+        /// The login name of the channel to shout out
+        to_user: String,
+    },
+    /// Replace the chat commands the server listens for, matching messages are sent as
+    /// `TwitchEventSource::Command` events
+    SetChatCommands {
+        request_id: i32,
+        /// The prefix commands start with, such as `!`
+        prefix: String,
+        commands: Vec<ChatCommand>,
+    },
+    /// Time a user out of chat, needs the `moderator:manage:banned_users` scope
+    TimeoutUser {
+        request_id: i32,
+        user_login: String,
+        /// How long the timeout lasts, from 1 second to 2 weeks
+        duration_seconds: u32,
+        reason: Option<String>,
+    },
+    /// Permanently ban a user from chat, needs the `moderator:manage:banned_users` scope
+    BanUser {
+        request_id: i32,
+        user_login: String,
+        reason: Option<String>,
+    },
+    /// Lift a ban or timeout, needs the `moderator:manage:banned_users` scope
+    UnbanUser {
+        request_id: i32,
+        user_login: String,
+    },
+    /// Delete a single chat message, needs the `moderator:manage:chat_messages` scope
+    DeleteChatMessage {
+        request_id: i32,
+        message_id: String,
+    },
+    /// Turn emote-only mode on or off, needs the `moderator:manage:chat_settings` scope
+    SetEmoteOnly {
+        request_id: i32,
+        enabled: bool,
+    },
+    /// Turn slow mode on with the given wait time of 3 to 120 seconds, or off with None, needs the
+    /// `moderator:manage:chat_settings` scope
+    SetSlowMode {
+        request_id: i32,
+        wait_seconds: Option<u32>,
+    },
 }
 
 #[derive(TS, Serialize, Deserialize, Clone, Debug)]
@@ -80,6 +276,17 @@ pub struct CustomRewardResponse {
     pub global_cooldown_seconds: u32,
 }
 
+#[derive(TS, Serialize, Deserialize, Clone, Debug)]
+#[ts(export)]
+pub struct RedemptionResponse {
+    pub id: String,
+    pub reward_id: String,
+    pub user_id: String,
+    pub user_name: String,
+    pub user_input: String,
+    pub redeemed_at: String,
+}
+
 #[derive(TS, Serialize, Deserialize, Clone, Debug)]
 #[ts(export)]
 pub struct Notify {
@@ -108,6 +315,8 @@ pub enum TwitchEventSource {
     ChannelPoints {
         reward_id: String,
         reward_name: String,
+        redemption_id: String,
+        user_input: String,
     },
     BitDonation {
         amount: u32,
@@ -121,9 +330,80 @@ pub enum TwitchEventSource {
     Message {
         sender: String,
         message: String,
+        message_id: String,
+        /// The highest role of the chatter, taken from their badges
+        role: ChatRole,
+        badges: Vec<ChatBadge>,
+        /// The chatter's name color as a hex string, if they have set one
+        color: Option<String>,
+        /// The message split into text, emotes, cheermotes and mentions
+        fragments: Vec<ChatFragment>,
+        /// The amount of bits cheered in the message, if any
+        cheer: Option<u32>,
+        /// The message this one is replying to, if any, boxed as most messages aren't replies
+        reply: Option<Box<ChatReply>>,
+    },
+    /// A chat message that matched one of the configured chat commands
+    Command {
+        /// The name of the matched command, even if an alias was used
+        name: String,
+        args: Vec<String>,
+        role: ChatRole,
+    },
+}
+
+#[derive(TS, Serialize, Deserialize, Clone, Debug)]
+#[ts(export)]
+pub struct ChatBadge {
+    /// The badge set, such as `subscriber` or `moderator`
+    pub set_id: String,
+    /// The version of the badge within the set
+    pub id: String,
+    /// Extra badge information, for subscriber badges this is the number of months subscribed
+    pub info: String,
+}
+
+#[derive(TS, Serialize, Deserialize, Clone, Debug)]
+#[ts(export)]
+#[serde(tag = "type")]
+pub enum ChatFragment {
+    Text {
+        text: String,
+    },
+    Emote {
+        text: String,
+        id: String,
+        emote_set_id: String,
+        owner_id: String,
+        /// The available formats, `static` and/or `animated`
+        format: Vec<String>,
+    },
+    Cheermote {
+        text: String,
+        prefix: String,
+        bits: i32,
+        tier: i32,
+    },
+    Mention {
+        text: String,
+        user_id: String,
+        user_name: String,
+        user_login: String,
     },
 }
 
+#[derive(TS, Serialize, Deserialize, Clone, Debug)]
+#[ts(export)]
+pub struct ChatReply {
+    pub parent_message_id: String,
+    pub parent_message_body: String,
+    pub parent_user_id: String,
+    pub parent_user_name: String,
+    pub parent_user_login: String,
+    /// The first message of the reply thread
+    pub thread_message_id: String,
+}
+
 #[derive(TS, Serialize, Deserialize, Clone, Debug)]
 #[ts(export)]
 pub struct StreamLabsEvent {
@@ -149,6 +429,54 @@ pub struct ErrorMessage {
     pub message: String,
 }
 
+#[derive(TS, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[ts(export)]
+pub enum DeviceRole {
+    /// Can do everything, including fulfilling and cancelling redemptions
+    Controller,
+    /// Receives events but can't change anything on Twitch, every device starts as one
+    #[default]
+    Viewer,
+}
+
+#[derive(TS, Serialize, Deserialize, Clone, Debug)]
+#[ts(export)]
+pub struct RegisterDevice {
+    /// A name for the device, shown to the other devices sharing the state token
+    pub name: String,
+    /// Controller is only granted to the first controller of a state token, the others have to be
+    /// made one by an existing controller
+    pub role: DeviceRole,
+    /// A random secret kept by the client, so it keeps its id and role when it reconnects
+    pub key: String,
+}
+
+/// Change the role of a device sharing the state token, only controllers can send this
+#[derive(TS, Serialize, Deserialize, Clone, Debug)]
+#[ts(export)]
+pub struct SetDeviceRole {
+    pub device_id: String,
+    pub role: DeviceRole,
+}
+
+#[derive(TS, Serialize, Deserialize, Clone, Debug)]
+#[ts(export)]
+pub struct DeviceInfo {
+    /// Unique for every connection, a device reconnecting gets a new one
+    pub id: String,
+    pub name: String,
+    pub role: DeviceRole,
+}
+
+#[derive(TS, Serialize, Deserialize, Clone, Debug)]
+#[ts(export)]
+pub struct DeviceList {
+    /// The id of the device receiving the list
+    pub own_id: String,
+    /// Every device sharing the state token on this server
+    pub devices: Vec<DeviceInfo>,
+}
+
 #[derive(TS, Serialize, Deserialize, Clone, Debug)]
 #[ts(export)]
 pub struct TaskResponse {
@@ -163,13 +491,21 @@ pub struct TaskResponse {
 pub enum ServerMessage {
     ConnectResponse(ConnectResponse),
     CodeResponse(CodeResponse),
+    AuthorizeResponse(AuthorizeResponse),
     CustomRewards { rewards: Vec<CustomRewardResponse> },
+    Redemptions {
+        reward_id: String,
+        redemptions: Vec<RedemptionResponse>,
+        /// Cursor for the next page, if there are more redemptions
+        cursor: Option<String>,
+    },
     Notify(Notify),
     ChangeAvatar(ChangeAvatar),
     TwitchEvent(TwitchEvent),
     StreamLabsEvent(StreamLabsEvents),
     Error(ErrorMessage),
     TaskResponse(TaskResponse),
+    Devices(DeviceList),
 }
 
 #[derive(TS, Serialize, Deserialize, Clone, Debug)]
@@ -178,5 +514,8 @@ pub enum ServerMessage {
 pub enum ClientMessage {
     Connect(ConnectRequest),
     CodeRequest(CodeRequest),
+    Authorize(AuthorizeRequest),
     TwitchTrigger(TwitchTriggerRequest),
+    RegisterDevice(RegisterDevice),
+    SetDeviceRole(SetDeviceRole),
 }
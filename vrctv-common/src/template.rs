@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+
+/// Fill in a message template such as "Thanks {user} for {bits} bits!" from the trigger context
+///
+/// `{name}` is replaced by the value of `name`, placeholders without a value are kept as-is so
+/// mistakes are visible in the output. Use `{{` and `}}` for literal braces.
+pub fn render(template: &str, variables: &HashMap<String, String>) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                output.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                output.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                let mut closed = false;
+
+                for c in chars.by_ref() {
+                    if c == '}' {
+                        closed = true;
+                        break;
+                    }
+                    name.push(c);
+                }
+
+                match variables.get(name.trim()) {
+                    Some(value) if closed => output.push_str(value),
+                    _ => {
+                        output.push('{');
+                        output.push_str(&name);
+                        if closed {
+                            output.push('}');
+                        }
+                    }
+                }
+            }
+            c => output.push(c),
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables() -> HashMap<String, String> {
+        HashMap::from([
+            ("user".to_string(), "Alice".to_string()),
+            ("bits".to_string(), "100".to_string()),
+        ])
+    }
+
+    #[test]
+    fn replaces_placeholders() {
+        assert_eq!(
+            render("Thanks {user} for {bits} bits!", &variables()),
+            "Thanks Alice for 100 bits!"
+        );
+    }
+
+    #[test]
+    fn trims_placeholder_names() {
+        assert_eq!(render("Hi { user }", &variables()), "Hi Alice");
+    }
+
+    #[test]
+    fn keeps_unknown_placeholders() {
+        assert_eq!(render("Hi {viewer}", &variables()), "Hi {viewer}");
+        assert_eq!(render("Hi { viewer }", &variables()), "Hi { viewer }");
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        assert_eq!(render("{{user}}", &variables()), "{user}");
+        assert_eq!(render("{{{user}}}", &variables()), "{Alice}");
+        assert_eq!(render("a }} b", &variables()), "a } b");
+    }
+
+    #[test]
+    fn keeps_unclosed_braces() {
+        assert_eq!(render("Hi {user", &variables()), "Hi {user");
+        assert_eq!(render("Hi {", &variables()), "Hi {");
+    }
+
+    #[test]
+    fn keeps_single_closing_brace() {
+        assert_eq!(render("a } b", &variables()), "a } b");
+    }
+
+    #[test]
+    fn keeps_multibyte_text() {
+        let variables = HashMap::from([("emoji".to_string(), "🎉".to_string())]);
+        assert_eq!(render("Yay {emoji} ünïcode", &variables), "Yay 🎉 ünïcode");
+    }
+}
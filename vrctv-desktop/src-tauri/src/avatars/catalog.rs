@@ -0,0 +1,286 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use glob::glob;
+use log::{error, info, warn};
+use notify::RecursiveMode;
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tauri::{AppHandle, Manager};
+use tauri_specta::Event;
+use tokio::sync::{mpsc, Notify};
+
+use crate::{
+    avatars::{config::AvatarConfig, Avatar},
+    vrchat_dir::osc_dir,
+};
+
+/// How long the OSC folder has to be quiet before it is scanned, VRChat writes a config in
+/// several steps
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+/// How often a missing OSC folder is looked for, VRChat creates it once OSC is enabled
+const MISSING_FOLDER_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Sent when VRChat wrote, changed or removed an avatar config
+#[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
+pub struct AvatarCatalogChangedEvent {
+    pub avatars: Vec<Avatar>,
+}
+
+struct CatalogFile {
+    modified: SystemTime,
+    config: AvatarConfig,
+}
+
+#[derive(Default)]
+struct CatalogState {
+    /// Set after the first scan, until then lookups scan first
+    scanned: bool,
+    /// Why the last scan failed, returned instead of an empty list
+    error: Option<String>,
+    files: HashMap<PathBuf, CatalogFile>,
+    /// Avatar id to the path of its config, an avatar used by several accounts has one per account
+    by_id: HashMap<String, PathBuf>,
+    /// Configs that couldn't be read and when they were written, read again once VRChat rewrites
+    /// them
+    failed: HashMap<PathBuf, SystemTime>,
+}
+
+/// Every avatar config in VRChat's OSC folder, kept in memory
+#[derive(Default)]
+pub struct AvatarCatalog {
+    state: RwLock<CatalogState>,
+    /// Held for a whole scan, so the configs can be read without blocking lookups
+    scanning: Mutex<()>,
+    /// Wakes the watcher to look at the folder again
+    folder_changed: Notify,
+}
+
+impl AvatarCatalog {
+    /// Bring the catalogue up to date with the OSC folder, returning whether anything changed
+    /// This reads files, use `scan_catalog` from async code
+    pub fn scan(&self, app: &AppHandle) -> bool {
+        let _scanning = self.scanning.lock().unwrap();
+
+        let dir = match osc_dir(app) {
+            Ok(dir) => dir,
+            Err(e) => {
+                let mut state = self.state.write().unwrap();
+                let e = e.to_string();
+                if state.error.as_ref() != Some(&e) {
+                    warn!("Avatar catalogue unavailable: {}", e);
+                }
+                let changed = !state.files.is_empty();
+                *state = CatalogState {
+                    scanned: true,
+                    error: Some(e),
+                    ..Default::default()
+                };
+                return changed;
+            }
+        };
+
+        let pattern = format!(
+            "{}/*/Avatars/*.json",
+            glob::Pattern::escape(&dir.to_string_lossy())
+        );
+        let paths = match glob(&pattern) {
+            Ok(paths) => paths.filter_map(Result::ok).collect::<Vec<_>>(),
+            Err(e) => {
+                error!("Invalid avatar config pattern {}: {}", pattern, e);
+                return false;
+            }
+        };
+
+        // Only scans change the files, and they are serialised, so this stays current
+        let (known, failed) = {
+            let state = self.state.read().unwrap();
+            let known = state
+                .files
+                .iter()
+                .map(|(path, file)| (path.clone(), file.modified))
+                .collect::<HashMap<_, _>>();
+            (known, state.failed.clone())
+        };
+
+        let mut unchanged = Vec::new();
+        let mut loaded = HashMap::new();
+        let mut still_failed = HashMap::new();
+        for path in paths {
+            let modified = fs::metadata(&path)
+                .and_then(|m| m.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+
+            if known.get(&path) == Some(&modified) {
+                unchanged.push(path);
+            } else if failed.get(&path) == Some(&modified) {
+                still_failed.insert(path, modified);
+            } else {
+                match AvatarConfig::read(&path) {
+                    Ok(config) => {
+                        info!("Loaded avatar config for {} ({})", config.name, config.id);
+                        loaded.insert(path, CatalogFile { modified, config });
+                    }
+                    // VRChat may still be writing it, it is read again once it is written
+                    Err(e) => {
+                        warn!("Skipping avatar config: {:#}", e);
+                        still_failed.insert(path, modified);
+                    }
+                }
+            }
+        }
+
+        let mut state = self.state.write().unwrap();
+        let mut changed = !loaded.is_empty();
+        state.scanned = true;
+        state.error = None;
+        state.failed = still_failed;
+
+        let mut files = loaded;
+        for path in unchanged {
+            if let Some(file) = state.files.remove(&path) {
+                files.insert(path, file);
+            }
+        }
+        // Whatever wasn't moved over was removed
+        changed |= !state.files.is_empty();
+
+        let mut by_id: HashMap<String, PathBuf> = HashMap::new();
+        for (path, file) in &files {
+            // Prefer the most recently written config when several accounts have the avatar
+            let newer = by_id
+                .get(&file.config.id)
+                .is_none_or(|other| files[other].modified < file.modified);
+            if newer {
+                by_id.insert(file.config.id.clone(), path.clone());
+            }
+        }
+
+        state.files = files;
+        state.by_id = by_id;
+        changed
+    }
+
+    /// Look at the OSC folder again, after the user picked another VRChat folder
+    pub fn folder_changed(&self) {
+        self.folder_changed.notify_one();
+    }
+
+    pub fn avatars(&self) -> Result<Vec<Avatar>, String> {
+        Ok(self
+            .configs()?
+            .into_iter()
+            .map(|config| Avatar {
+                id: config.id,
+                name: config.name,
+            })
+            .collect())
+    }
+
+    /// Every avatar once, sorted by name
+    pub fn configs(&self) -> Result<Vec<AvatarConfig>, String> {
+        let state = self.state.read().unwrap();
+        if let Some(e) = &state.error {
+            return Err(e.clone());
+        }
+
+        let mut configs = state
+            .by_id
+            .values()
+            .map(|path| state.files[path].config.clone())
+            .collect::<Vec<_>>();
+        configs.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.id.cmp(&b.id)));
+        Ok(configs)
+    }
+
+    pub fn config(&self, id: &str) -> Option<AvatarConfig> {
+        let state = self.state.read().unwrap();
+        state
+            .by_id
+            .get(id)
+            .map(|path| state.files[path].config.clone())
+    }
+}
+
+/// Scan on a blocking thread, telling the frontend if anything changed
+pub async fn scan_catalog(app: &AppHandle) {
+    let scan_app = app.clone();
+    let changed = tauri::async_runtime::spawn_blocking(move || {
+        scan_app.state::<AvatarCatalog>().scan(&scan_app)
+    })
+    .await
+    .unwrap_or_else(|e| {
+        error!("Avatar catalogue scan failed: {}", e);
+        false
+    });
+
+    if changed {
+        AvatarCatalogChangedEvent {
+            avatars: app.state::<AvatarCatalog>().avatars().unwrap_or_default(),
+        }
+        .emit(app)
+        .unwrap_or_else(|e| {
+            error!("Failed to emit avatar catalogue event: {}", e);
+        });
+    }
+}
+
+/// Scan unless the watcher already did, for lookups made right after starting
+pub async fn ensure_scanned(app: &AppHandle) {
+    if !app.state::<AvatarCatalog>().state.read().unwrap().scanned {
+        scan_catalog(app).await;
+    }
+}
+
+/// Keep the catalogue current, telling the frontend whenever VRChat writes a config
+pub async fn watch_avatar_configs(app: AppHandle) {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut debouncer = match new_debouncer(WATCH_DEBOUNCE, move |res: DebounceEventResult| {
+        let _ = tx.send(res);
+    }) {
+        Ok(debouncer) => debouncer,
+        Err(e) => {
+            error!("Failed to start watching avatar configs: {}", e);
+            return;
+        }
+    };
+    let catalog = app.state::<AvatarCatalog>();
+    let mut watched: Option<PathBuf> = None;
+
+    loop {
+        // Follow the folder when the user picks another one or VRChat creates it
+        let dir = osc_dir(&app).ok();
+        if dir != watched {
+            if let Some(old) = watched.take() {
+                let _ = debouncer.watcher().unwatch(&old);
+            }
+            if let Some(dir) = dir {
+                match debouncer.watcher().watch(&dir, RecursiveMode::Recursive) {
+                    Ok(()) => {
+                        info!("Watching {:?} for avatar configs", dir);
+                        watched = Some(dir);
+                    }
+                    Err(e) => error!("Failed to watch {:?}: {}", dir, e),
+                }
+            }
+        }
+
+        scan_catalog(&app).await;
+
+        tokio::select! {
+            Some(res) = rx.recv() => {
+                if let Err(e) = res {
+                    warn!("Error watching avatar configs: {}", e);
+                }
+            }
+            _ = catalog.folder_changed.notified() => {}
+            _ = tokio::time::sleep(MISSING_FOLDER_INTERVAL), if watched.is_none() => {}
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+
+use crate::OscValueType;
+
+/// The OSC config VRChat writes for every avatar, in `OSC/<user id>/Avatars/<avatar id>.json`
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+pub struct AvatarConfig {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub parameters: Vec<AvatarParameter>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+pub struct AvatarParameter {
+    pub name: String,
+    /// Where VRChat listens for the parameter, missing for parameters that can't be set over OSC
+    #[serde(default)]
+    pub input: Option<ParameterEndpoint>,
+    /// Where VRChat sends the parameter when it changes
+    #[serde(default)]
+    pub output: Option<ParameterEndpoint>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+pub struct ParameterEndpoint {
+    pub address: String,
+    #[serde(rename = "type")]
+    pub value_type: OscValueType,
+}
+
+impl AvatarConfig {
+    /// VRChat writes the file as UTF-8 with a byte order mark, which serde_json refuses
+    pub fn parse(content: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(content.trim_start_matches('\u{feff}'))
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let content =
+            fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+        Self::parse(&content).with_context(|| format!("Invalid avatar config {:?}", path))
+    }
+
+    /// The type VRChat accepts at an address, None if it doesn't listen there
+    pub fn input_type(&self, address: &str) -> Option<OscValueType> {
+        self.parameters
+            .iter()
+            .filter_map(|p| p.input.as_ref())
+            .find(|input| input.address == address)
+            .map(|input| input.value_type)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Trimmed down from a config VRChat wrote, including its byte order mark
+    const CONFIG: &str = concat!(
+        "\u{feff}",
+        r#"{
+  "id": "avtr_0b3f64cd-7a43-4b2e-9c1c-5a3e1f0d2a11",
+  "name": "Fox: Winter Edition",
+  "parameters": [
+    {
+      "name": "Outfit",
+      "input": { "address": "/avatar/parameters/Outfit", "type": "Int" },
+      "output": { "address": "/avatar/parameters/Outfit", "type": "Int" }
+    },
+    {
+      "name": "Hue",
+      "input": { "address": "/avatar/parameters/Hue", "type": "Float" },
+      "output": { "address": "/avatar/parameters/Hue", "type": "Float" }
+    },
+    {
+      "name": "VelocityZ",
+      "output": { "address": "/avatar/parameters/VelocityZ", "type": "Float" }
+    },
+    {
+      "name": "Hat",
+      "input": { "address": "/avatar/parameters/Hat", "type": "Bool" }
+    }
+  ]
+}"#
+    );
+
+    #[test]
+    fn parses_a_vrchat_config() {
+        let config = AvatarConfig::parse(CONFIG).unwrap();
+        assert_eq!(config.id, "avtr_0b3f64cd-7a43-4b2e-9c1c-5a3e1f0d2a11");
+        assert_eq!(config.name, "Fox: Winter Edition");
+        assert_eq!(config.parameters.len(), 4);
+    }
+
+    #[test]
+    fn parses_configs_without_a_byte_order_mark() {
+        let config = AvatarConfig::parse(CONFIG.trim_start_matches('\u{feff}')).unwrap();
+        assert_eq!(config.name, "Fox: Winter Edition");
+    }
+
+    #[test]
+    fn reads_every_parameter_type() {
+        let config = AvatarConfig::parse(CONFIG).unwrap();
+        let types = config
+            .parameters
+            .iter()
+            .map(|p| p.input.as_ref().or(p.output.as_ref()).map(|e| e.value_type))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            vec![
+                Some(OscValueType::Int),
+                Some(OscValueType::Float),
+                Some(OscValueType::Float),
+                Some(OscValueType::Bool),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_parameters_with_only_one_endpoint() {
+        let config = AvatarConfig::parse(CONFIG).unwrap();
+
+        let velocity = &config.parameters[2];
+        assert!(velocity.input.is_none());
+        assert_eq!(
+            velocity.output.as_ref().unwrap().address,
+            "/avatar/parameters/VelocityZ"
+        );
+
+        let hat = &config.parameters[3];
+        assert!(hat.output.is_none());
+        assert_eq!(
+            hat.input.as_ref().unwrap().address,
+            "/avatar/parameters/Hat"
+        );
+    }
+
+    #[test]
+    fn looks_up_input_types() {
+        let config = AvatarConfig::parse(CONFIG).unwrap();
+        assert_eq!(
+            config.input_type("/avatar/parameters/Hat"),
+            Some(OscValueType::Bool)
+        );
+        // Output only parameters can't be set
+        assert_eq!(config.input_type("/avatar/parameters/VelocityZ"), None);
+        assert_eq!(config.input_type("/avatar/parameters/Missing"), None);
+    }
+
+    #[test]
+    fn parses_configs_without_parameters() {
+        let config = AvatarConfig::parse(r#"{"id": "avtr_1", "name": "Empty"}"#).unwrap();
+        assert!(config.parameters.is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_types() {
+        let config = r#"{"id": "avtr_1", "name": "Odd", "parameters": [
+            {"name": "A", "input": {"address": "/avatar/parameters/A", "type": "Vector"}}
+        ]}"#;
+        assert!(AvatarConfig::parse(config).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use std::sync::RwLock;
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tauri::{AppHandle, Manager};
+use tauri_specta::Event;
+
+use crate::{
+    avatars::{
+        catalog::{ensure_scanned, scan_catalog, AvatarCatalog},
+        config::AvatarConfig,
+    },
+    osc::{animation::forget_animations, effects::forget_effects},
+    OscValueType,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+pub struct WornAvatar {
+    pub id: String,
+    /// None if VRChat hasn't written an OSC config for the avatar
+    pub config: Option<AvatarConfig>,
+}
+
+/// Sent when VRChat reports a different avatar than the one worn before
+#[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
+pub struct AvatarChangedEvent {
+    pub avatar: WornAvatar,
+    /// None for the first avatar seen since starting
+    pub previous_id: Option<String>,
+}
+
+/// The id of the avatar VRChat last reported
+#[derive(Default)]
+pub struct CurrentAvatar(RwLock<Option<String>>);
+
+impl CurrentAvatar {
+    pub fn id(&self) -> Option<String> {
+        self.0.read().unwrap().clone()
+    }
+}
+
+/// The type the worn avatar's config declares for an address, from the configs already scanned
+pub fn current_input_type(app: &AppHandle, address: &str) -> Option<OscValueType> {
+    let id = app.state::<CurrentAvatar>().id()?;
+    app.state::<AvatarCatalog>()
+        .config(&id)?
+        .input_type(address)
+}
+
+/// Record the avatar VRChat reported, telling the frontend if it is a different one
+pub fn avatar_changed(app: &AppHandle, id: &str) {
+    let state = app.state::<CurrentAvatar>();
+    let previous_id = {
+        let mut current = state.0.write().unwrap();
+        if current.as_deref() == Some(id) {
+            return;
+        }
+        current.replace(id.to_string())
+    };
+
+    if previous_id.is_some() {
+        forget_effects(app);
+        forget_animations(app);
+    }
+
+    // Looking up the config may scan the OSC folder, which isn't done on the OSC thread
+    let app = app.clone();
+    let id = id.to_string();
+    tauri::async_runtime::spawn(async move {
+        let avatar = worn_avatar(&app, &id).await;
+        if app.state::<CurrentAvatar>().id().as_deref() != Some(id.as_str()) {
+            // Another avatar was put on while scanning, it is announced on its own
+            return;
+        }
+
+        info!(
+            "Avatar changed to {} ({})",
+            avatar
+                .config
+                .as_ref()
+                .map(|c| c.name.as_str())
+                .unwrap_or("unknown"),
+            id
+        );
+
+        AvatarChangedEvent {
+            avatar,
+            previous_id,
+        }
+        .emit(&app)
+        .unwrap_or_else(|e| {
+            error!("Failed to emit avatar changed event: {}", e);
+        });
+    });
+}
+
+async fn worn_avatar(app: &AppHandle, id: &str) -> WornAvatar {
+    ensure_scanned(app).await;
+
+    // VRChat writes the config of an avatar worn for the first time as it loads it, so it may
+    // not have been picked up yet
+    let mut config = app.state::<AvatarCatalog>().config(id);
+    if config.is_none() {
+        scan_catalog(app).await;
+        config = app.state::<AvatarCatalog>().config(id);
+    }
+
+    WornAvatar {
+        id: id.to_string(),
+        config,
+    }
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn get_current_avatar(app: AppHandle) -> Result<Option<WornAvatar>, String> {
+    match app.state::<CurrentAvatar>().id() {
+        Some(id) => Ok(Some(worn_avatar(&app, &id).await)),
+        None => Ok(None),
+    }
+}
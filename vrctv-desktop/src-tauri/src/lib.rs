@@ -1,11 +1,12 @@
-use std::{env, time::Duration};
+use std::{env, sync::Arc, time::Duration};
 
 use log::{error, LevelFilter};
 use serde::{Deserialize, Serialize};
 use specta::Type;
 #[cfg(debug_assertions)]
 use specta_typescript::Typescript;
-use tauri::Manager;
+use tauri::{AppHandle, Manager, RunEvent};
+use tauri_plugin_deep_link::DeepLinkExt;
 use tauri_specta::{collect_commands, collect_events, Builder, Event};
 use tokio::{
     sync::{broadcast, mpsc, watch},
@@ -14,24 +15,50 @@ use tokio::{
 use vrctv_overlay::start_server;
 
 use crate::{
-    avatars::{change_avatar, fetch_avatar_osc, fetch_avatars, set_osc, set_warudo_osc},
-    osc::osc_message_broadcaster,
+    avatars::{
+        catalog::{watch_avatar_configs, AvatarCatalog, AvatarCatalogChangedEvent},
+        change_avatar,
+        current::{get_current_avatar, AvatarChangedEvent, CurrentAvatar},
+        fetch_avatar_config, fetch_avatar_configs, fetch_avatar_osc, fetch_avatars, set_osc,
+        set_warudo_osc,
+    },
+    osc::{
+        animation::{
+            get_osc_animations, start_osc_animation, stop_animations, stop_osc_animation,
+            stop_osc_animations, OscAnimationEndedEvent, OscAnimations,
+        },
+        cache::{get_osc_parameter, get_osc_snapshot, OscCache, OscSnapshotEvent},
+        chatbox::{chatbox_sender, clear_chatbox, send_chatbox},
+        effects::{
+            cancel_osc_effect, cancel_osc_effects, end_effects, get_osc_effects, start_osc_effect,
+            OscEffectEndedEvent, OscEffects,
+        },
+        input::{
+            run_input_sequence, stop_input_sequence, stop_sequence, InputSequenceEndedEvent,
+            OscInputs,
+        },
+        osc_message_broadcaster,
+    },
     overlay::{send_overlay_command, update_overlays},
+    vrchat_dir::{get_osc_dir, set_vrchat_dir, VrchatDirOverride},
     xsoverlay::{send_notification, xsoverlay_notifier},
 };
 
 mod avatars;
 mod osc;
 mod overlay;
+mod vrchat_dir;
 mod xsoverlay;
 
 #[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
 pub struct OscChangeEvent {
     pub address: String,
     pub value: OscValue,
+    /// The cached value before the change, None if it wasn't known
+    pub previous: Option<OscValue>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Type)]
 pub enum OscValue {
     Int(i32),
     Float(f32),
@@ -39,6 +66,59 @@ pub enum OscValue {
     Bool(bool),
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Type)]
+pub enum OscValueType {
+    Int,
+    Float,
+    String,
+    Bool,
+}
+
+impl OscValue {
+    pub fn value_type(&self) -> OscValueType {
+        match self {
+            OscValue::Int(_) => OscValueType::Int,
+            OscValue::Float(_) => OscValueType::Float,
+            OscValue::String(_) => OscValueType::String,
+            OscValue::Bool(_) => OscValueType::Bool,
+        }
+    }
+}
+
+impl OscValueType {
+    /// Parse a value typed by the user, rejecting anything VRChat can't store in this type
+    /// Int parameters hold 0 to 255 and Float parameters -1 to 1
+    pub fn parse(self, value: &str) -> Result<OscValue, String> {
+        let value = value.trim();
+        match self {
+            OscValueType::Int => {
+                let int = value
+                    .parse::<i32>()
+                    .map_err(|_| format!("{} is not a whole number", value))?;
+                if !(0..=255).contains(&int) {
+                    return Err(format!("{} is outside of 0 to 255", int));
+                }
+                Ok(OscValue::Int(int))
+            }
+            OscValueType::Float => {
+                let float = value
+                    .parse::<f32>()
+                    .map_err(|_| format!("{} is not a number", value))?;
+                if !(-1.0..=1.0).contains(&float) {
+                    return Err(format!("{} is outside of -1 to 1", float));
+                }
+                Ok(OscValue::Float(float))
+            }
+            OscValueType::Bool => match value.to_ascii_lowercase().as_str() {
+                "true" | "1" => Ok(OscValue::Bool(true)),
+                "false" | "0" => Ok(OscValue::Bool(false)),
+                _ => Err(format!("{} is not true or false", value)),
+            },
+            OscValueType::String => Ok(OscValue::String(value.to_string())),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
 pub struct ServiceStatusEvent {
     pub service: Service,
@@ -58,19 +138,55 @@ pub enum ServiceStatus {
     Error(String),
 }
 
+/// Bring the main window to the front, used when the app is opened through a deep link
+fn focus_main_window(app: &AppHandle) {
+    if let Some(window) = app.get_webview_window("main") {
+        let _ = window.unminimize();
+        let _ = window.set_focus();
+    }
+}
+
 pub fn setup_builder() -> Builder {
     let builder = Builder::<tauri::Wry>::new()
         .commands(collect_commands![
             fetch_avatars,
             fetch_avatar_osc,
+            fetch_avatar_config,
+            fetch_avatar_configs,
+            get_current_avatar,
             change_avatar,
             set_osc,
             set_warudo_osc,
+            get_osc_parameter,
+            get_osc_snapshot,
+            start_osc_effect,
+            cancel_osc_effect,
+            cancel_osc_effects,
+            get_osc_effects,
+            start_osc_animation,
+            stop_osc_animation,
+            stop_osc_animations,
+            get_osc_animations,
+            send_chatbox,
+            clear_chatbox,
+            run_input_sequence,
+            stop_input_sequence,
             send_notification,
             send_overlay_command,
             update_overlays,
+            set_vrchat_dir,
+            get_osc_dir,
         ])
-        .events(collect_events![OscChangeEvent, ServiceStatusEvent]);
+        .events(collect_events![
+            OscChangeEvent,
+            OscSnapshotEvent,
+            OscEffectEndedEvent,
+            OscAnimationEndedEvent,
+            InputSequenceEndedEvent,
+            ServiceStatusEvent,
+            AvatarCatalogChangedEvent,
+            AvatarChangedEvent
+        ]);
 
     #[cfg(debug_assertions)]
     builder
@@ -85,6 +201,11 @@ pub fn run() {
     let builder = setup_builder();
 
     tauri::Builder::default()
+        // Opening a vrctv:// link starts a second instance, focus the running one instead
+        .plugin(tauri_plugin_single_instance::init(|app, _args, _cwd| {
+            focus_main_window(app);
+        }))
+        .plugin(tauri_plugin_deep_link::init())
         .plugin(tauri_plugin_fs::init())
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_websocket::init())
@@ -109,6 +230,17 @@ pub fn run() {
             // This is also required if you want to use events
             builder.mount_events(app);
 
+            // Installers register the scheme, this covers development builds
+            #[cfg(any(windows, target_os = "linux"))]
+            if let Err(e) = app.deep_link().register_all() {
+                error!("Failed to register deep link schemes: {}", e);
+            }
+
+            let deep_link_handle = app.handle().clone();
+            app.deep_link().on_open_url(move |_| {
+                focus_main_window(&deep_link_handle);
+            });
+
             let (tx, _) = broadcast::channel(16);
             let (watch_tx, watch_rx) = watch::channel(Vec::new());
             let state = vrctv_overlay::AppState {
@@ -117,6 +249,19 @@ pub fn run() {
 
             app.manage(watch_tx);
             app.manage(tx.clone());
+            app.manage(Arc::new(OscCache::default()));
+            app.manage(OscEffects::default());
+            app.manage(OscAnimations::default());
+            app.manage(OscInputs::default());
+            app.manage(VrchatDirOverride::default());
+            app.manage(AvatarCatalog::default());
+            app.manage(CurrentAvatar::default());
+
+            tauri::async_runtime::spawn(watch_avatar_configs(app.handle().clone()));
+
+            let (chatbox_tx, chatbox_rx) = mpsc::unbounded_channel();
+            tauri::async_runtime::spawn(chatbox_sender(app.handle().clone(), chatbox_rx));
+            app.manage(chatbox_tx);
 
             let overlay_handle = app.handle().clone();
             tauri::async_runtime::spawn(async move {
@@ -168,27 +313,26 @@ pub fn run() {
             tauri::async_runtime::spawn(async move {
                 tokio::time::sleep(Duration::from_secs(1)).await;
 
-                let osc_listener =
-                    match osc::setup_osc_listener(vec!["/avatar/change".into()]).await {
-                        Ok(listener) => listener,
-                        Err(e) => {
-                            error!("Failed to set up OSC listener: {}", e);
-
-                            ServiceStatusEvent {
-                                service: Service::Osc,
-                                status: ServiceStatus::Error(format!(
-                                    "Failed to set up OSC listener: {}",
-                                    e
-                                )),
-                            }
-                            .emit(&osc_handle)
-                            .unwrap_or_else(|e| {
-                                error!("Failed to emit service status event: {}", e);
-                            });
+                let osc_listener = match osc::setup_osc_listener(osc_handle.clone()).await {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        error!("Failed to set up OSC listener: {}", e);
 
-                            return;
+                        ServiceStatusEvent {
+                            service: Service::Osc,
+                            status: ServiceStatus::Error(format!(
+                                "Failed to set up OSC listener: {}",
+                                e
+                            )),
                         }
-                    };
+                        .emit(&osc_handle)
+                        .unwrap_or_else(|e| {
+                            error!("Failed to emit service status event: {}", e);
+                        });
+
+                        return;
+                    }
+                };
 
                 ServiceStatusEvent {
                     service: Service::Osc,
@@ -239,6 +383,58 @@ pub fn run() {
 
             Ok(())
         })
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while running tauri application")
+        .run(|app, event| {
+            // Parameters held by effects and pressed inputs would otherwise stay stuck in VRChat
+            if let RunEvent::Exit = event {
+                tauri::async_runtime::block_on(async {
+                    stop_sequence(app).await;
+                    stop_animations(app, |_| true).await;
+                    end_effects(app, |_| true).await;
+                });
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ints_vrchat_can_store() {
+        assert_eq!(OscValueType::Int.parse("0"), Ok(OscValue::Int(0)));
+        assert_eq!(OscValueType::Int.parse(" 255 "), Ok(OscValue::Int(255)));
+        assert!(OscValueType::Int.parse("256").is_err());
+        assert!(OscValueType::Int.parse("-1").is_err());
+        assert!(OscValueType::Int.parse("1.5").is_err());
+    }
+
+    #[test]
+    fn parses_floats_vrchat_can_store() {
+        assert_eq!(OscValueType::Float.parse("-1"), Ok(OscValue::Float(-1.0)));
+        assert_eq!(OscValueType::Float.parse("0.5"), Ok(OscValue::Float(0.5)));
+        assert!(OscValueType::Float.parse("1.01").is_err());
+        assert!(OscValueType::Float.parse("NaN").is_err());
+        assert!(OscValueType::Float.parse("half").is_err());
+    }
+
+    #[test]
+    fn parses_bools() {
+        for value in ["true", "TRUE", "1"] {
+            assert_eq!(OscValueType::Bool.parse(value), Ok(OscValue::Bool(true)));
+        }
+        for value in ["false", "False", "0"] {
+            assert_eq!(OscValueType::Bool.parse(value), Ok(OscValue::Bool(false)));
+        }
+        assert!(OscValueType::Bool.parse("yes").is_err());
+    }
+
+    #[test]
+    fn parses_strings_as_is() {
+        assert_eq!(
+            OscValueType::String.parse(" hello there "),
+            Ok(OscValue::String("hello there".into()))
+        );
+    }
 }
@@ -6,13 +6,58 @@ use tauri::{AppHandle, Manager};
 use tauri_specta::Event;
 use vrchat_osc::{
     models::OscRootNode,
-    rosc::{self, OscPacket},
+    rosc::{self, OscMessage, OscPacket},
     ServiceType, VRChatOSC,
 };
 
-use crate::{OscChangeEvent, OscValue, Service, ServiceStatus, ServiceStatusEvent};
+use crate::{
+    avatars::current::avatar_changed, OscValue, Service, ServiceStatus, ServiceStatusEvent,
+};
+
+pub mod animation;
+pub mod cache;
+pub mod chatbox;
+pub mod effects;
+pub mod input;
 
-pub async fn setup_osc_listener(paths: Vec<String>) -> Result<Arc<VRChatOSC>> {
+use cache::OscCache;
+
+impl From<OscValue> for rosc::OscType {
+    fn from(value: OscValue) -> Self {
+        match value {
+            OscValue::Int(i) => rosc::OscType::Int(i),
+            OscValue::Float(f) => rosc::OscType::Float(f),
+            OscValue::String(s) => rosc::OscType::String(s),
+            OscValue::Bool(b) => rosc::OscType::Bool(b),
+        }
+    }
+}
+
+/// Send a parameter to every VRChat client
+pub async fn send_parameter(app: &AppHandle, address: &str, value: OscValue) -> Result<(), String> {
+    send_message(app, address, vec![value.into()]).await
+}
+
+/// Send a message to every VRChat client
+pub async fn send_message(
+    app: &AppHandle,
+    address: &str,
+    args: Vec<rosc::OscType>,
+) -> Result<(), String> {
+    let osc = app
+        .try_state::<Arc<VRChatOSC>>()
+        .ok_or("The OSC service isn't running")?;
+    let packet = OscPacket::Message(OscMessage {
+        addr: address.into(),
+        args,
+    });
+
+    osc.send(packet, "VRChat-Client-*")
+        .await
+        .map_err(|e| e.to_string())
+}
+
+pub async fn setup_osc_listener(app: AppHandle) -> Result<Arc<VRChatOSC>> {
     let vrchat_osc = VRChatOSC::new().await?;
 
     info!("Starting VRChat OSC client...");
@@ -20,23 +65,20 @@ pub async fn setup_osc_listener(paths: Vec<String>) -> Result<Arc<VRChatOSC>> {
     vrchat_osc
         .on_connect(move |res| match res {
             ServiceType::Osc(name, addr) => {
-                error!("Connected to OSC server: {} at {}", name, addr);
+                info!("Connected to OSC server: {} at {}", name, addr);
             }
             ServiceType::OscQuery(name, addr) => {
-                error!("Connected to OSCQuery server: {} at {}", name, addr);
+                info!("Connected to OSCQuery server: {} at {}", name, addr);
+                // Our own service is discovered as well
+                if !name.to_ascii().starts_with("VRChat-Client") {
+                    return;
+                }
+
+                app.state::<Arc<OscCache>>().set_query_addr(addr);
                 let osc_clone = osc_clone.clone();
-                let paths = paths.clone();
+                let app = app.clone();
                 tauri::async_runtime::spawn(async move {
-                    for path in paths {
-                        // NOTE: When actually retrieving parameters, you should implement retry logic here.
-                        // If VRChat has just started, it is possible that valid values may not be returned immediately.
-                        match osc_clone.get_parameter_from_addr(&path, addr).await {
-                            Err(e) => {
-                                log::error!("Error retrieving parameter {}: {:?}", path, e);
-                            }
-                            Ok(params) => log::info!("Received parameter {}: {:?}", path, params),
-                        }
-                    }
+                    cache::refresh(&app, &osc_clone, addr).await;
                 });
             }
         })
@@ -70,11 +112,33 @@ pub async fn osc_message_broadcaster(app: &AppHandle) -> Result<()> {
 
         debug!("OSC Message - Address: {}, Value: {:?}", address, value);
 
-        OscChangeEvent { address, value }
-            .emit(&osc_callback_handle)
-            .unwrap_or_else(|e| {
+        let cache = osc_callback_handle.state::<Arc<OscCache>>();
+
+        // A new avatar has a different set of parameters
+        if address == "/avatar/change" {
+            if let OscValue::String(id) = &value {
+                let app = osc_callback_handle.clone();
+                let id = id.clone();
+                tauri::async_runtime::spawn(async move {
+                    avatar_changed(&app, &id);
+                });
+            }
+
+            if let Some(addr) = cache.query_addr() {
+                let app = osc_callback_handle.clone();
+                tauri::async_runtime::spawn(async move {
+                    let osc = app.state::<Arc<VRChatOSC>>().inner().clone();
+                    cache::refresh(&app, &osc, addr).await;
+                });
+            }
+        }
+
+        // VRChat repeats values that didn't change, only actual changes are passed on
+        if let Some(change) = cache.update(&address, value) {
+            change.emit(&osc_callback_handle).unwrap_or_else(|e| {
                 error!("Failed to emit OSC change event: {}", e);
             });
+        }
     };
 
     let root_node = OscRootNode::new().with_avatar();
@@ -0,0 +1,486 @@
+use std::{
+    collections::HashMap,
+    f32::consts::TAU,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tauri::{async_runtime::JoinHandle, AppHandle, Manager};
+use tauri_specta::Event;
+use tokio::time::{Instant, MissedTickBehavior};
+
+use crate::{
+    osc::{
+        cache::{parameter_type, OscCache},
+        send_parameter,
+    },
+    OscValue, OscValueType,
+};
+
+/// Values sent per second when no tick rate is given
+const DEFAULT_TICK_RATE: u32 = 20;
+/// More than this only adds OSC traffic without looking any smoother
+const MAX_TICK_RATE: u32 = 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Type)]
+pub enum Easing {
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+}
+
+impl Easing {
+    fn apply(self, t: f32) -> f32 {
+        match self {
+            Easing::Linear => t,
+            Easing::EaseIn => t * t,
+            Easing::EaseOut => 1.0 - (1.0 - t) * (1.0 - t),
+            Easing::EaseInOut => {
+                if t < 0.5 {
+                    2.0 * t * t
+                } else {
+                    1.0 - (2.0 - 2.0 * t).powi(2) / 2.0
+                }
+            }
+        }
+    }
+}
+
+/// How a Float parameter moves over time, values are clamped to VRChat's -1 to 1
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Type)]
+pub enum OscCurve {
+    /// From `from` to `to` over the whole duration
+    Ramp { from: f32, to: f32, easing: Easing },
+    /// Swings `amplitude` around `center`, `frequency` times per second
+    Sine {
+        center: f32,
+        amplitude: f32,
+        frequency: f32,
+    },
+    /// Switches between `low` and `high` `frequency` times per second, `duty` is the share spent high
+    Pulse {
+        low: f32,
+        high: f32,
+        frequency: f32,
+        duty: f32,
+    },
+    /// A random value at most `amplitude` away from `center` on every tick
+    Jitter { center: f32, amplitude: f32 },
+}
+
+impl OscCurve {
+    /// The value `elapsed` seconds in, `progress` is how much of the duration has passed
+    fn value(&self, elapsed: f32, progress: f32) -> f32 {
+        let value = match *self {
+            OscCurve::Ramp { from, to, easing } => from + (to - from) * easing.apply(progress),
+            OscCurve::Sine {
+                center,
+                amplitude,
+                frequency,
+            } => center + amplitude * (TAU * frequency * elapsed).sin(),
+            OscCurve::Pulse {
+                low,
+                high,
+                frequency,
+                duty,
+            } => {
+                if (elapsed * frequency).fract() < duty {
+                    high
+                } else {
+                    low
+                }
+            }
+            OscCurve::Jitter { center, amplitude } => {
+                // Anything further than 2 is clamped anyway, and a wider range overflows
+                let amplitude = amplitude.abs().min(2.0);
+                center + rand::random_range(-amplitude..=amplitude)
+            }
+        };
+        value.clamp(-1.0, 1.0)
+    }
+
+    /// Reject NaN and infinite values, which would break the value or make jitter panic
+    fn validate(&self) -> Result<(), String> {
+        let values: &[(&str, f32)] = match *self {
+            OscCurve::Ramp { from, to, .. } => &[("from", from), ("to", to)],
+            OscCurve::Sine {
+                center,
+                amplitude,
+                frequency,
+            } => &[
+                ("center", center),
+                ("amplitude", amplitude),
+                ("frequency", frequency),
+            ],
+            OscCurve::Pulse {
+                low,
+                high,
+                frequency,
+                duty,
+            } => &[
+                ("low", low),
+                ("high", high),
+                ("frequency", frequency),
+                ("duty", duty),
+            ],
+            OscCurve::Jitter { center, amplitude } => {
+                &[("center", center), ("amplitude", amplitude)]
+            }
+        };
+
+        match values.iter().find(|(_, value)| !value.is_finite()) {
+            Some((name, value)) => Err(format!("The {} of the curve is {}", name, value)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+pub struct OscAnimation {
+    pub id: u32,
+    pub address: String,
+    pub curve: OscCurve,
+    /// Lets animations started by the same reward channel be stopped together
+    pub channel_id: Option<String>,
+}
+
+/// Sent when an animation ran its duration, was stopped or was replaced by another one
+#[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
+pub struct OscAnimationEndedEvent {
+    pub animation: OscAnimation,
+}
+
+struct RunningAnimation {
+    animation: OscAnimation,
+    /// Sent after the animation, None to keep its last value
+    restore: Option<OscValue>,
+    task: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct AnimationState {
+    next_id: u32,
+    running: HashMap<u32, RunningAnimation>,
+}
+
+/// Every running animation, a parameter has at most one
+#[derive(Default)]
+pub struct OscAnimations {
+    state: Mutex<AnimationState>,
+}
+
+impl OscAnimations {
+    pub fn animations(&self) -> Vec<OscAnimation> {
+        let state = self.state.lock().unwrap();
+        let mut animations = state
+            .running
+            .values()
+            .map(|r| r.animation.clone())
+            .collect::<Vec<_>>();
+        animations.sort_by_key(|a| a.id);
+        animations
+    }
+
+    /// Take the matching animations out, stopping their tasks unless `abort` is false
+    fn remove(
+        &self,
+        matches: impl Fn(&OscAnimation) -> bool,
+        abort: bool,
+    ) -> Vec<RunningAnimation> {
+        let mut state = self.state.lock().unwrap();
+        let ids = state
+            .running
+            .values()
+            .filter(|r| matches(&r.animation))
+            .map(|r| r.animation.id)
+            .collect::<Vec<_>>();
+
+        let mut removed = ids
+            .into_iter()
+            .filter_map(|id| state.running.remove(&id))
+            .collect::<Vec<_>>();
+        if abort {
+            for running in &removed {
+                running.task.abort();
+            }
+        }
+
+        removed.sort_by_key(|r| r.animation.id);
+        removed
+    }
+}
+
+/// Animate a Float parameter, sending `tick_rate` values per second
+/// Without a duration it runs until it is stopped, which a ramp can't
+pub async fn start_animation(
+    app: &AppHandle,
+    address: &str,
+    curve: OscCurve,
+    duration: Option<Duration>,
+    tick_rate: Option<u32>,
+    restore: bool,
+    channel_id: Option<String>,
+) -> Result<OscAnimation, String> {
+    if parameter_type(app, address)? != OscValueType::Float {
+        return Err(format!("{} is not a Float parameter", address));
+    }
+    let current = app
+        .state::<Arc<OscCache>>()
+        .get(address)
+        .and_then(|p| p.value);
+
+    curve.validate()?;
+
+    let duration = duration.filter(|d| !d.is_zero());
+    if matches!(curve, OscCurve::Ramp { .. }) && duration.is_none() {
+        return Err("A ramp needs a duration".to_string());
+    }
+
+    let tick = Duration::from_secs_f32(
+        1.0 / tick_rate
+            .unwrap_or(DEFAULT_TICK_RATE)
+            .clamp(1, MAX_TICK_RATE) as f32,
+    );
+
+    // A new animation replaces the running one, but still restores what was there before both
+    let replaced = app
+        .state::<OscAnimations>()
+        .remove(|a| a.address == address, true);
+    let restore = if restore {
+        replaced
+            .iter()
+            .find_map(|r| r.restore.clone())
+            .or(current)
+            .map(Some)
+            .ok_or_else(|| {
+                format!(
+                    "The value of {} isn't known yet, so it couldn't be restored",
+                    address
+                )
+            })
+    } else {
+        Ok(None)
+    };
+    emit_ended(app, replaced.into_iter().map(|r| r.animation).collect());
+    let restore = restore?;
+
+    let animations = app.state::<OscAnimations>();
+    let mut state = animations.state.lock().unwrap();
+    state.next_id = state.next_id.wrapping_add(1);
+
+    let animation = OscAnimation {
+        id: state.next_id,
+        address: address.to_string(),
+        curve,
+        channel_id,
+    };
+
+    // Inserted before the lock is released, so the task can't finish before it is known
+    let task = tauri::async_runtime::spawn(run_animation(
+        app.clone(),
+        animation.clone(),
+        duration,
+        tick,
+    ));
+    state.running.insert(
+        animation.id,
+        RunningAnimation {
+            animation: animation.clone(),
+            restore,
+            task,
+        },
+    );
+
+    info!(
+        "Started OSC animation {} on {} with {:?} at {:?} per tick",
+        animation.id, animation.address, animation.curve, tick
+    );
+
+    Ok(animation)
+}
+
+async fn run_animation(
+    app: AppHandle,
+    animation: OscAnimation,
+    duration: Option<Duration>,
+    tick: Duration,
+) {
+    let mut interval = tokio::time::interval(tick);
+    // A late tick is skipped rather than sending a burst of stale values
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let start = Instant::now();
+
+    loop {
+        interval.tick().await;
+
+        let elapsed = start.elapsed();
+        let progress = duration
+            .map(|d| (elapsed.as_secs_f32() / d.as_secs_f32()).min(1.0))
+            .unwrap_or(0.0);
+        let value = animation.curve.value(elapsed.as_secs_f32(), progress);
+
+        if let Err(e) = send_parameter(&app, &animation.address, OscValue::Float(value)).await {
+            warn!(
+                "Stopping OSC animation {} on {}: {}",
+                animation.id, animation.address, e
+            );
+            break;
+        }
+
+        if duration.is_some_and(|d| elapsed >= d) {
+            break;
+        }
+    }
+
+    let id = animation.id;
+    let removed = app.state::<OscAnimations>().remove(|a| a.id == id, false);
+    finish(&app, removed).await;
+}
+
+/// Stop the matching animations, restoring the values they had before
+pub async fn stop_animations(
+    app: &AppHandle,
+    matches: impl Fn(&OscAnimation) -> bool,
+) -> Vec<OscAnimation> {
+    let removed = app.state::<OscAnimations>().remove(matches, true);
+    finish(app, removed).await
+}
+
+/// Stop every animation without restoring anything, VRChat loads the new avatar's own values
+pub fn forget_animations(app: &AppHandle) {
+    let removed = app.state::<OscAnimations>().remove(|_| true, true);
+    if !removed.is_empty() {
+        info!(
+            "Stopped {} OSC animations because the avatar changed",
+            removed.len()
+        );
+    }
+
+    emit_ended(app, removed.into_iter().map(|r| r.animation).collect());
+}
+
+async fn finish(app: &AppHandle, removed: Vec<RunningAnimation>) -> Vec<OscAnimation> {
+    let mut animations = Vec::with_capacity(removed.len());
+
+    for running in removed {
+        if let Some(value) = running.restore {
+            if let Err(e) = send_parameter(app, &running.animation.address, value).await {
+                warn!(
+                    "Failed to restore {} after OSC animation: {}",
+                    running.animation.address, e
+                );
+            }
+        }
+        animations.push(running.animation);
+    }
+
+    emit_ended(app, animations.clone());
+    animations
+}
+
+fn emit_ended(app: &AppHandle, animations: Vec<OscAnimation>) {
+    for animation in animations {
+        info!(
+            "Ended OSC animation {} on {}",
+            animation.id, animation.address
+        );
+
+        OscAnimationEndedEvent { animation }
+            .emit(app)
+            .unwrap_or_else(|e| {
+                error!("Failed to emit OSC animation ended event: {}", e);
+            });
+    }
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn start_osc_animation(
+    app: AppHandle,
+    address: &str,
+    curve: OscCurve,
+    duration_ms: Option<u32>,
+    tick_rate: Option<u32>,
+    restore: bool,
+    channel_id: Option<String>,
+) -> Result<OscAnimation, String> {
+    let duration = duration_ms.map(|ms| Duration::from_millis(ms.into()));
+
+    start_animation(
+        &app, address, curve, duration, tick_rate, restore, channel_id,
+    )
+    .await
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn stop_osc_animation(app: AppHandle, id: u32) -> Result<(), String> {
+    stop_animations(&app, |a| a.id == id).await;
+    Ok(())
+}
+
+/// Stop every animation of a channel, or all of them without one
+#[tauri::command]
+#[specta::specta]
+pub async fn stop_osc_animations(
+    app: AppHandle,
+    channel_id: Option<String>,
+) -> Result<Vec<OscAnimation>, String> {
+    Ok(stop_animations(&app, |a| channel_id.is_none() || a.channel_id == channel_id).await)
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn get_osc_animations(app: AppHandle) -> Result<Vec<OscAnimation>, String> {
+    Ok(app.state::<OscAnimations>().animations())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_curves_with_non_finite_values() {
+        let curves = [
+            OscCurve::Ramp {
+                from: f32::NAN,
+                to: 1.0,
+                easing: Easing::Linear,
+            },
+            OscCurve::Sine {
+                center: 0.0,
+                amplitude: 0.5,
+                frequency: f32::INFINITY,
+            },
+            OscCurve::Pulse {
+                low: 0.0,
+                high: 1.0,
+                frequency: 1.0,
+                duty: f32::NAN,
+            },
+            OscCurve::Jitter {
+                center: 0.0,
+                amplitude: f32::NEG_INFINITY,
+            },
+        ];
+        for curve in curves {
+            assert!(curve.validate().is_err(), "{:?} was accepted", curve);
+        }
+    }
+
+    #[test]
+    fn keeps_jitter_in_range() {
+        let curve = OscCurve::Jitter {
+            center: 0.5,
+            amplitude: f32::MAX,
+        };
+        assert!(curve.validate().is_ok());
+        for _ in 0..100 {
+            assert!((-1.0..=1.0).contains(&curve.value(0.0, 0.0)));
+        }
+    }
+}
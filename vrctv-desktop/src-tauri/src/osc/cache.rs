@@ -0,0 +1,399 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tauri::{AppHandle, Manager};
+use tauri_specta::Event;
+use vrchat_osc::{
+    models::{OscNode, OscType, OscValue as NodeValue},
+    VRChatOSC,
+};
+
+use crate::{
+    avatars::current::{avatar_changed, current_input_type},
+    OscChangeEvent, OscValue, OscValueType,
+};
+
+/// How many times the parameters are fetched before giving up
+const QUERY_ATTEMPTS: u32 = 5;
+/// Delay before the first retry, doubled after every failed attempt
+const QUERY_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+pub struct OscParameter {
+    pub address: String,
+    /// The type declared over OSCQuery, None for parameters only seen in messages
+    pub value_type: Option<OscValueType>,
+    /// None until VRChat reports a value
+    pub value: Option<OscValue>,
+}
+
+/// Sent after the parameters were fetched from VRChat, replaces everything known before
+#[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
+pub struct OscSnapshotEvent {
+    pub parameters: Vec<OscParameter>,
+}
+
+/// Every avatar parameter VRChat told us about, kept current from incoming OSC messages
+#[derive(Default)]
+pub struct OscCache {
+    parameters: RwLock<HashMap<String, OscParameter>>,
+    /// The OSCQuery server of the VRChat client, used to fetch the parameters again
+    query_addr: RwLock<Option<SocketAddr>>,
+}
+
+impl OscCache {
+    pub fn get(&self, address: &str) -> Option<OscParameter> {
+        self.parameters.read().unwrap().get(address).cloned()
+    }
+
+    pub fn snapshot(&self) -> Vec<OscParameter> {
+        let mut parameters = self
+            .parameters
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        parameters.sort_by(|a, b| a.address.cmp(&b.address));
+        parameters
+    }
+
+    /// The type VRChat declared for the parameter
+    /// Parameters that were never declared use the type of the last value VRChat sent for them,
+    /// and then `fallback`, the type from the avatar's config
+    pub fn value_type(
+        &self,
+        address: &str,
+        fallback: Option<OscValueType>,
+    ) -> Result<OscValueType, String> {
+        let Some(parameter) = self.get(address) else {
+            return fallback.ok_or_else(|| format!("Unknown OSC parameter: {}", address));
+        };
+
+        parameter
+            .value_type
+            .or_else(|| parameter.value.as_ref().map(OscValue::value_type))
+            .or(fallback)
+            .ok_or_else(|| format!("The type of {} isn't known yet", address))
+    }
+
+    /// Turn a value typed by the user into the type of the parameter, see `value_type`
+    pub fn coerce(
+        &self,
+        address: &str,
+        value: &str,
+        fallback: Option<OscValueType>,
+    ) -> Result<OscValue, String> {
+        self.value_type(address, fallback)?
+            .parse(value)
+            .map_err(|e| format!("Invalid value for {}: {}", address, e))
+    }
+
+    /// Store a value, returning the change if it differs from the cached one
+    pub fn update(&self, address: &str, value: OscValue) -> Option<OscChangeEvent> {
+        let mut parameters = self.parameters.write().unwrap();
+        let parameter = parameters
+            .entry(address.to_string())
+            .or_insert_with(|| OscParameter {
+                address: address.to_string(),
+                value_type: None,
+                value: None,
+            });
+
+        if parameter.value.as_ref() == Some(&value) {
+            return None;
+        }
+
+        let previous = parameter.value.replace(value.clone());
+        Some(OscChangeEvent {
+            address: address.to_string(),
+            value,
+            previous,
+        })
+    }
+
+    fn replace(&self, parameters: Vec<OscParameter>) {
+        *self.parameters.write().unwrap() = parameters
+            .into_iter()
+            .map(|p| (p.address.clone(), p))
+            .collect();
+    }
+
+    pub fn set_query_addr(&self, addr: SocketAddr) {
+        *self.query_addr.write().unwrap() = Some(addr);
+    }
+
+    pub fn query_addr(&self) -> Option<SocketAddr> {
+        *self.query_addr.read().unwrap()
+    }
+}
+
+/// The type of a parameter, falling back to the worn avatar's config when OSCQuery hasn't
+/// reported it, which happens while VRChat is still loading the avatar or without OSCQuery
+pub fn parameter_type(app: &AppHandle, address: &str) -> Result<OscValueType, String> {
+    app.state::<Arc<OscCache>>()
+        .value_type(address, current_input_type(app, address))
+}
+
+/// Coerce a value typed by the user, see `parameter_type`
+pub fn coerce_parameter(app: &AppHandle, address: &str, value: &str) -> Result<OscValue, String> {
+    app.state::<Arc<OscCache>>()
+        .coerce(address, value, current_input_type(app, address))
+}
+
+/// Fetch every avatar parameter from VRChat's OSCQuery server and replace the cache with them
+/// VRChat answers with an empty tree while it is still loading, so it is retried a few times
+pub async fn refresh(app: &AppHandle, osc: &VRChatOSC, addr: SocketAddr) {
+    let cache = app.state::<Arc<OscCache>>();
+    let mut delay = QUERY_RETRY_DELAY;
+
+    for attempt in 1..=QUERY_ATTEMPTS {
+        match fetch_parameters(osc, addr).await {
+            Ok(parameters) => {
+                info!(
+                    "Fetched {} OSC parameters from {} (attempt {})",
+                    parameters.len(),
+                    addr,
+                    attempt
+                );
+                cache.replace(parameters);
+
+                if let Some(OscValue::String(id)) =
+                    cache.get("/avatar/change").and_then(|p| p.value)
+                {
+                    avatar_changed(app, &id);
+                }
+
+                OscSnapshotEvent {
+                    parameters: cache.snapshot(),
+                }
+                .emit(app)
+                .unwrap_or_else(|e| {
+                    error!("Failed to emit OSC snapshot event: {}", e);
+                });
+                return;
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to fetch OSC parameters from {} (attempt {}/{}): {}",
+                    addr, attempt, QUERY_ATTEMPTS, e
+                );
+            }
+        }
+
+        if attempt < QUERY_ATTEMPTS {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+
+    error!(
+        "Giving up on fetching OSC parameters from {} after {} attempts",
+        addr, QUERY_ATTEMPTS
+    );
+}
+
+async fn fetch_parameters(osc: &VRChatOSC, addr: SocketAddr) -> Result<Vec<OscParameter>> {
+    let root = osc.get_parameter_from_addr("/avatar", addr).await?;
+
+    let mut parameters = Vec::new();
+    collect_parameters(&root, &mut parameters);
+
+    if !parameters
+        .iter()
+        .any(|p| p.address.starts_with("/avatar/parameters/"))
+    {
+        bail!("VRChat didn't report any avatar parameters yet");
+    }
+
+    Ok(parameters)
+}
+
+fn collect_parameters(node: &OscNode, parameters: &mut Vec<OscParameter>) {
+    let value_type = node.get_single_osc_type().and_then(value_type);
+    if let Some(value_type) = value_type {
+        parameters.push(OscParameter {
+            address: node.full_path.clone(),
+            value_type: Some(value_type),
+            value: node
+                .value
+                .as_ref()
+                .and_then(|v| v.first())
+                .and_then(|v| node_value(v, value_type)),
+        });
+    }
+
+    for child in node.contents.values() {
+        collect_parameters(child, parameters);
+    }
+}
+
+fn value_type(osc_type: &OscType) -> Option<OscValueType> {
+    match osc_type {
+        OscType::Int32 => Some(OscValueType::Int),
+        OscType::Float32 => Some(OscValueType::Float),
+        OscType::True | OscType::False => Some(OscValueType::Bool),
+        OscType::OscString => Some(OscValueType::String),
+        _ => None,
+    }
+}
+
+/// OSCQuery values are plain JSON, so whole floats come back as ints
+fn node_value(value: &NodeValue, value_type: OscValueType) -> Option<OscValue> {
+    match (value, value_type) {
+        (NodeValue::Int(i), OscValueType::Int) => Some(OscValue::Int(*i)),
+        (NodeValue::Int(i), OscValueType::Float) => Some(OscValue::Float(*i as f32)),
+        (NodeValue::Float(f), OscValueType::Float) => Some(OscValue::Float(*f as f32)),
+        (NodeValue::Bool(b), OscValueType::Bool) => Some(OscValue::Bool(*b)),
+        (NodeValue::String(s), OscValueType::String) => Some(OscValue::String(s.clone())),
+        _ => None,
+    }
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn get_osc_parameter(app: AppHandle, address: &str) -> Result<OscParameter, String> {
+    app.state::<Arc<OscCache>>()
+        .get(address)
+        .ok_or_else(|| format!("Unknown OSC parameter: {}", address))
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn get_osc_snapshot(app: AppHandle) -> Result<Vec<OscParameter>, String> {
+    Ok(app.state::<Arc<OscCache>>().snapshot())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache() -> OscCache {
+        let cache = OscCache::default();
+        cache.replace(vec![
+            OscParameter {
+                address: "/avatar/parameters/Outfit".into(),
+                value_type: Some(OscValueType::Int),
+                value: None,
+            },
+            OscParameter {
+                address: "/avatar/parameters/Hue".into(),
+                value_type: Some(OscValueType::Float),
+                value: None,
+            },
+            OscParameter {
+                address: "/avatar/parameters/Hat".into(),
+                value_type: Some(OscValueType::Bool),
+                value: None,
+            },
+        ]);
+        cache
+    }
+
+    #[test]
+    fn coerces_to_the_declared_type() {
+        let cache = cache();
+        assert_eq!(
+            cache.coerce("/avatar/parameters/Outfit", "255", None),
+            Ok(OscValue::Int(255))
+        );
+        assert_eq!(
+            cache.coerce("/avatar/parameters/Hue", "-0.5", None),
+            Ok(OscValue::Float(-0.5))
+        );
+        assert_eq!(
+            cache.coerce("/avatar/parameters/Hat", "TRUE", None),
+            Ok(OscValue::Bool(true))
+        );
+        assert_eq!(
+            cache.coerce("/avatar/parameters/Hat", "0", None),
+            Ok(OscValue::Bool(false))
+        );
+    }
+
+    #[test]
+    fn rejects_values_outside_of_the_range() {
+        let cache = cache();
+        assert!(cache
+            .coerce("/avatar/parameters/Outfit", "256", None)
+            .is_err());
+        assert!(cache
+            .coerce("/avatar/parameters/Outfit", "-1", None)
+            .is_err());
+        assert!(cache.coerce("/avatar/parameters/Hue", "1.5", None).is_err());
+        assert!(cache
+            .coerce("/avatar/parameters/Hue", "-1.5", None)
+            .is_err());
+        assert!(cache
+            .coerce("/avatar/parameters/Hat", "maybe", None)
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_parameters() {
+        let err = cache()
+            .coerce("/avatar/parameters/Missing", "1", None)
+            .unwrap_err();
+        assert!(err.contains("Unknown OSC parameter"));
+    }
+
+    #[test]
+    fn uses_the_last_value_for_undeclared_parameters() {
+        let cache = cache();
+        cache.update("/avatar/parameters/Seen", OscValue::Float(0.0));
+        assert_eq!(
+            cache.coerce("/avatar/parameters/Seen", "1", None),
+            Ok(OscValue::Float(1.0))
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_avatar_config() {
+        let cache = cache();
+        assert_eq!(
+            cache.coerce("/avatar/parameters/Missing", "1", Some(OscValueType::Int)),
+            Ok(OscValue::Int(1))
+        );
+        // OSCQuery still wins over the config
+        assert_eq!(
+            cache.coerce("/avatar/parameters/Hue", "1", Some(OscValueType::Int)),
+            Ok(OscValue::Float(1.0))
+        );
+
+        cache.replace(vec![OscParameter {
+            address: "/avatar/parameters/Pending".into(),
+            value_type: None,
+            value: None,
+        }]);
+        assert_eq!(
+            cache.coerce(
+                "/avatar/parameters/Pending",
+                "true",
+                Some(OscValueType::Bool)
+            ),
+            Ok(OscValue::Bool(true))
+        );
+    }
+
+    #[test]
+    fn rejects_parameters_of_unknown_type() {
+        let cache = cache();
+        cache.replace(vec![OscParameter {
+            address: "/avatar/parameters/Pending".into(),
+            value_type: None,
+            value: None,
+        }]);
+        let err = cache
+            .coerce("/avatar/parameters/Pending", "1", None)
+            .unwrap_err();
+        assert!(err.contains("isn't known yet"));
+    }
+}
@@ -0,0 +1,234 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tauri::{AppHandle, Manager};
+use tokio::{sync::mpsc, time::Instant};
+use vrchat_osc::rosc::OscType;
+use vrctv_common::template::render;
+
+use crate::osc::send_message;
+
+/// VRChat shows at most this many characters, longer text is split over several messages
+const MAX_MESSAGE_LENGTH: usize = 144;
+/// VRChat ignores chatbox messages that come faster than about one every 1.5 seconds
+const MIN_MESSAGE_INTERVAL: Duration = Duration::from_millis(1500);
+/// Messages beyond this are dropped, so a burst of rewards doesn't keep the chatbox busy long after
+const MAX_QUEUED_MESSAGES: usize = 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+pub struct ChatboxMessage {
+    /// Placeholders such as `{user}` are filled in from `variables`
+    pub template: String,
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+    /// Clear the chatbox after this long, unless another message was shown since
+    pub clear_after_ms: Option<u32>,
+    /// Play VRChat's chatbox notification sound
+    #[serde(default)]
+    pub notify: bool,
+}
+
+pub enum ChatboxRequest {
+    Send(ChatboxMessage),
+    /// Drop everything queued and empty the chatbox
+    Clear,
+}
+
+struct QueuedMessage {
+    text: String,
+    clear_after: Option<Duration>,
+    notify: bool,
+}
+
+/// Split text into pieces VRChat can show, breaking between words where possible
+fn split_message(text: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut rest = text.trim();
+
+    while !rest.is_empty() {
+        let Some((limit, _)) = rest.char_indices().nth(MAX_MESSAGE_LENGTH) else {
+            parts.push(rest.to_string());
+            break;
+        };
+
+        let cut = rest[..limit]
+            .rfind(char::is_whitespace)
+            .filter(|i| *i > 0)
+            .unwrap_or(limit);
+        parts.push(rest[..cut].trim_end().to_string());
+        rest = rest[cut..].trim_start();
+    }
+
+    parts
+}
+
+async fn send_text(app: &AppHandle, text: &str, notify: bool) {
+    // The second argument sends the text straight away instead of opening the keyboard
+    let args = vec![
+        OscType::String(text.to_string()),
+        OscType::Bool(true),
+        OscType::Bool(notify),
+    ];
+    if let Err(e) = send_message(app, "/chatbox/input", args).await {
+        warn!("Failed to send chatbox message: {}", e);
+    }
+}
+
+async fn send_typing(app: &AppHandle, typing: bool) {
+    if let Err(e) = send_message(app, "/chatbox/typing", vec![OscType::Bool(typing)]).await {
+        warn!("Failed to send chatbox typing indicator: {}", e);
+    }
+}
+
+/// Send queued chatbox messages one at a time, spaced out so VRChat doesn't drop any
+pub async fn chatbox_sender(app: AppHandle, mut rx: mpsc::UnboundedReceiver<ChatboxRequest>) {
+    let mut queue: VecDeque<QueuedMessage> = VecDeque::new();
+    let mut last_sent: Option<Instant> = None;
+    let mut clear_at: Option<Instant> = None;
+    let mut typing = false;
+
+    loop {
+        let ready_at = last_sent
+            .map(|t| t + MIN_MESSAGE_INTERVAL)
+            .unwrap_or_else(Instant::now);
+        let wake_at = if !queue.is_empty() {
+            Some(ready_at)
+        } else {
+            clear_at.map(|t| t.max(ready_at))
+        };
+
+        tokio::select! {
+            request = rx.recv() => match request {
+                Some(ChatboxRequest::Send(message)) => {
+                    let text = render(&message.template, &message.variables);
+                    let parts = split_message(&text);
+
+                    if parts.is_empty() {
+                        warn!(
+                            "Not sending empty chatbox message from {:?}",
+                            message.template
+                        );
+                    } else if queue.len() + parts.len() > MAX_QUEUED_MESSAGES {
+                        warn!("Chatbox queue is full, dropping message {:?}", text);
+                    } else {
+                        let clear_after = message
+                            .clear_after_ms
+                            .filter(|ms| *ms > 0)
+                            .map(|ms| Duration::from_millis(ms.into()));
+                        queue.extend(parts.into_iter().map(|text| QueuedMessage {
+                            text,
+                            clear_after,
+                            notify: message.notify,
+                        }));
+                    }
+                }
+                Some(ChatboxRequest::Clear) => {
+                    queue.clear();
+                    clear_at = Some(Instant::now());
+                }
+                None => return,
+            },
+            _ = tokio::time::sleep_until(wake_at.unwrap_or_else(Instant::now)),
+                if wake_at.is_some() => {}
+        }
+
+        let now = Instant::now();
+        if now < ready_at {
+            // Show that more is coming while waiting for VRChat's rate limit
+            if !queue.is_empty() && !typing {
+                send_typing(&app, true).await;
+                typing = true;
+            }
+            continue;
+        }
+
+        if let Some(message) = queue.pop_front() {
+            info!("Sending chatbox message {:?}", message.text);
+            send_text(&app, &message.text, message.notify).await;
+            last_sent = Some(now);
+            clear_at = message.clear_after.map(|d| now + d);
+
+            if queue.is_empty() && typing {
+                send_typing(&app, false).await;
+                typing = false;
+            }
+        } else if clear_at.is_some_and(|t| t <= now) {
+            send_text(&app, "", false).await;
+            last_sent = Some(now);
+            clear_at = None;
+
+            if typing {
+                send_typing(&app, false).await;
+                typing = false;
+            }
+        }
+    }
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn send_chatbox(app: AppHandle, message: ChatboxMessage) -> Result<(), String> {
+    app.state::<mpsc::UnboundedSender<ChatboxRequest>>()
+        .send(ChatboxRequest::Send(message))
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn clear_chatbox(app: AppHandle) -> Result<(), String> {
+    app.state::<mpsc::UnboundedSender<ChatboxRequest>>()
+        .send(ChatboxRequest::Clear)
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_short_messages_whole() {
+        assert_eq!(split_message("  hello there "), vec!["hello there"]);
+        assert!(split_message("   ").is_empty());
+    }
+
+    #[test]
+    fn splits_between_words() {
+        let text = "word ".repeat(40);
+        let parts = split_message(&text);
+        assert_eq!(parts.len(), 2);
+        assert!(parts
+            .iter()
+            .all(|p| p.chars().count() <= MAX_MESSAGE_LENGTH));
+        assert!(parts.iter().all(|p| p.split(' ').all(|w| w == "word")));
+        assert_eq!(parts.join(" "), text.trim());
+    }
+
+    #[test]
+    fn counts_characters_not_bytes() {
+        let text = "é".repeat(MAX_MESSAGE_LENGTH);
+        assert_eq!(split_message(&text), vec![text.clone()]);
+
+        let text = "日本語 ".repeat(60);
+        let parts = split_message(&text);
+        assert_eq!(parts.len(), 2);
+        assert!(parts
+            .iter()
+            .all(|p| p.chars().count() <= MAX_MESSAGE_LENGTH));
+        assert_eq!(parts.join(" "), text.trim());
+    }
+
+    #[test]
+    fn cuts_words_longer_than_a_message() {
+        let word = "a".repeat(MAX_MESSAGE_LENGTH * 2 + 10);
+        let parts = split_message(&format!("hi {}", word));
+        assert_eq!(parts[0], "hi");
+        assert_eq!(parts[1].len(), MAX_MESSAGE_LENGTH);
+        assert_eq!(parts[2].len(), MAX_MESSAGE_LENGTH);
+        assert_eq!(parts[3].len(), 10);
+    }
+}
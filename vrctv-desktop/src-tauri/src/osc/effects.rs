@@ -0,0 +1,399 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tauri::{AppHandle, Manager};
+use tauri_specta::Event;
+
+use crate::{
+    osc::{
+        cache::{coerce_parameter, OscCache},
+        send_parameter,
+    },
+    OscValue,
+};
+
+/// A parameter value held for a while, after which the parameter goes back to what it was
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+pub struct OscEffect {
+    pub id: u32,
+    pub address: String,
+    pub value: OscValue,
+    /// Of the effects on the same parameter the highest priority is shown, the newest among equals
+    pub priority: i32,
+    /// Lets effects started by the same reward channel be cancelled together
+    pub channel_id: Option<String>,
+}
+
+/// Sent when an effect ran out, was cancelled or was dropped because the avatar changed
+#[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
+pub struct OscEffectEndedEvent {
+    pub effect: OscEffect,
+}
+
+struct ParameterEffects {
+    /// The value before the first effect, restored once the last one ends
+    restore: OscValue,
+    /// Oldest first
+    effects: Vec<OscEffect>,
+}
+
+impl ParameterEffects {
+    fn shown(&self) -> Option<&OscEffect> {
+        // max_by_key returns the last of equal elements, which is the newest
+        self.effects.iter().max_by_key(|e| e.priority)
+    }
+}
+
+#[derive(Default)]
+struct EffectState {
+    next_id: u32,
+    parameters: HashMap<String, ParameterEffects>,
+}
+
+/// Every running effect, grouped by parameter
+#[derive(Default)]
+pub struct OscEffects {
+    state: Mutex<EffectState>,
+}
+
+impl OscEffects {
+    pub fn effects(&self) -> Vec<OscEffect> {
+        let state = self.state.lock().unwrap();
+        let mut effects = state
+            .parameters
+            .values()
+            .flat_map(|p| p.effects.iter().cloned())
+            .collect::<Vec<_>>();
+        effects.sort_by_key(|e| e.id);
+        effects
+    }
+
+    /// Add an effect, returning the value to send if it is the one shown now
+    fn push(
+        &self,
+        address: &str,
+        value: OscValue,
+        priority: i32,
+        channel_id: Option<String>,
+        current: OscValue,
+    ) -> (OscEffect, Option<OscValue>) {
+        let mut state = self.state.lock().unwrap();
+        state.next_id = state.next_id.wrapping_add(1);
+
+        let effect = OscEffect {
+            id: state.next_id,
+            address: address.to_string(),
+            value,
+            priority,
+            channel_id,
+        };
+
+        let parameter = state
+            .parameters
+            .entry(address.to_string())
+            .or_insert_with(|| ParameterEffects {
+                restore: current,
+                effects: Vec::new(),
+            });
+        parameter.effects.push(effect.clone());
+
+        let send = match parameter.shown() {
+            Some(shown) if shown.id == effect.id => Some(effect.value.clone()),
+            _ => None,
+        };
+        (effect, send)
+    }
+
+    /// Remove the matching effects, returning them and the values the parameters go back to
+    fn remove(
+        &self,
+        matches: impl Fn(&OscEffect) -> bool,
+    ) -> (Vec<OscEffect>, Vec<(String, OscValue)>) {
+        let mut state = self.state.lock().unwrap();
+        let mut removed = Vec::new();
+        let mut sends = Vec::new();
+
+        state.parameters.retain(|address, parameter| {
+            let shown = parameter.shown().map(|e| e.id);
+
+            let (ended, kept): (Vec<_>, Vec<_>) =
+                parameter.effects.drain(..).partition(|e| matches(e));
+            parameter.effects = kept;
+            removed.extend(ended);
+
+            match parameter.shown() {
+                None => {
+                    sends.push((address.clone(), parameter.restore.clone()));
+                    false
+                }
+                Some(next) if Some(next.id) != shown => {
+                    sends.push((address.clone(), next.value.clone()));
+                    true
+                }
+                Some(_) => true,
+            }
+        });
+
+        removed.sort_by_key(|e| e.id);
+        (removed, sends)
+    }
+
+    /// Take back an effect whose value was never sent, nothing has to be restored for it
+    fn rollback(&self, id: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.parameters.retain(|_, parameter| {
+            parameter.effects.retain(|e| e.id != id);
+            !parameter.effects.is_empty()
+        });
+    }
+}
+
+/// Show a value on a parameter, going back to the previous value after `duration`
+/// Without a duration the effect lasts until it is cancelled
+pub async fn start_effect(
+    app: &AppHandle,
+    address: &str,
+    value: OscValue,
+    duration: Option<Duration>,
+    priority: i32,
+    channel_id: Option<String>,
+) -> Result<OscEffect, String> {
+    let current = app
+        .state::<Arc<OscCache>>()
+        .get(address)
+        .and_then(|p| p.value)
+        .ok_or_else(|| {
+            format!(
+                "The value of {} isn't known yet, so it couldn't be restored",
+                address
+            )
+        })?;
+
+    let effects = app.state::<OscEffects>();
+    let (effect, send) = effects.push(address, value, priority, channel_id, current);
+
+    if let Some(value) = send {
+        if let Err(e) = send_parameter(app, address, value).await {
+            // Nothing changed in VRChat, so the previously shown value is still correct
+            effects.rollback(effect.id);
+            return Err(e);
+        }
+    }
+
+    info!(
+        "Started OSC effect {} on {} with {:?} (priority {}, {})",
+        effect.id,
+        effect.address,
+        effect.value,
+        effect.priority,
+        duration
+            .map(|d| format!("{} ms", d.as_millis()))
+            .unwrap_or_else(|| "until cancelled".to_string())
+    );
+
+    if let Some(duration) = duration {
+        let app = app.clone();
+        let id = effect.id;
+        tauri::async_runtime::spawn(async move {
+            tokio::time::sleep(duration).await;
+            end_effects(&app, |e| e.id == id).await;
+        });
+    }
+
+    Ok(effect)
+}
+
+/// End the matching effects, sending whatever each affected parameter shows next
+pub async fn end_effects(app: &AppHandle, matches: impl Fn(&OscEffect) -> bool) -> Vec<OscEffect> {
+    let (removed, sends) = app.state::<OscEffects>().remove(matches);
+
+    for (address, value) in sends {
+        if let Err(e) = send_parameter(app, &address, value).await {
+            warn!("Failed to restore {} after OSC effect: {}", address, e);
+        }
+    }
+
+    emit_ended(app, &removed);
+    removed
+}
+
+/// Drop every effect without restoring anything, VRChat loads the new avatar's own values
+pub fn forget_effects(app: &AppHandle) {
+    let (removed, _) = app.state::<OscEffects>().remove(|_| true);
+    if !removed.is_empty() {
+        info!(
+            "Dropped {} OSC effects because the avatar changed",
+            removed.len()
+        );
+    }
+
+    emit_ended(app, &removed);
+}
+
+fn emit_ended(app: &AppHandle, effects: &[OscEffect]) {
+    for effect in effects {
+        info!("Ended OSC effect {} on {}", effect.id, effect.address);
+
+        OscEffectEndedEvent {
+            effect: effect.clone(),
+        }
+        .emit(app)
+        .unwrap_or_else(|e| {
+            error!("Failed to emit OSC effect ended event: {}", e);
+        });
+    }
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn start_osc_effect(
+    app: AppHandle,
+    address: &str,
+    value: &str,
+    duration_ms: Option<u32>,
+    priority: i32,
+    channel_id: Option<String>,
+) -> Result<OscEffect, String> {
+    let value = coerce_parameter(&app, address, value)?;
+    let duration = duration_ms
+        .filter(|ms| *ms > 0)
+        .map(|ms| Duration::from_millis(ms.into()));
+
+    start_effect(&app, address, value, duration, priority, channel_id).await
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn cancel_osc_effect(app: AppHandle, id: u32) -> Result<(), String> {
+    end_effects(&app, |e| e.id == id).await;
+    Ok(())
+}
+
+/// Cancel every effect of a channel, or all of them without one
+#[tauri::command]
+#[specta::specta]
+pub async fn cancel_osc_effects(
+    app: AppHandle,
+    channel_id: Option<String>,
+) -> Result<Vec<OscEffect>, String> {
+    Ok(end_effects(&app, |e| channel_id.is_none() || e.channel_id == channel_id).await)
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn get_osc_effects(app: AppHandle) -> Result<Vec<OscEffect>, String> {
+    Ok(app.state::<OscEffects>().effects())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: &str = "/avatar/parameters/Outfit";
+
+    fn push(effects: &OscEffects, value: i32, priority: i32) -> (u32, Option<OscValue>) {
+        let (effect, send) = effects.push(
+            ADDRESS,
+            OscValue::Int(value),
+            priority,
+            None,
+            OscValue::Int(0),
+        );
+        (effect.id, send)
+    }
+
+    fn remove(effects: &OscEffects, id: u32) -> Vec<(String, OscValue)> {
+        effects.remove(|e| e.id == id).1
+    }
+
+    #[test]
+    fn higher_priorities_take_over_and_hand_back() {
+        let effects = OscEffects::default();
+        let (low, send) = push(&effects, 1, 0);
+        assert_eq!(send, Some(OscValue::Int(1)));
+        let (high, send) = push(&effects, 2, 5);
+        assert_eq!(send, Some(OscValue::Int(2)));
+
+        // A lower priority waits behind the shown effect
+        let (_, send) = push(&effects, 3, 1);
+        assert_eq!(send, None);
+
+        assert_eq!(
+            remove(&effects, high),
+            vec![(ADDRESS.into(), OscValue::Int(3))]
+        );
+        // Removing an effect that isn't shown changes nothing
+        assert_eq!(remove(&effects, low), Vec::new());
+    }
+
+    #[test]
+    fn newest_wins_among_equal_priorities() {
+        let effects = OscEffects::default();
+        let (first, _) = push(&effects, 1, 0);
+        let (second, send) = push(&effects, 2, 0);
+        assert_eq!(send, Some(OscValue::Int(2)));
+
+        assert_eq!(
+            remove(&effects, second),
+            vec![(ADDRESS.into(), OscValue::Int(1))]
+        );
+        assert_eq!(
+            remove(&effects, first),
+            vec![(ADDRESS.into(), OscValue::Int(0))]
+        );
+    }
+
+    #[test]
+    fn restores_once_the_last_effect_ends() {
+        let effects = OscEffects::default();
+        let (first, _) = push(&effects, 1, 0);
+        // The value shown by the first effect isn't what is restored
+        effects.push(ADDRESS, OscValue::Int(2), 0, None, OscValue::Int(1));
+        let (third, _) = push(&effects, 3, 0);
+
+        assert_eq!(remove(&effects, first), Vec::new());
+        assert_eq!(
+            remove(&effects, third),
+            vec![(ADDRESS.into(), OscValue::Int(2))]
+        );
+
+        let (removed, sends) = effects.remove(|_| true);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(sends, vec![(ADDRESS.into(), OscValue::Int(0))]);
+        assert!(effects.effects().is_empty());
+    }
+
+    #[test]
+    fn rolls_back_without_restoring() {
+        let effects = OscEffects::default();
+        let (low, _) = push(&effects, 1, 0);
+        let (high, _) = push(&effects, 2, 5);
+
+        // The send of the higher priority failed, so the lower one is still shown
+        effects.rollback(high);
+        assert_eq!(
+            effects.effects().iter().map(|e| e.id).collect::<Vec<_>>(),
+            vec![low]
+        );
+        assert_eq!(
+            remove(&effects, low),
+            vec![(ADDRESS.into(), OscValue::Int(0))]
+        );
+
+        // Rolling back the only effect forgets the parameter, the next one restores its own value
+        let (only, _) = push(&effects, 1, 0);
+        effects.rollback(only);
+        assert!(effects.effects().is_empty());
+        let (next, _) = effects.push(ADDRESS, OscValue::Int(2), 0, None, OscValue::Int(7));
+        assert_eq!(
+            remove(&effects, next.id),
+            vec![(ADDRESS.into(), OscValue::Int(7))]
+        );
+    }
+}
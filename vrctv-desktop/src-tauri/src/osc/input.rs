@@ -0,0 +1,348 @@
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use tauri::{async_runtime::JoinHandle, AppHandle, Manager};
+use tauri_specta::Event;
+
+use crate::{osc::send_parameter, OscValue};
+
+/// VRChat only notices a press that lasts a few frames
+const MIN_HOLD: Duration = Duration::from_millis(100);
+/// No single input is held longer than this, whatever the sequence asks for
+const MAX_HOLD: Duration = Duration::from_secs(5);
+/// A sequence is cut off and everything released after this long
+const MAX_SEQUENCE_DURATION: Duration = Duration::from_secs(30);
+
+/// Buttons VRChat listens for under `/input/`, sent as 1 while pressed and 0 when released
+/// The variant names are the addresses VRChat uses
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Type)]
+pub enum InputButton {
+    MoveForward,
+    MoveBackward,
+    MoveLeft,
+    MoveRight,
+    LookLeft,
+    LookRight,
+    Jump,
+    Run,
+    ComfortLeft,
+    ComfortRight,
+    DropRight,
+    UseRight,
+    GrabRight,
+    DropLeft,
+    UseLeft,
+    GrabLeft,
+    PanicButton,
+    QuickMenuToggleLeft,
+    QuickMenuToggleRight,
+    /// Toggles the microphone when VRChat is set to toggle voice, holds it otherwise
+    Voice,
+}
+
+/// Axes VRChat listens for under `/input/`, from -1 to 1 and centered at 0
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Type)]
+pub enum InputAxis {
+    /// Forwards and backwards
+    Vertical,
+    /// Left and right
+    Horizontal,
+    /// Turning, which is what makes the player spin
+    LookHorizontal,
+    UseAxisRight,
+    GrabAxisRight,
+    MoveHoldFB,
+    SpinHoldCwCcw,
+    SpinHoldUD,
+    SpinHoldLR,
+}
+
+impl InputButton {
+    fn address(self) -> String {
+        format!("/input/{:?}", self)
+    }
+}
+
+impl InputAxis {
+    fn address(self) -> String {
+        format!("/input/{:?}", self)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Type)]
+pub enum InputStep {
+    /// Press a button and release it after `hold_ms`
+    Press { button: InputButton, hold_ms: u32 },
+    /// Push an axis to `value` and center it again after `hold_ms`
+    /// Values outside of -1 to 1 are clamped, values that aren't numbers are refused
+    Axis {
+        axis: InputAxis,
+        value: f32,
+        hold_ms: u32,
+    },
+    /// Do nothing for `ms` before the next step
+    /// Unlike holds this isn't capped, a single wait can use up the whole `MAX_SEQUENCE_DURATION`
+    Wait { ms: u32 },
+}
+
+/// Sent when a sequence finished, was stopped or was replaced by another one
+#[derive(Serialize, Deserialize, Debug, Clone, Type, Event)]
+pub struct InputSequenceEndedEvent {
+    pub id: u32,
+}
+
+struct RunningSequence {
+    id: u32,
+    task: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct InputState {
+    next_id: u32,
+    running: Option<RunningSequence>,
+    /// Inputs that are pressed right now and the value that releases them
+    held: HashMap<String, OscValue>,
+}
+
+/// The input sequence being played, only one runs at a time so they can't release each other's inputs
+#[derive(Default)]
+pub struct OscInputs {
+    state: Mutex<InputState>,
+}
+
+impl OscInputs {
+    fn hold(&self, address: &str, release: OscValue) {
+        self.state
+            .lock()
+            .unwrap()
+            .held
+            .insert(address.to_string(), release);
+    }
+
+    fn unhold(&self, address: &str) {
+        self.state.lock().unwrap().held.remove(address);
+    }
+}
+
+fn hold_duration(ms: u32) -> Duration {
+    let duration = Duration::from_millis(ms.into());
+    if duration > MAX_HOLD {
+        warn!(
+            "Holding an input for {} ms is too long, holding it for {:?} instead",
+            ms, MAX_HOLD
+        );
+    }
+    duration.clamp(MIN_HOLD, MAX_HOLD)
+}
+
+/// Check the steps before anything is stopped or sent
+fn validate_steps(steps: &[InputStep]) -> Result<(), String> {
+    if steps.is_empty() {
+        return Err("The input sequence is empty".to_string());
+    }
+
+    for step in steps {
+        if let InputStep::Axis { axis, value, .. } = step {
+            if !value.is_finite() {
+                return Err(format!("The value of {:?} is {}", axis, value));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Play a sequence of inputs, stopping the one that is running
+pub async fn start_sequence(app: &AppHandle, steps: Vec<InputStep>) -> Result<u32, String> {
+    validate_steps(&steps)?;
+
+    let replaced = stop_sequence(app).await;
+
+    let inputs = app.state::<OscInputs>();
+    let mut state = inputs.state.lock().unwrap();
+    state.next_id = state.next_id.wrapping_add(1);
+    let id = state.next_id;
+
+    info!("Starting input sequence {} with {} steps", id, steps.len());
+
+    // Stored before the lock is released, so the task can't finish before it is known
+    let task_app = app.clone();
+    let task = tauri::async_runtime::spawn(async move {
+        if tokio::time::timeout(MAX_SEQUENCE_DURATION, play_steps(&task_app, &steps))
+            .await
+            .is_err()
+        {
+            warn!(
+                "Input sequence {} ran longer than {:?}, stopping it",
+                id, MAX_SEQUENCE_DURATION
+            );
+        }
+
+        let inputs = task_app.state::<OscInputs>();
+        let finished = {
+            let mut state = inputs.state.lock().unwrap();
+            // A stopped or replaced sequence was already released by whoever stopped it
+            let current = state.running.as_ref().is_some_and(|r| r.id == id);
+            if current {
+                state.running = None;
+            }
+            current
+        };
+        if finished {
+            release_all(&task_app).await;
+            emit_ended(&task_app, id);
+        }
+    });
+    state.running = Some(RunningSequence { id, task });
+    drop(state);
+
+    if let Some(replaced) = replaced {
+        emit_ended(app, replaced);
+    }
+
+    Ok(id)
+}
+
+async fn play_steps(app: &AppHandle, steps: &[InputStep]) {
+    let inputs = app.state::<OscInputs>();
+
+    for step in steps {
+        let (address, value, release, hold) = match step {
+            InputStep::Press { button, hold_ms } => (
+                button.address(),
+                OscValue::Int(1),
+                OscValue::Int(0),
+                hold_duration(*hold_ms),
+            ),
+            InputStep::Axis {
+                axis,
+                value,
+                hold_ms,
+            } => (
+                axis.address(),
+                OscValue::Float(value.clamp(-1.0, 1.0)),
+                OscValue::Float(0.0),
+                hold_duration(*hold_ms),
+            ),
+            InputStep::Wait { ms } => {
+                tokio::time::sleep(Duration::from_millis((*ms).into())).await;
+                continue;
+            }
+        };
+
+        inputs.hold(&address, release.clone());
+        if let Err(e) = send_parameter(app, &address, value).await {
+            warn!("Failed to send input {}: {}", address, e);
+        }
+
+        tokio::time::sleep(hold).await;
+
+        if let Err(e) = send_parameter(app, &address, release).await {
+            warn!("Failed to release input {}: {}", address, e);
+        }
+        inputs.unhold(&address);
+    }
+}
+
+/// Release every input that is still held, VRChat would keep them pressed otherwise
+async fn release_all(app: &AppHandle) {
+    let held = std::mem::take(&mut app.state::<OscInputs>().state.lock().unwrap().held);
+
+    for (address, release) in held {
+        if let Err(e) = send_parameter(app, &address, release).await {
+            warn!("Failed to release input {}: {}", address, e);
+        }
+    }
+}
+
+/// Stop the running sequence and release its inputs, returning its id
+pub async fn stop_sequence(app: &AppHandle) -> Option<u32> {
+    let running = app
+        .state::<OscInputs>()
+        .state
+        .lock()
+        .unwrap()
+        .running
+        .take();
+
+    let running = running?;
+    running.task.abort();
+    release_all(app).await;
+
+    info!("Stopped input sequence {}", running.id);
+    Some(running.id)
+}
+
+fn emit_ended(app: &AppHandle, id: u32) {
+    InputSequenceEndedEvent { id }
+        .emit(app)
+        .unwrap_or_else(|e| {
+            error!("Failed to emit input sequence ended event: {}", e);
+        });
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn run_input_sequence(app: AppHandle, steps: Vec<InputStep>) -> Result<u32, String> {
+    start_sequence(&app, steps).await
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn stop_input_sequence(app: AppHandle) -> Result<(), String> {
+    if let Some(id) = stop_sequence(&app).await {
+        emit_ended(&app, id);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_inputs_long_enough_to_be_noticed() {
+        assert_eq!(hold_duration(0), MIN_HOLD);
+        assert_eq!(hold_duration(50), MIN_HOLD);
+    }
+
+    #[test]
+    fn keeps_durations_in_range() {
+        assert_eq!(hold_duration(250), Duration::from_millis(250));
+        assert_eq!(hold_duration(5000), MAX_HOLD);
+    }
+
+    #[test]
+    fn caps_long_holds() {
+        assert_eq!(hold_duration(60_000), MAX_HOLD);
+        assert_eq!(hold_duration(u32::MAX), MAX_HOLD);
+    }
+
+    fn axis(value: f32) -> InputStep {
+        InputStep::Axis {
+            axis: InputAxis::LookHorizontal,
+            value,
+            hold_ms: 500,
+        }
+    }
+
+    #[test]
+    fn accepts_axis_values_to_clamp() {
+        assert!(validate_steps(&[axis(0.5), InputStep::Wait { ms: 100 }, axis(-3.0)]).is_ok());
+    }
+
+    #[test]
+    fn rejects_axis_values_that_arent_numbers() {
+        for value in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            let err = validate_steps(&[InputStep::Wait { ms: 100 }, axis(value)]).unwrap_err();
+            assert!(err.contains("LookHorizontal"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn rejects_empty_sequences() {
+        assert!(validate_steps(&[]).is_err());
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    env, fmt,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use log::info;
+use tauri::{AppHandle, Manager};
+
+use crate::avatars::catalog::AvatarCatalog;
+
+/// VRChat's Steam app id, Proton keeps its Windows prefix under this id
+const VRCHAT_APP_ID: &str = "438100";
+
+/// Where VRChat keeps its data inside a Windows user folder
+const LOCAL_LOW_PATH: &str = "AppData/LocalLow/VRChat/VRChat";
+
+/// The VRChat data folder picked by the user, for installs that aren't found on their own
+#[derive(Default)]
+pub struct VrchatDirOverride(RwLock<Option<PathBuf>>);
+
+impl VrchatDirOverride {
+    pub fn get(&self) -> Option<PathBuf> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn set(&self, path: Option<PathBuf>) {
+        *self.0.write().unwrap() = path;
+    }
+}
+
+#[derive(Debug)]
+pub enum VrchatDirError {
+    /// The folder picked by the user doesn't exist
+    OverrideMissing(PathBuf),
+    /// None of the usual install locations exist
+    NotFound(Vec<PathBuf>),
+    /// VRChat was found but never wrote any OSC configs
+    NoOscFolder(PathBuf),
+}
+
+impl fmt::Display for VrchatDirError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VrchatDirError::OverrideMissing(path) => {
+                write!(f, "The configured VRChat folder {:?} doesn't exist", path)
+            }
+            VrchatDirError::NotFound(searched) => {
+                write!(
+                    f,
+                    "Couldn't find VRChat's data folder, set it manually. Searched: {}",
+                    searched
+                        .iter()
+                        .map(|p| p.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            }
+            VrchatDirError::NoOscFolder(path) => write!(
+                f,
+                "VRChat's data folder {:?} has no OSC folder, enable OSC in VRChat first",
+                path
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VrchatDirError {}
+
+/// VRChat's data folder, the override if one is set, otherwise the first install found
+pub fn vrchat_dir(app: &AppHandle) -> Result<PathBuf, VrchatDirError> {
+    if let Some(path) = app.state::<VrchatDirOverride>().get() {
+        let path = override_dir(path);
+        return if path.is_dir() {
+            Ok(path)
+        } else {
+            Err(VrchatDirError::OverrideMissing(path))
+        };
+    }
+
+    let candidates = candidate_dirs();
+    match candidates.iter().find(|p| p.is_dir()) {
+        Some(path) => Ok(path.clone()),
+        None => Err(VrchatDirError::NotFound(candidates)),
+    }
+}
+
+/// The data folder for a folder picked by the user
+/// Accepts the OSC folder itself as well, it is what users usually find first
+fn override_dir(path: PathBuf) -> PathBuf {
+    match path.file_name() {
+        Some(name) if name == "OSC" => path.parent().map(Path::to_path_buf).unwrap_or(path),
+        _ => path,
+    }
+}
+
+/// The folder VRChat writes avatar OSC configs to, one subfolder per account
+pub fn osc_dir(app: &AppHandle) -> Result<PathBuf, VrchatDirError> {
+    let vrchat_dir = vrchat_dir(app)?;
+    let osc_dir = vrchat_dir.join("OSC");
+
+    if osc_dir.is_dir() {
+        Ok(osc_dir)
+    } else {
+        Err(VrchatDirError::NoOscFolder(vrchat_dir))
+    }
+}
+
+#[cfg(windows)]
+fn candidate_dirs() -> Vec<PathBuf> {
+    env::home_dir()
+        .map(|home| vec![home.join(LOCAL_LOW_PATH)])
+        .unwrap_or_default()
+}
+
+/// VRChat runs through Proton, its data is in the Windows prefix of the Steam library it is
+/// installed in
+#[cfg(not(windows))]
+fn candidate_dirs() -> Vec<PathBuf> {
+    let Some(home) = env::home_dir() else {
+        return Vec::new();
+    };
+
+    let steam_roots = [
+        home.join(".steam/steam"),
+        home.join(".steam/root"),
+        home.join(".local/share/Steam"),
+        // Flatpak
+        home.join(".var/app/com.valvesoftware.Steam/.local/share/Steam"),
+    ];
+
+    let mut libraries: Vec<PathBuf> = Vec::new();
+    for root in steam_roots.iter().filter(|r| r.is_dir()) {
+        let root = std::fs::canonicalize(root).unwrap_or_else(|_| root.clone());
+        for library in std::iter::once(root.clone()).chain(library_folders(&root)) {
+            if !libraries.contains(&library) {
+                libraries.push(library);
+            }
+        }
+    }
+    if libraries.is_empty() {
+        libraries.push(home.join(".local/share/Steam"));
+    }
+
+    libraries
+        .into_iter()
+        .map(|library| {
+            library
+                .join("steamapps/compatdata")
+                .join(VRCHAT_APP_ID)
+                .join("pfx/drive_c/users/steamuser")
+                .join(LOCAL_LOW_PATH)
+        })
+        .collect()
+}
+
+/// Extra Steam libraries listed in `libraryfolders.vdf`, as `"path"  "/mnt/games/SteamLibrary"`
+#[cfg(not(windows))]
+fn library_folders(steam_root: &Path) -> Vec<PathBuf> {
+    match std::fs::read_to_string(steam_root.join("steamapps/libraryfolders.vdf")) {
+        Ok(content) => parse_library_folders(&content),
+        Err(_) => Vec::new(),
+    }
+}
+
+#[cfg(not(windows))]
+fn parse_library_folders(content: &str) -> Vec<PathBuf> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split('"').filter(|p| !p.trim().is_empty());
+            match (parts.next(), parts.next()) {
+                (Some("path"), Some(path)) => Some(PathBuf::from(path.replace("\\\\", "\\"))),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn set_vrchat_dir(app: AppHandle, path: Option<String>) -> Result<(), String> {
+    let path = path.filter(|p| !p.trim().is_empty()).map(PathBuf::from);
+    info!("VRChat data folder override set to {:?}", path);
+    app.state::<VrchatDirOverride>().set(path);
+    app.state::<AvatarCatalog>().folder_changed();
+    Ok(())
+}
+
+/// The OSC folder that is used, to show the user where avatars are read from
+#[tauri::command]
+#[specta::specta]
+pub async fn get_osc_dir(app: AppHandle) -> Result<String, String> {
+    osc_dir(&app)
+        .map(|p| p.display().to_string())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_the_osc_folder_as_override() {
+        let data = PathBuf::from("/games/VRChat/VRChat");
+        assert_eq!(override_dir(data.join("OSC")), data);
+        assert_eq!(override_dir(data.clone()), data);
+        // Only the folder itself, not one inside it
+        assert_eq!(override_dir(data.join("OSC/usr_1")), data.join("OSC/usr_1"));
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn parses_library_folders() {
+        // Trimmed down from a libraryfolders.vdf written by Steam
+        let content = r#""libraryfolders"
+{
+	"0"
+	{
+		"path"		"/home/user/.local/share/Steam"
+		"label"		""
+		"contentid"		"7126418377543411207"
+		"apps"
+		{
+			"438100"		"28451925504"
+		}
+	}
+	"1"
+	{
+		"path"		"/mnt/my games/SteamLibrary"
+		"label"		""
+	}
+	"2"
+	{
+		"path"		"D:\\SteamLibrary"
+	}
+}
+"#;
+
+        assert_eq!(
+            parse_library_folders(content),
+            vec![
+                PathBuf::from("/home/user/.local/share/Steam"),
+                PathBuf::from("/mnt/my games/SteamLibrary"),
+                PathBuf::from("D:\\SteamLibrary"),
+            ]
+        );
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn ignores_files_without_library_folders() {
+        assert!(parse_library_folders("").is_empty());
+        assert!(parse_library_folders("\"libraryfolders\"\n{\n}\n").is_empty());
+    }
+}
@@ -1,48 +1,28 @@
-import { derived, get, writable } from "svelte/store";
-import { commands, type Avatar } from "../bindings";
+import { readable } from "svelte/store";
+import { commands, events, type Avatar } from "../bindings";
 import toast from "svelte-french-toast";
 
-const avatarListCache = writable<Avatar[]>([]);
-const avatarLastFetchTime = writable<number>(0);
-const avatarOscCache = writable<Record<string, string[]>>({});
-const avatarOscLastFetchTime = writable<Record<string, number>>({});
-const CACHE_DURATION_MS = 5 * 60 * 1000; // 5 minutes
-
-export const cachedAvatarStore = derived<[typeof avatarListCache, typeof avatarLastFetchTime], Avatar[]>(
-    [avatarListCache, avatarLastFetchTime],
-    ([$avatarListCache, $avatarLastFetchTime], set) => {
-        const now = Date.now();
-
-        if ($avatarListCache && (now - $avatarLastFetchTime) < CACHE_DURATION_MS) {
-            return; // Value is fresh, no need to fetch
+// The backend keeps the avatar catalogue in memory and tells us when VRChat writes a new config
+export const cachedAvatarStore = readable<Avatar[]>([], (set) => {
+    commands.fetchAvatars().then((avatars) => {
+        if (avatars.status === "error") {
+            toast.error("Failed to fetch avatars: " + avatars.error);
+            return;
         }
 
-        avatarLastFetchTime.set(Date.now());
-        commands.fetchAvatars().then((avatars) => {
-            if (avatars.status === "error") {
-                toast.error("Failed to fetch avatars: " + avatars.error);
-                return;
-            }
+        set(avatars.data);
+    });
 
-            avatarListCache.set(avatars.data);
-            set(avatars.data);
-        });
+    const unlisten = events.avatarCatalogChangedEvent.listen((event) => {
+        set(event.payload.avatars);
+    });
 
-        return; // Initial return to avoid undefined
-    },
-    []
-)
+    return () => {
+        unlisten.then((f) => f());
+    };
+});
 
 export async function getAvatarOscs(avatarId: string): Promise<string[]> {
-    const now = Date.now();
-
-    let cache = get(avatarOscCache);
-    let lastFetchTimes = get(avatarOscLastFetchTime);
-
-    if (cache[avatarId] && lastFetchTimes[avatarId] && (now - lastFetchTimes[avatarId]) < CACHE_DURATION_MS) {
-        return cache[avatarId]; // Return cached value
-    }
-
     const response = await commands.fetchAvatarOsc(avatarId);
 
     if (response.status === "error") {
@@ -50,10 +30,5 @@ export async function getAvatarOscs(avatarId: string): Promise<string[]> {
         return [];
     }
 
-    cache[avatarId] = response.data;
-    lastFetchTimes[avatarId] = now;
-    avatarOscLastFetchTime.set(lastFetchTimes);
-    avatarOscCache.set(cache);
-
     return response.data;
-}
\ No newline at end of file
+}
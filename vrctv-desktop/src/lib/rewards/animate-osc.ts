@@ -0,0 +1,110 @@
+import { get } from "svelte/store";
+import { commands, events, type OscCurve } from "../../bindings";
+import { CancellableReward, type RewardContext } from "./types";
+import { currentAvatarStore } from "$lib/stores/global";
+import { error } from "@tauri-apps/plugin-log";
+import { rewardStore } from "$lib/stores/rewards";
+import type { UnlistenFn } from "@tauri-apps/api/event";
+
+export type AnimateOSCRewardParams = {
+    id: string;
+    for_avatar: string;
+    param: string;
+    curve: OscCurve;
+    channel_id: string;
+    timeout_ms: number;
+    // Values sent per second, the backend default is used when 0
+    tick_rate: number;
+    // Whether the parameter goes back to its previous value afterwards or keeps the last one
+    restore: boolean;
+}
+export class AnimateOSCReward extends CancellableReward<AnimateOSCRewardParams> {
+    static id = "animate-osc-reward";
+    static title = "Animate OSC Reward";
+    static description = "Animate a Float OSC parameter for a duration"
+    reward = AnimateOSCReward;
+
+    animationId: number | null = null;
+    unlistenAnimation: UnlistenFn | null = null;
+
+    constructor(params: Partial<AnimateOSCRewardParams>) {
+        super({
+            id: params.id ?? crypto.randomUUID(),
+            for_avatar: params.for_avatar || get(rewardStore).baseAvatarId || "",
+            param: params.param || "",
+            curve: params.curve || { Sine: { center: 0, amplitude: 1, frequency: 1 } },
+            channel_id: params.channel_id || "",
+            timeout_ms: params.timeout_ms || 0,
+            tick_rate: params.tick_rate || 0,
+            restore: params.restore ?? true,
+        });
+    }
+
+    async validate(): Promise<string | null> {
+        if (!this.params.param) {
+            return "Parameter cannot be empty.";
+        }
+        if ("Ramp" in this.params.curve && this.params.timeout_ms <= 0) {
+            return "A ramp needs a timeout.";
+        }
+        return null;
+    }
+
+    async readyToStart(context: RewardContext): Promise<boolean> {
+        if ((get(currentAvatarStore)?.id ?? "") !== this.params.for_avatar) {
+            return false;
+        }
+
+        let runningRewards = context.runningRewards.filter((r) => r instanceof AnimateOSCReward);
+        if (this.params.channel_id !== "" && runningRewards.find((r) => r.params.channel_id === this.params.channel_id)) {
+            return false;
+        }
+
+        return true;
+    }
+
+    async onStart(_context: RewardContext): Promise<void> {
+        this.unlistenAnimation = await events.oscAnimationEndedEvent.listen((event) => {
+            if (event.payload.animation.id === this.animationId) {
+                this.onFinished();
+            }
+        });
+
+        const result = await commands.startOscAnimation(
+            this.params.param,
+            this.params.curve,
+            this.params.timeout_ms || null,
+            this.params.tick_rate || null,
+            this.params.restore,
+            this.params.channel_id || null,
+        );
+        if (result.status === "error") {
+            error(`AnimateOSCReward: ${result.error}`);
+            await this.onFinished();
+            return;
+        }
+        this.animationId = result.data.id;
+    }
+
+    async isStillRunning(_context: RewardContext): Promise<boolean> {
+        return this.unlistenAnimation !== null;
+    }
+
+    async onFinished(): Promise<void> {
+        this.unlistenAnimation?.();
+        this.unlistenAnimation = null;
+        this.animationId = null;
+
+        this.finishCallback?.();
+    }
+
+    async onCancel(_context: RewardContext): Promise<void> {
+        if (this.animationId === null) {
+            await this.onFinished();
+            return;
+        }
+
+        // The ended event finishes the reward
+        await commands.stopOscAnimation(this.animationId);
+    }
+}
@@ -1,5 +1,7 @@
+import { commands } from "../../bindings";
 import { RewardInstance, type RewardContext } from "./types";
 import { SetOSCReward } from "./set-osc";
+import { AnimateOSCReward } from "./animate-osc";
 
 export type CancelOSCRewardParams = {
     id: string;
@@ -8,19 +10,23 @@ export type CancelOSCRewardParams = {
 export class CancelOSCReward extends RewardInstance<CancelOSCRewardParams> {
     static id = "cancel-osc-reward";
     static title = "Cancel OSC Reward";
-    static description = "Cancel any active OSC rewards (optionally for a specific channel)";
+    static description = "Cancel any active OSC rewards and animations (optionally for a specific channel)";
     reward = CancelOSCReward;
 
     async readyToStart(_context: RewardContext): Promise<boolean> {
         return true;
     }
     async onStart(context: RewardContext): Promise<void> {
-        const setOscRewards: SetOSCReward[] = context.runningRewards.filter((r) => r instanceof SetOSCReward);
+        const oscRewards: (SetOSCReward | AnimateOSCReward)[] = context.runningRewards.filter((r) => r instanceof SetOSCReward || r instanceof AnimateOSCReward);
 
-        for (const reward of setOscRewards) {
+        for (const reward of oscRewards) {
             if (!this.params.channel_id || reward.params.channel_id === this.params.channel_id)
                 await reward.onCancel(context);
         }
+
+        // Effects and animations started before the app was reloaded have no reward left to cancel them
+        await commands.cancelOscEffects(this.params.channel_id || null);
+        await commands.stopOscAnimations(this.params.channel_id || null);
     }
     async isStillRunning(_context: RewardContext): Promise<boolean> {
         return false;
@@ -0,0 +1,82 @@
+import { commands, events, type InputStep } from "../../bindings";
+import { CancellableReward, type RewardContext } from "./types";
+import { error } from "@tauri-apps/plugin-log";
+import type { UnlistenFn } from "@tauri-apps/api/event";
+
+export type InputSequenceRewardParams = {
+    id: string;
+    steps: InputStep[];
+    channel_id: string;
+}
+export class InputSequenceReward extends CancellableReward<InputSequenceRewardParams> {
+    static id = "input-sequence-reward";
+    static title = "Input Sequence Reward";
+    static description = "Press VRChat inputs such as jumping or turning";
+    reward = InputSequenceReward;
+
+    sequenceId: number | null = null;
+    unlistenSequence: UnlistenFn | null = null;
+
+    constructor(params: Partial<InputSequenceRewardParams>) {
+        super({
+            id: params.id ?? crypto.randomUUID(),
+            steps: params.steps ?? [{ Press: { button: "Jump", hold_ms: 200 } }],
+            channel_id: params.channel_id ?? "",
+        });
+    }
+
+    async validate(): Promise<string | null> {
+        if (this.params.steps.length === 0) {
+            return "Input sequence cannot be empty.";
+        }
+        return null;
+    }
+
+    async readyToStart(context: RewardContext): Promise<boolean> {
+        // The backend plays one sequence at a time, a new one stops the running one
+        let runningRewards = context.runningRewards.filter((r) => r instanceof InputSequenceReward);
+        if (this.params.channel_id !== "" && runningRewards.find((r) => r.params.channel_id === this.params.channel_id)) {
+            return false;
+        }
+
+        return true;
+    }
+
+    async onStart(_context: RewardContext): Promise<void> {
+        this.unlistenSequence = await events.inputSequenceEndedEvent.listen((event) => {
+            if (event.payload.id === this.sequenceId) {
+                this.onFinished();
+            }
+        });
+
+        const result = await commands.runInputSequence(this.params.steps);
+        if (result.status === "error") {
+            error(`InputSequenceReward: ${result.error}`);
+            await this.onFinished();
+            return;
+        }
+        this.sequenceId = result.data;
+    }
+
+    async isStillRunning(_context: RewardContext): Promise<boolean> {
+        return this.unlistenSequence !== null;
+    }
+
+    async onFinished(): Promise<void> {
+        this.unlistenSequence?.();
+        this.unlistenSequence = null;
+        this.sequenceId = null;
+
+        this.finishCallback?.();
+    }
+
+    async onCancel(_context: RewardContext): Promise<void> {
+        if (this.sequenceId === null) {
+            await this.onFinished();
+            return;
+        }
+
+        // The ended event finishes the reward
+        await commands.stopInputSequence();
+    }
+}
@@ -0,0 +1,53 @@
+import { commands } from "../../bindings";
+import { RewardInstance, type RewardContext } from "./types";
+import { error } from "@tauri-apps/plugin-log";
+
+export type SendChatboxRewardParams = {
+    id: string;
+    // Placeholders such as {message_sender} are filled in from the trigger's values
+    message: string;
+    // 0 leaves the message up until VRChat hides it
+    clear_after_ms: number;
+    notify: boolean;
+}
+export class SendChatboxReward extends RewardInstance<SendChatboxRewardParams> {
+    static id = "send-chatbox-reward";
+    static title = "Send Chatbox Reward";
+    static description = "Show a message in the VRChat chatbox";
+    reward = SendChatboxReward;
+
+    constructor(params: Partial<SendChatboxRewardParams>) {
+        super({
+            id: params.id ?? crypto.randomUUID(),
+            message: params.message ?? "",
+            clear_after_ms: params.clear_after_ms ?? 0,
+            notify: params.notify ?? false,
+        });
+    }
+
+    async readyToStart(_context: RewardContext): Promise<boolean> {
+        return true;
+    }
+    async onStart(context: RewardContext): Promise<void> {
+        // The backend queues the message behind VRChat's rate limit, so this doesn't wait for it to show
+        const result = await commands.sendChatbox({
+            template: this.params.message,
+            variables: { ...context.global_values, ...context.trigger_values },
+            clear_after_ms: this.params.clear_after_ms || null,
+            notify: this.params.notify,
+        });
+
+        if (result.status === "error") {
+            error(`SendChatboxReward: ${result.error}`);
+        }
+    }
+    async isStillRunning(_context: RewardContext): Promise<boolean> {
+        return false;
+    }
+    async validate(): Promise<string | null> {
+        if (!this.params.message.trim()) {
+            return "Message cannot be empty.";
+        }
+        return null;
+    }
+}
@@ -2,7 +2,7 @@ import { cachedAvatarStore } from "$lib/avatar-list-cache";
 import { get } from "svelte/store";
 import { commands } from "../../bindings";
 import { CancellableReward, type RewardContext } from "./types";
-import { oscStateStore } from "$lib/stores/global";
+import { currentAvatarStore } from "$lib/stores/global";
 import { info } from "@tauri-apps/plugin-log";
 import { rewardStore, updateContext } from "$lib/stores/rewards";
 
@@ -69,13 +69,13 @@ export class SetAvatarReward extends CancellableReward<SetAvatarRewardParams> {
             if (this.caughtPreviousAvatarId) { // We caught it in readyToStart when we were added to the queue
                 this.params.return_avatar_id = this.caughtPreviousAvatarId;
                 this.caughtPreviousAvatarId = null;
-            } else { // We are starting immediately, so get the current avatar ID from OSC
-                const currentAvatarId = get(oscStateStore)["/avatar/change"];
+            } else { // We are starting immediately, so return to the avatar the backend saw last
+                const currentAvatar = get(currentAvatarStore);
 
-                if (currentAvatarId && "String" in currentAvatarId) {
-                    this.params.return_avatar_id = currentAvatarId.String;
+                if (currentAvatar) {
+                    this.params.return_avatar_id = currentAvatar.id;
                 } else {
-                    info("SetAvatarReward: No current avatar ID found in OSC state store.");
+                    info("SetAvatarReward: No current avatar reported by VRChat yet.");
                     this.params.return_avatar_id = undefined;
                 }
             }
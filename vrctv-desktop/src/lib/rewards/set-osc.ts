@@ -1,9 +1,10 @@
 import { get } from "svelte/store";
-import { commands, type Result } from "../../bindings";
+import { commands, events, type Result } from "../../bindings";
 import { CancellableReward, type RewardContext } from "./types";
-import { oscStateStore } from "$lib/stores/global";
-import { info } from "@tauri-apps/plugin-log";
-import { rewardStore, updateContext } from "$lib/stores/rewards";
+import { currentAvatarStore } from "$lib/stores/global";
+import { error } from "@tauri-apps/plugin-log";
+import { rewardStore } from "$lib/stores/rewards";
+import type { UnlistenFn } from "@tauri-apps/api/event";
 import type { KV } from "$lib/triggers/types";
 
 export type SetOSCRewardParams = {
@@ -14,6 +15,8 @@ export type SetOSCRewardParams = {
     return_to: "previous" | "specific";
     return_params?: KV;
     timeout_ms: number;
+    // The value of the reward with the highest priority is shown while several set the same parameter
+    priority: number;
 }
 export class SetOSCReward extends CancellableReward<SetOSCRewardParams> {
     static id = "set-osc-reward";
@@ -21,8 +24,10 @@ export class SetOSCReward extends CancellableReward<SetOSCRewardParams> {
     static description = "Set OSC parameters for a duration"
     reward = SetOSCReward;
 
-    finishTimeout: number | null = null;
-    caughtPreviousParams: KV | null = null;
+    effectIds: Set<number> = new Set();
+    unlistenEffects: UnlistenFn | null = null;
+    // Set until every effect is started, so an early ended event doesn't finish the reward
+    starting = false;
 
     constructor(params: Partial<SetOSCRewardParams>) {
         super({
@@ -33,6 +38,7 @@ export class SetOSCReward extends CancellableReward<SetOSCRewardParams> {
             channel_id: params.channel_id || "",
             return_params: params.return_params || {},
             timeout_ms: params.timeout_ms || 0,
+            priority: params.priority || 0,
         });
     }
 
@@ -40,23 +46,8 @@ export class SetOSCReward extends CancellableReward<SetOSCRewardParams> {
         return null;
     }
 
-    findPreviousParam(rewards: SetOSCReward[], key: string): string | null {
-        for (const reward of rewards) {
-            if (reward.params.return_to === "previous" && reward.params.return_params?.[key]) {
-                return reward.params.return_params[key];
-            }
-        }
-        return null;
-    }
-
     currentAvatarId(): string {
-        const currentAvatarId = get(oscStateStore)["/avatar/change"];
-
-        if (currentAvatarId && "String" in currentAvatarId) {
-            return currentAvatarId.String;
-        } else {
-            return "";
-        }
+        return get(currentAvatarStore)?.id ?? "";
     }
 
     async readyToStart(context: RewardContext): Promise<boolean> {
@@ -64,22 +55,8 @@ export class SetOSCReward extends CancellableReward<SetOSCRewardParams> {
             return false;
         }
 
+        // Overlapping rewards are stacked by the backend, channels let rewards wait for each other instead
         let runningRewards = context.runningRewards.filter((r) => r instanceof SetOSCReward);
-
-        if (this.caughtPreviousParams === null && this.params.return_to === "previous") {
-            // It will return to the previous params, which works if there isn't a queued SetOSCReward, but if there is, it will try to return to that one instead, so we catch the previous params from this one
-            let previousParams: KV = {};
-
-            for (const key of Object.keys(this.params.params)) {
-                const previousParam = this.findPreviousParam(runningRewards as SetOSCReward[], key);
-                if (previousParam !== null) {
-                    previousParams[key] = previousParam;
-                }
-            }
-
-            this.caughtPreviousParams = previousParams;
-        }
-
         if (this.params.channel_id !== "" && runningRewards.find((r) => r.params.channel_id === this.params.channel_id)) {
             return false;
         }
@@ -87,66 +64,91 @@ export class SetOSCReward extends CancellableReward<SetOSCRewardParams> {
         return true;
     }
 
-    setParams(params: KV): Promise<Result<null, string>[]> {
+    async setParams(params: KV): Promise<Result<null, string>[]> {
         const promises: Promise<Result<null, string>>[] = [];
 
         for (const [key, value] of Object.entries(params)) {
             promises.push(commands.setOsc(key, value));
         }
 
-        return Promise.all(promises);
+        // Unknown parameters and values that don't fit the parameter's type are rejected
+        const results = await Promise.all(promises);
+        for (const result of results) {
+            if (result.status === "error") {
+                error(`SetOSCReward: ${result.error}`);
+            }
+        }
+        return results;
     }
 
-    async onStart(context: RewardContext): Promise<void> {
-        if (this.params.return_to === "previous") {
-            for (const key in this.params.params) {
-                if (this.caughtPreviousParams && this.caughtPreviousParams[key]) {
-                    this.params.return_params = this.params.return_params || {};
-                    this.params.return_params[key] = this.caughtPreviousParams[key];
-                } else {
-                    const currentValue = get(oscStateStore)[key];
-
-                    if (currentValue) {
-                        if ("String" in currentValue) {
-                            this.params.return_params = this.params.return_params || {};
-                            this.params.return_params[key] = currentValue["String"];
-                        } else if ("Bool" in currentValue) {
-                            this.params.return_params = this.params.return_params || {};
-                            this.params.return_params[key] = currentValue["Bool"] ? "true" : "false";
-                        } else if ("Int" in currentValue) {
-                            this.params.return_params = this.params.return_params || {};
-                            this.params.return_params[key] = currentValue["Int"].toString();
-                        } else if ("Float" in currentValue) {
-                            this.params.return_params = this.params.return_params || {};
-                            this.params.return_params[key] = currentValue["Float"].toString();
-                        } else {
-                            info(`SetOSCReward: No current value found in OSC state store for key ${key}.`);
-                        }
-                    }
+    async onStart(_context: RewardContext): Promise<void> {
+        if (this.params.timeout_ms <= 0) {
+            // Without a timeout the parameters are set for good
+            await this.setParams(this.params.params);
+            return;
+        }
+
+        // The backend restores the previous values when the timeout runs out, even if the app was reloaded
+        this.starting = true;
+        this.unlistenEffects = await events.oscEffectEndedEvent.listen((event) => {
+            if (this.effectIds.delete(event.payload.effect.id) && this.effectIds.size === 0 && !this.starting) {
+                this.onFinished();
+            }
+        });
+
+        for (const [key, value] of Object.entries(this.params.params)) {
+            const result = await commands.startOscEffect(key, value, this.params.timeout_ms, this.params.priority, this.params.channel_id || null);
+            if (result.status === "error") {
+                error(`SetOSCReward: ${result.error}`);
+                continue;
+            }
+            this.effectIds.add(result.data.id);
+        }
+
+        // An effect can end before its id comes back, its ended event was missed then
+        const running = await commands.getOscEffects();
+        if (running.status === "ok") {
+            const runningIds = new Set(running.data.map((effect) => effect.id));
+            for (const id of [...this.effectIds]) {
+                if (!runningIds.has(id)) {
+                    this.effectIds.delete(id);
                 }
             }
+        } else {
+            error(`SetOSCReward: ${running.error}`);
         }
+        this.starting = false;
 
-        await this.setParams(this.params.params);
-        if (this.params.timeout_ms > 0) {
-            this.finishTimeout = setTimeout(() => this.onCancel(updateContext(context)), this.params.timeout_ms);
+        if (this.effectIds.size === 0) {
+            await this.onFinished();
         }
     }
 
     async isStillRunning(_context: RewardContext): Promise<boolean> {
-        return this.finishTimeout !== null;
+        return this.unlistenEffects !== null;
     }
 
-    async onCancel(_context: RewardContext): Promise<void> {
-        if (this.finishTimeout !== null) {
-            clearTimeout(this.finishTimeout);
-            this.finishTimeout = null;
-        }
+    async onFinished(): Promise<void> {
+        this.unlistenEffects?.();
+        this.unlistenEffects = null;
+        this.effectIds.clear();
 
-        if (this.params.return_params) {
+        if (this.params.return_to === "specific" && this.params.return_params) {
             await this.setParams(this.params.return_params);
         }
 
         this.finishCallback?.();
     }
-}
\ No newline at end of file
+
+    async onCancel(_context: RewardContext): Promise<void> {
+        if (this.effectIds.size === 0) {
+            await this.onFinished();
+            return;
+        }
+
+        // Each ended effect is reported back, the last one finishes the reward
+        for (const id of [...this.effectIds]) {
+            await commands.cancelOscEffect(id);
+        }
+    }
+}
@@ -1,7 +1,9 @@
-import { writable, type Writable } from "svelte/store";
+import { derived, get, writable, type Readable, type Writable } from "svelte/store";
 import * as ENV from "$env/static/public";
-import type { OscValue } from "../../bindings";
+import type { OscValue, WornAvatar } from "../../bindings";
 import type { ConnectResponse } from "../../../../vrctv-common/bindings/ConnectResponse";
+import type { DeviceList } from "../../../../vrctv-common/bindings/DeviceList";
+import type { DeviceRole } from "../../../../vrctv-common/bindings/DeviceRole";
 import { persisted } from "svelte-persisted-store";
 
 interface LocalState {
@@ -12,6 +14,8 @@ interface LocalState {
 type ClientState = LocalState & ConnectResponse;
 
 export const oscStateStore: Writable<{ [key: string]: OscValue }> = writable({});
+/// The avatar VRChat reported last, null until VRChat is seen
+export const currentAvatarStore: Writable<WornAvatar | null> = writable(null);
 export const clientStateStore: Writable<ClientState> = writable({
     connected: false,
     id: null,
@@ -24,4 +28,18 @@ export const clientStateStore: Writable<ClientState> = writable({
 })
 
 export const wssUrl: Writable<string> = persisted("PUBLIC_WEBSOCKET_URL", "PUBLIC_WEBSOCKET_URL" in ENV ? ENV.PUBLIC_WEBSOCKET_URL as string : "");
-export const backendUrl: Writable<string> = persisted("PUBLIC_BACKEND_URL", "PUBLIC_BACKEND_URL" in ENV ? ENV.PUBLIC_BACKEND_URL as string : "");
\ No newline at end of file
+export const backendUrl: Writable<string> = persisted("PUBLIC_BACKEND_URL", "PUBLIC_BACKEND_URL" in ENV ? ENV.PUBLIC_BACKEND_URL as string : "");
+
+export const deviceName: Writable<string> = persisted("deviceName", "");
+/// Kept secret by this device, the server recognises it by this when it reconnects
+export const deviceKey: Writable<string> = persisted("deviceKey", "");
+if (!get(deviceKey)) {
+    deviceKey.set(crypto.randomUUID());
+}
+export const deviceRole: Writable<DeviceRole> = persisted("deviceRole", "Controller");
+export const devicesStore: Writable<DeviceList | null> = writable(null);
+/// The role the server gave this device, the requested one until the device list arrives
+export const ownDeviceRole: Readable<DeviceRole> = derived([devicesStore, deviceRole], ([devices, requested]) =>
+    devices?.devices.find(d => d.id === devices.own_id)?.role ?? requested);
+/// VRChat's data folder, empty to find it automatically
+export const vrchatDir: Writable<string> = persisted("vrchatDir", "");
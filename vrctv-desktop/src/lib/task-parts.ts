@@ -10,12 +10,14 @@ import { TwitchBitDonationTrigger } from "./triggers/twitch-bit-donation";
 import { TwitchChannelPointsTrigger } from "./triggers/twitch-channel-points";
 import { TwitchMessageTrigger } from "./triggers/twitch-message";
 import { TwitchWhisperTrigger } from "./triggers/twitch-whisper";
+import { WearingAvatarTrigger } from "./triggers/wearing-avatar";
 import type { Component } from "svelte";
 import SetAvatarEditor from "./components/rewards/set-avatar-editor.svelte";
 import StreamlabsDonationEditor from "./components/triggers/streamlabs-donation-editor.svelte";
 import TwitchBitDonationEditor from "./components/triggers/twitch-bit-donation-editor.svelte";
 import TwitchChannelPointsEditor from "./components/triggers/twitch-channel-points-editor.svelte";
 import TwitchMessagishEditor from "./components/triggers/twitch-messagish-editor.svelte";
+import WearingAvatarEditor from "./components/triggers/wearing-avatar-editor.svelte";
 import { SetOSCReward } from "./rewards/set-osc";
 import { CancelOSCReward } from "./rewards/cancel-osc";
 import SetOscEditor from "./components/rewards/set-osc-editor.svelte";
@@ -27,6 +29,12 @@ import { CancelOverlayReward } from "./rewards/cancel-overlay";
 import CancelOverlayEditor from "./components/rewards/cancel-overlay-editor.svelte";
 import { SetWarudoOscReward } from "./rewards/set-warudo-osc";
 import SetWarudoOscEditor from "./components/rewards/set-warudo-osc-editor.svelte";
+import { AnimateOSCReward } from "./rewards/animate-osc";
+import AnimateOscEditor from "./components/rewards/animate-osc-editor.svelte";
+import { SendChatboxReward } from "./rewards/send-chatbox";
+import SendChatboxEditor from "./components/rewards/send-chatbox-editor.svelte";
+import { InputSequenceReward } from "./rewards/input-sequence";
+import InputSequenceEditor from "./components/rewards/input-sequence-editor.svelte";
 
 export const rewards: {
     [id: string]: {
@@ -49,6 +57,10 @@ export const rewards: {
         reward: CancelOSCReward,
         editor: CancelOscEditor,
     },
+    [AnimateOSCReward.id]: {
+        reward: AnimateOSCReward,
+        editor: AnimateOscEditor,
+    },
     [SetOverlayReward.id]: {
         reward: SetOverlayReward,
         editor: SetOverlayEditor
@@ -61,6 +73,14 @@ export const rewards: {
         reward: SetWarudoOscReward,
         editor: SetWarudoOscEditor,
     },
+    [SendChatboxReward.id]: {
+        reward: SendChatboxReward,
+        editor: SendChatboxEditor,
+    },
+    [InputSequenceReward.id]: {
+        reward: InputSequenceReward,
+        editor: InputSequenceEditor,
+    },
 }
 export const triggers: {
     [id: string]: {
@@ -96,6 +116,10 @@ export const triggers: {
         trigger: TwitchWhisperTrigger,
         editor: TwitchMessagishEditor,
     },
+    [WearingAvatarTrigger.id]: {
+        trigger: WearingAvatarTrigger,
+        editor: WearingAvatarEditor,
+    },
 };
 
 export function restoreReward<P>(stored: StoredReward): RewardInstance<P> {
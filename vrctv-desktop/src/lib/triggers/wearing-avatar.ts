@@ -0,0 +1,33 @@
+import type { RewardContext } from "$lib/rewards/types";
+import { get } from "svelte/store";
+import { currentAvatarStore } from "$lib/stores/global";
+import { TriggerInstance, type KV, type TriggerSource } from "./types";
+
+export type WearingAvatarTriggerParams = {
+    avatar_id?: string;
+}
+// Doesn't fire on its own, it is meant to be combined with an event in an AND trigger
+export class WearingAvatarTrigger extends TriggerInstance<WearingAvatarTriggerParams> {
+    static id = "wearing-avatar-trigger";
+    static title = "Wearing Avatar Trigger";
+    static description = "Only passes while wearing the selected avatar";
+    trigger = WearingAvatarTrigger;
+
+    async evaluate(_source: TriggerSource): Promise<boolean> {
+        const currentAvatar = get(currentAvatarStore);
+
+        return !!this.params.avatar_id && currentAvatar?.id === this.params.avatar_id;
+    }
+
+    async getContext(_context: RewardContext): Promise<KV> {
+        const currentAvatar = get(currentAvatarStore);
+        if (!currentAvatar) {
+            return {};
+        }
+
+        return {
+            avatar_id: currentAvatar.id,
+            avatar_name: currentAvatar.config?.name ?? "",
+        }
+    }
+}
@@ -1,6 +1,7 @@
 import type { ClientMessage } from "../../../vrctv-common/bindings/ClientMessage";
 import type { ServerMessage } from "../../../vrctv-common/bindings/ServerMessage";
-import { clientStateStore } from "./stores/global";
+import type { DeviceRole } from "../../../vrctv-common/bindings/DeviceRole";
+import { backendUrl, clientStateStore, deviceKey, deviceName, deviceRole, devicesStore, ownDeviceRole } from "./stores/global";
 import toast from "svelte-french-toast";
 import { debug, error, info } from "@tauri-apps/plugin-log";
 import { commands } from "../bindings";
@@ -11,9 +12,13 @@ import type { Message, MessageKind } from "@tauri-apps/plugin-websocket";
 import { eventLogStore, TaskState, taskStateStore } from "./stores/debug";
 import { customRewardsStore, rewardHandler } from "./stores/rewards";
 import { getVersion } from "@tauri-apps/api/app";
+import { openUrl } from "@tauri-apps/plugin-opener";
 
 export const serverConnection = writable<ServerConnection | null>(null);
 
+/// vrctv_common::PROTOCOL_VERSION, the server refuses clients older than it supports
+const PROTOCOL_VERSION = 2;
+
 class ServerConnection {
     private websocket: WebSocket;
     private retryMethod: () => void;
@@ -51,6 +56,7 @@ class ServerConnection {
             error(`Failed to disconnect websocket: ${e}`);
         }
         this.connected = false;
+        devicesStore.set(null);
     }
 
     send(data: ClientMessage, queueIfNotLoggedIn = true) {
@@ -117,14 +123,25 @@ export async function onConnect(ws: WebSocket, retryMethod: () => void): Promise
 
     if (stateToken) {
         clientStateStore.update(state => ({ ...state, id: stateToken }));
-        conn.send({ type: "connect", state_token: stateToken, client_version: version }, false);
+        conn.send({ type: "connect", state_token: stateToken, client_version: version, protocol_version: PROTOCOL_VERSION }, false);
     } else {
-        conn.send({ type: "codeRequest", client_version: version } as ClientMessage, false);
+        conn.send({ type: "codeRequest", client_version: version, protocol_version: PROTOCOL_VERSION } as ClientMessage, false);
     }
+    registerDevice(conn);
 
     return conn;
 }
 
+/// Tell the server what this device is called and whether it may change things on Twitch
+export function registerDevice(conn: ServerConnection) {
+    conn.send({ type: "registerDevice", name: get(deviceName), role: get(deviceRole), key: get(deviceKey) }, false);
+}
+
+/// Change the role of another device on this connection, only controllers may do this
+export function setDeviceRole(conn: ServerConnection, deviceId: string, role: DeviceRole) {
+    conn.send({ type: "setDeviceRole", device_id: deviceId, role }, false);
+}
+
 export async function sendNotif(title: string, message: string) {
     info(`Sending notification: ${title} - ${message}`);
     let permissionGranted = await isPermissionGranted();
@@ -195,6 +212,11 @@ export function handleMessage(message: MessageKind<"Text", string>) {
             clientStateStore.update(state => ({ ...state, ...rest }));
             break;
         }
+        case "authorizeResponse": {
+            const provider = parsed.provider === "Twitch" ? "twitch" : "streamlabs";
+            openUrl(`${get(backendUrl)}${provider}/auth/${encodeURIComponent(parsed.state)}`);
+            break;
+        }
         case "changeAvatar":
             commands.changeAvatar(parsed.id);
             info(`Changing avatar to ${parsed.id}`);
@@ -234,6 +256,10 @@ export function handleMessage(message: MessageKind<"Text", string>) {
                 error(`Task ${parsed.request_id} completed with message: ${parsed.message}`);
             }
             break;
+        case "devices":
+            info(`Devices on this connection: ${parsed.devices.map(d => d.name).join(", ")}`);
+            devicesStore.set({ own_id: parsed.own_id, devices: parsed.devices });
+            break;
         case "customRewards":
             info(`Received custom rewards: ${JSON.stringify(parsed.rewards)}`);
             customRewardsStore.set(parsed.rewards);
@@ -243,7 +269,10 @@ export function handleMessage(message: MessageKind<"Text", string>) {
             // toast.success(`Twitch event: ${JSON.stringify(parsed.event)}`);
 
             eventLogStore.update(logs => ([...logs, parsed.event]));
-            get(rewardHandler).handleEvent(parsed.event);
+            // Viewers only show events, the controllers run the rewards
+            if (get(ownDeviceRole) !== "Viewer") {
+                get(rewardHandler).handleEvent(parsed.event);
+            }
 
             break;
         case "streamLabsEvent":
@@ -252,8 +281,10 @@ export function handleMessage(message: MessageKind<"Text", string>) {
 
             eventLogStore.update(logs => ([...logs, ...parsed.events]));
 
-            for (const event of parsed.events) {
-                get(rewardHandler).handleEvent(event);
+            if (get(ownDeviceRole) !== "Viewer") {
+                for (const event of parsed.events) {
+                    get(rewardHandler).handleEvent(event);
+                }
             }
 
             break;
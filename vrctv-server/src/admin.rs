@@ -0,0 +1,381 @@
+use axum::{
+    Extension, Json, Router,
+    extract::{
+        Path, Request, State,
+        ws::{CloseFrame, Message, close_code},
+    },
+    http::{StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use twitch_api::twitch_oauth2::{AccessToken, ClientId};
+use vrctv_common::{DeviceInfo, Notify, ServerMessage};
+
+use crate::{
+    AppState, bus::SessionEvent, config::config, db::Database, server::twitch_token_from_key,
+};
+
+pub mod cli;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdminUser {
+    pub id: i64,
+    /// RFC 3339 timestamp of when the user first connected
+    pub joined_at: String,
+    /// The state token the user's token is bound to, if they still have one
+    pub state_token: Option<String>,
+    /// Whether a client is connected with this user right now
+    pub connected: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdminUsers {
+    pub twitch_users: Vec<AdminUser>,
+    pub streamlabs_users: Vec<AdminUser>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdminSession {
+    pub state_token: String,
+    /// Number of connected clients sharing the state token
+    pub clients: usize,
+    /// The devices attached to the session on this server
+    pub devices: Vec<DeviceInfo>,
+    pub twitch_login: Option<String>,
+    pub streamlabs_login: Option<String>,
+    /// Whether the session has an open EventSub websocket
+    pub eventsub: bool,
+    /// Whether the session has an open Streamlabs socket
+    pub streamlabs_socket: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdminNotify {
+    pub title: String,
+    pub message: String,
+    /// The sessions to notify, every session if empty
+    #[serde(default)]
+    pub state_tokens: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdminResult {
+    /// Number of sessions on this server the action applied to, other servers apply it to theirs
+    pub affected: usize,
+}
+
+type AdminError = (StatusCode, String);
+
+/// The admin API, only reachable with the configured admin token
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/users", get(list_users))
+        .route("/sessions", get(list_sessions))
+        .route("/sessions/{state}", delete(disconnect_session))
+        .route("/users/{provider}/{id}/revoke", post(revoke_user))
+        .route("/notify", post(notify))
+        .route_layer(middleware::from_fn(require_admin))
+}
+
+async fn require_admin(request: Request, next: Next) -> Response {
+    let Some(admin_token) = config().await.admin_token() else {
+        return (StatusCode::NOT_FOUND, "Admin API is disabled").into_response();
+    };
+
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .is_some_and(|token| constant_time_eq(token.as_bytes(), admin_token.as_bytes()));
+
+    if !authorized {
+        return (StatusCode::UNAUTHORIZED, "Invalid admin token").into_response();
+    }
+
+    next.run(request).await
+}
+
+/// Compare without bailing out early, so the token can't be guessed from response times
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn database_error(e: impl std::fmt::Display) -> AdminError {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("Database error: {}", e),
+    )
+}
+
+async fn list_users(
+    Extension(database): Extension<Database>,
+    State(app_state): State<AppState>,
+) -> Result<Json<AdminUsers>, AdminError> {
+    let connected = app_state
+        .connection_table
+        .lock()
+        .await
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let mut twitch_users = Vec::new();
+    for user in database.twitch_users().await.map_err(database_error)? {
+        let state_token = database
+            .twitch_key_by_user(user.id)
+            .await
+            .map_err(database_error)?
+            .map(|k| k.state);
+        twitch_users.push(AdminUser {
+            id: user.id,
+            joined_at: user.joined_at.to_rfc3339(),
+            connected: state_token.as_ref().is_some_and(|s| connected.contains(s)),
+            state_token,
+        });
+    }
+
+    let mut streamlabs_users = Vec::new();
+    for user in database.streamlabs_users().await.map_err(database_error)? {
+        let state_token = database
+            .streamlabs_key_by_user(user.id)
+            .await
+            .map_err(database_error)?
+            .map(|k| k.state);
+        streamlabs_users.push(AdminUser {
+            id: user.id,
+            joined_at: user.joined_at.to_rfc3339(),
+            connected: state_token.as_ref().is_some_and(|s| connected.contains(s)),
+            state_token,
+        });
+    }
+
+    Ok(Json(AdminUsers {
+        twitch_users,
+        streamlabs_users,
+    }))
+}
+
+async fn list_sessions(State(app_state): State<AppState>) -> Json<Vec<AdminSession>> {
+    let connections = app_state
+        .connection_table
+        .lock()
+        .await
+        .iter()
+        .map(|(state, c)| (state.clone(), c.clone()))
+        .collect::<Vec<_>>();
+
+    let mut sessions = Vec::with_capacity(connections.len());
+    for (state_token, connection) in connections {
+        let context = connection.context.lock().await;
+        sessions.push(AdminSession {
+            state_token,
+            clients: connection.sender.len(),
+            devices: connection.devices.iter().map(|d| d.info.clone()).collect(),
+            twitch_login: context.twitch.as_ref().map(|t| t.login.to_string()),
+            streamlabs_login: context.streamlabs.as_ref().map(|s| s.login.clone()),
+            eventsub: connection.twitch_connection.is_some(),
+            streamlabs_socket: connection.streamlabs_connection.is_some(),
+        });
+    }
+
+    Json(sessions)
+}
+
+/// What disconnecting a session reached
+#[derive(Debug, Clone, Copy)]
+pub struct Disconnected {
+    /// A live session for the state token was closed on this server
+    pub local: bool,
+    /// The other servers were told to close theirs
+    pub published: bool,
+}
+
+/// Close every client of a session, on every server
+pub async fn disconnect(app_state: &AppState, state_token: &str, reason: &str) -> Disconnected {
+    let local = disconnect_local(app_state, state_token, reason).await;
+
+    let published = match app_state
+        .session_bus
+        .publish(SessionEvent::Disconnect {
+            state_token: state_token.to_string(),
+            reason: reason.to_string(),
+        })
+        .await
+    {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Failed to publish disconnect for {}: {}", state_token, e);
+            false
+        }
+    };
+
+    Disconnected { local, published }
+}
+
+/// Close every client of a session held by this server
+pub async fn disconnect_local(app_state: &AppState, state_token: &str, reason: &str) -> bool {
+    let senders = match app_state.connection_table.lock().await.get(state_token) {
+        Some(connection) => connection.sender.clone(),
+        None => return false,
+    };
+
+    for sender in senders {
+        let _ = sender
+            .send(Message::Close(Some(CloseFrame {
+                code: close_code::POLICY,
+                reason: reason.into(),
+            })))
+            .await;
+    }
+
+    info!("Disconnected session {}: {}", state_token, reason);
+    true
+}
+
+async fn disconnect_session(
+    Path(state_token): Path<String>,
+    State(app_state): State<AppState>,
+) -> Result<(StatusCode, Json<AdminResult>), AdminError> {
+    match disconnect(&app_state, &state_token, "Disconnected by an administrator").await {
+        Disconnected { local: true, .. } => Ok((StatusCode::OK, Json(AdminResult { affected: 1 }))),
+        // The session may live on another server, which closes it when it gets the event
+        Disconnected {
+            published: true, ..
+        } => Ok((StatusCode::ACCEPTED, Json(AdminResult { affected: 0 }))),
+        Disconnected { .. } => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "No live session on this server and the other servers couldn't be reached".into(),
+        )),
+    }
+}
+
+/// Remove a user's stored token and revoke it where the provider allows it, live sessions using it
+/// are disconnected so they reconnect without it
+async fn revoke_user(
+    Path((provider, id)): Path<(String, i64)>,
+    Extension(database): Extension<Database>,
+    Extension(http_client): Extension<reqwest::Client>,
+    State(app_state): State<AppState>,
+) -> Result<Json<AdminResult>, AdminError> {
+    let state_token = match provider.as_str() {
+        "twitch" => {
+            let key = database
+                .twitch_key_by_user(id)
+                .await
+                .map_err(database_error)?
+                .ok_or((
+                    StatusCode::NOT_FOUND,
+                    "User has no stored token".to_string(),
+                ))?;
+
+            // Twitch only revokes live access tokens, so an expired one is refreshed first,
+            // otherwise the refresh token would keep the grant alive
+            let access_token = match twitch_token_from_key(&http_client, key.clone()).await {
+                Ok(token) => token.access_token,
+                Err(e) => {
+                    error!(
+                        "Failed to refresh Twitch token for {} before revoking it: {}",
+                        id, e
+                    );
+                    AccessToken::new(key.authentication.clone())
+                }
+            };
+
+            let client_id = ClientId::new(config().await.twitch_oauth().client().to_string());
+            if let Err(e) = access_token.revoke_token(&http_client, &client_id).await {
+                // The token may already be expired or revoked, it is removed either way
+                error!("Failed to revoke Twitch token for {}: {}", id, e);
+            }
+
+            database
+                .delete_twitch_key(key.id)
+                .await
+                .map_err(database_error)?;
+            key.state
+        }
+        // Streamlabs tokens can't be revoked, forgetting them is all we can do
+        "streamlabs" => {
+            let key = database
+                .streamlabs_key_by_user(id)
+                .await
+                .map_err(database_error)?
+                .ok_or((
+                    StatusCode::NOT_FOUND,
+                    "User has no stored token".to_string(),
+                ))?;
+
+            database
+                .delete_streamlabs_key(key.id)
+                .await
+                .map_err(database_error)?;
+            key.state
+        }
+        _ => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Provider must be twitch or streamlabs".into(),
+            ));
+        }
+    };
+
+    let disconnected = disconnect(&app_state, &state_token, "Your token was revoked").await;
+    info!("Revoked {} token for user {}", provider, id);
+
+    Ok(Json(AdminResult {
+        affected: disconnected.local as usize,
+    }))
+}
+
+async fn notify(
+    State(app_state): State<AppState>,
+    Json(request): Json<AdminNotify>,
+) -> Json<AdminResult> {
+    let connections = app_state
+        .connection_table
+        .lock()
+        .await
+        .iter()
+        .filter(|(state, _)| {
+            request.state_tokens.is_empty() || request.state_tokens.contains(state)
+        })
+        .map(|(_, c)| c.clone())
+        .collect::<Vec<_>>();
+
+    let message = ServerMessage::Notify(Notify {
+        title: request.title,
+        message: request.message,
+    });
+
+    let mut affected = 0;
+    for connection in connections {
+        match connection.send(message.clone()).await {
+            Ok(()) => affected += 1,
+            Err(e) => error!("Failed to send admin notification: {}", e),
+        }
+    }
+
+    // Sessions on other servers are notified by them
+    let events = if request.state_tokens.is_empty() {
+        vec![SessionEvent::SendAll { message }]
+    } else {
+        request
+            .state_tokens
+            .into_iter()
+            .map(|state_token| SessionEvent::Send {
+                state_token,
+                message: message.clone(),
+            })
+            .collect()
+    };
+    for event in events {
+        if let Err(e) = app_state.session_bus.publish(event).await {
+            error!("Failed to publish admin notification: {}", e);
+        }
+    }
+
+    Json(AdminResult { affected })
+}
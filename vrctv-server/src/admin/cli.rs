@@ -0,0 +1,88 @@
+use std::env;
+
+use reqwest::Method;
+
+use crate::admin::AdminNotify;
+
+const USAGE: &str = "Usage: vrctv-server admin <command>
+
+Commands:
+  users                             List Twitch and Streamlabs users
+  sessions                          List live sessions
+  disconnect <state_token>          Disconnect every client of a session
+  revoke <twitch|streamlabs> <id>   Revoke a user's token and disconnect their session
+  notify <title> <message> [state_token...]
+                                    Send a notification to the given sessions, or all of them
+
+Talks to the running server at ADMIN_URL (defaults to http://HOST:PORT) using ADMIN_TOKEN";
+
+/// Run an admin command against a running server
+pub async fn run(args: &[String]) -> Result<(), String> {
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+
+    let (method, path, body) = match args.as_slice() {
+        ["users"] => (Method::GET, "/admin/users".to_string(), None),
+        ["sessions"] => (Method::GET, "/admin/sessions".to_string(), None),
+        ["disconnect", state_token] => (
+            Method::DELETE,
+            format!("/admin/sessions/{}", state_token),
+            None,
+        ),
+        ["revoke", provider, id] => (
+            Method::POST,
+            format!("/admin/users/{}/{}/revoke", provider, id),
+            None,
+        ),
+        ["notify", title, message, state_tokens @ ..] => (
+            Method::POST,
+            "/admin/notify".to_string(),
+            Some(AdminNotify {
+                title: title.to_string(),
+                message: message.to_string(),
+                state_tokens: state_tokens.iter().map(|s| s.to_string()).collect(),
+            }),
+        ),
+        _ => return Err(USAGE.into()),
+    };
+
+    let token = env::var("ADMIN_TOKEN").map_err(|_| "ADMIN_TOKEN must be set")?;
+    let base_url = env::var("ADMIN_URL").unwrap_or_else(|_| {
+        format!(
+            "http://{}:{}",
+            env::var("HOST").unwrap_or_else(|_| String::from("127.0.0.1")),
+            env::var("PORT").unwrap_or_else(|_| String::from("3000"))
+        )
+    });
+
+    let mut request = reqwest::Client::new()
+        .request(
+            method,
+            format!("{}{}", base_url.trim_end_matches('/'), path),
+        )
+        .bearer_auth(token);
+    if let Some(body) = body {
+        request = request.json(&body);
+    }
+
+    let response = request
+        .send()
+        .await
+        .map_err(|e| format!("Failed to reach the server at {}: {}", base_url, e))?;
+    let status = response.status();
+    let text = response
+        .text()
+        .await
+        .map_err(|e| format!("Failed to read the response: {}", e))?;
+
+    if !status.is_success() {
+        return Err(format!("Server returned {}: {}", status, text));
+    }
+
+    // Pretty print JSON responses, anything else is printed as-is
+    match serde_json::from_str::<serde_json::Value>(&text) {
+        Ok(value) => println!("{}", serde_json::to_string_pretty(&value).unwrap_or(text)),
+        Err(_) => println!("{}", text),
+    }
+
+    Ok(())
+}
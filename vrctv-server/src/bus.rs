@@ -0,0 +1,729 @@
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use vrctv_common::{AuthProvider, ChatCommand, ServerMessage};
+
+use crate::{AppState, admin, db::Database, server};
+
+mod memory;
+mod postgres;
+
+/// How long a server holds a session without renewing its claim, after which another can take it
+const SESSION_LEASE: Duration = Duration::from_secs(60);
+/// How often a server renews the claims on the sessions it holds
+const SESSION_RENEW_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Something that happened to a session, delivered to whichever server holds its websocket
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum SessionEvent {
+    /// An account was connected to the session, its token is loaded from the database
+    Authorized {
+        state_token: String,
+        provider: AuthProvider,
+    },
+    /// Send a message to every client of the session
+    Send {
+        state_token: String,
+        message: ServerMessage,
+    },
+    /// Send a message to every session
+    SendAll { message: ServerMessage },
+    /// Chat commands set by a client on another server, for the server that reads the chat
+    ChatCommands {
+        state_token: String,
+        prefix: String,
+        commands: Vec<ChatCommand>,
+    },
+    /// Close every client of the session
+    Disconnect { state_token: String, reason: String },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Envelope {
+    /// The server that published the event, which has already handled it
+    origin: String,
+    event: SessionEvent,
+}
+
+/// Carries published payloads between servers
+#[async_trait]
+pub trait BusBackend: Send + Sync {
+    async fn publish(&self, payload: String) -> Result<()>;
+
+    /// Start receiving payloads, including the ones published by this server
+    async fn subscribe(&self) -> Result<mpsc::UnboundedReceiver<String>>;
+}
+
+/// Routes session events between the servers sharing a backend
+#[derive(Clone)]
+pub struct SessionBus {
+    node_id: String,
+    backend: Arc<dyn BusBackend>,
+    /// Whether other servers share the backend, a lone server has nothing to claim or tell
+    shared: bool,
+}
+
+impl fmt::Debug for SessionBus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionBus")
+            .field("node_id", &self.node_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SessionBus {
+    /// Connect to the bus at `url` as `node_id`, a random id is used without one
+    /// `postgres://` and `postgresql://` urls use LISTEN/NOTIFY, without a url events stay in
+    /// this process
+    pub fn new(url: Option<&str>, node_id: Option<&str>) -> Result<Self> {
+        let backend: Arc<dyn BusBackend> = match url {
+            Some(url) if url.starts_with("postgres://") || url.starts_with("postgresql://") => {
+                Arc::new(postgres::PostgresBus::new(url)?)
+            }
+            Some(url) => anyhow::bail!("Unsupported session bus url: {}", url),
+            None => Arc::new(memory::MemoryBus::new()),
+        };
+
+        Ok(Self {
+            node_id: node_id
+                .map(str::to_string)
+                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            backend,
+            shared: url.is_some(),
+        })
+    }
+
+    /// Another server on the same backend, for running several in one process
+    pub fn node(&self) -> Self {
+        Self {
+            node_id: uuid::Uuid::new_v4().to_string(),
+            backend: self.backend.clone(),
+            shared: true,
+        }
+    }
+
+    /// Claim a session for this server, returning false if another server holds it
+    /// Only the holder connects to Twitch and Streamlabs for a session, the other servers get its
+    /// events over the bus
+    pub async fn claim(&self, database: &Database, state_token: &str) -> Result<bool> {
+        if !self.shared {
+            return Ok(true);
+        }
+
+        let now = unix_now();
+        database
+            .claim_session(
+                state_token,
+                &self.node_id,
+                now,
+                now + SESSION_LEASE.as_secs(),
+            )
+            .await
+    }
+
+    /// Let another server take a session once its last client on this server left
+    pub async fn release(&self, database: &Database, state_token: &str) -> Result<()> {
+        if !self.shared {
+            return Ok(());
+        }
+
+        database.release_session(state_token, &self.node_id).await
+    }
+
+    /// Drop the claims this server left behind when it stopped, along with any that expired, so
+    /// its clients don't wait for the leases to run out when they reconnect
+    pub async fn clear_claims(&self, database: &Database) -> Result<()> {
+        if !self.shared {
+            return Ok(());
+        }
+
+        let cleared = database
+            .clear_session_claims(&self.node_id, unix_now())
+            .await?;
+        if cleared > 0 {
+            info!("Cleared {} stale session claims", cleared);
+        }
+        Ok(())
+    }
+
+    /// Tell the other servers about an event, after applying it to the sessions held here
+    /// Clients sharing a state token can be connected to different servers
+    pub async fn publish(&self, event: SessionEvent) -> Result<()> {
+        if !self.shared {
+            return Ok(());
+        }
+
+        let payload = serde_json::to_string(&Envelope {
+            origin: self.node_id.clone(),
+            event,
+        })?;
+        self.backend.publish(payload).await
+    }
+}
+
+/// Renew the claims on the sessions held by this server, and take over the sessions whose holder
+/// is gone
+/// A session another server took over while this one couldn't reach the database is closed here,
+/// as is one this server took over, so its clients reconnect and the holder connects to Twitch and
+/// Streamlabs for them
+pub async fn hold_sessions(app_state: AppState, database: Database) {
+    if !app_state.session_bus.shared {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(SESSION_RENEW_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let sessions = app_state
+            .connection_table
+            .lock()
+            .await
+            .iter()
+            .map(|(state_token, connection)| (state_token.clone(), connection.held))
+            .collect::<Vec<_>>();
+
+        for (state_token, held) in sessions {
+            let reason = match app_state.session_bus.claim(&database, &state_token).await {
+                Ok(claimed) if claimed == held => continue,
+                Ok(true) => {
+                    info!(
+                        "Taking over session {} from a server that left",
+                        state_token
+                    );
+                    "The server connected to Twitch for this session left, reconnect to continue"
+                }
+                Ok(false) => {
+                    warn!("Session {} is held by another server", state_token);
+                    "This session moved to another server, reconnect to continue"
+                }
+                Err(e) => {
+                    error!("Failed to renew the claim on {}: {}", state_token, e);
+                    continue;
+                }
+            };
+            admin::disconnect_local(&app_state, &state_token, reason).await;
+        }
+    }
+}
+
+/// Apply events published by other servers to the sessions held by this one
+pub async fn listen(app_state: AppState, database: Database, http_client: reqwest::Client) {
+    let bus = app_state.session_bus.clone();
+    let mut events = match bus.backend.subscribe().await {
+        Ok(events) => events,
+        Err(e) => {
+            error!("Failed to subscribe to the session bus: {}", e);
+            return;
+        }
+    };
+    info!("Listening on the session bus as {}", bus.node_id);
+
+    while let Some(payload) = events.recv().await {
+        let envelope: Envelope = match serde_json::from_str(&payload) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                error!("Invalid session bus payload: {}", e);
+                continue;
+            }
+        };
+        if envelope.origin == bus.node_id {
+            continue;
+        }
+
+        debug!("Session bus event: {:?}", envelope.event);
+        if let Err(e) = handle_event(&app_state, &database, &http_client, envelope.event).await {
+            error!("Failed to handle session bus event: {}", e);
+        }
+    }
+}
+
+async fn handle_event(
+    app_state: &AppState,
+    database: &Database,
+    http_client: &reqwest::Client,
+    event: SessionEvent,
+) -> Result<(), String> {
+    match event {
+        SessionEvent::Authorized {
+            state_token,
+            provider,
+        } => {
+            let Some(connection) = app_state
+                .connection_table
+                .lock()
+                .await
+                .get(&state_token)
+                .cloned()
+            else {
+                return Ok(());
+            };
+
+            match provider {
+                AuthProvider::Twitch => {
+                    let key = database
+                        .twitch_key_by_state(&state_token)
+                        .await
+                        .map_err(|e| format!("Database error: {}", e))?
+                        .ok_or("No Twitch token stored for the session")?;
+                    let _ = app_state.rate_limiter.twitch.lock().await;
+                    let token = server::twitch_token_from_key(http_client, key).await?;
+                    connection.context.lock().await.twitch = Some(token);
+                }
+                AuthProvider::Streamlabs => {
+                    let key = database
+                        .streamlabs_key_by_state(&state_token)
+                        .await
+                        .map_err(|e| format!("Database error: {}", e))?
+                        .ok_or("No Streamlabs token stored for the session")?;
+                    let _ = app_state.rate_limiter.streamlabs.lock().await;
+                    let token = server::streamlabs_token_from_key(http_client, key).await?;
+                    connection.context.lock().await.streamlabs = Some(token);
+                }
+            }
+
+            connection
+                .send(connection.get_connect_message().await)
+                .await
+        }
+        SessionEvent::Send {
+            state_token,
+            message,
+        } => {
+            let connection = app_state
+                .connection_table
+                .lock()
+                .await
+                .get(&state_token)
+                .cloned();
+            match connection {
+                Some(connection) => connection.send(message).await,
+                None => Ok(()),
+            }
+        }
+        SessionEvent::SendAll { message } => {
+            let connections = app_state
+                .connection_table
+                .lock()
+                .await
+                .values()
+                .cloned()
+                .collect::<Vec<_>>();
+            for connection in connections {
+                if let Err(e) = connection.send(message.clone()).await {
+                    error!("Failed to send session bus message: {}", e);
+                }
+            }
+            Ok(())
+        }
+        SessionEvent::ChatCommands {
+            state_token,
+            prefix,
+            commands,
+        } => {
+            let connection = app_state
+                .connection_table
+                .lock()
+                .await
+                .get(&state_token)
+                .cloned();
+            match connection {
+                Some(connection) => connection.commands.lock().await.configure(prefix, commands),
+                None => Ok(()),
+            }
+        }
+        SessionEvent::Disconnect {
+            state_token,
+            reason,
+        } => {
+            admin::disconnect_local(app_state, &state_token, &reason).await;
+            Ok(())
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::SocketAddr, ops::Deref, path::PathBuf};
+
+    use axum::extract::ws::Message;
+    use tokio::{
+        sync::{Mutex, mpsc},
+        time::{interval, timeout},
+    };
+    use vrctv_common::{ChatRole, DeviceInfo, DeviceRole, Notify};
+
+    use super::*;
+    use crate::{
+        RateLimitHolder,
+        oauth::OAuthStates,
+        server::{ClientConnection, ClientContext, send_all_message},
+        twitch::{commands::CommandRegistry, rewards::RewardUpdateQueue},
+    };
+
+    const RECEIVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+    /// A SQLite database in the temp folder, removed again when the test is done
+    struct TempDatabase {
+        database: Database,
+        path: PathBuf,
+    }
+
+    impl Deref for TempDatabase {
+        type Target = Database;
+
+        fn deref(&self) -> &Self::Target {
+            &self.database
+        }
+    }
+
+    impl Drop for TempDatabase {
+        fn drop(&mut self) {
+            for suffix in ["", "-wal", "-shm"] {
+                let mut path = self.path.clone().into_os_string();
+                path.push(suffix);
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    async fn database() -> TempDatabase {
+        let path = std::env::temp_dir().join(format!("vrctv-bus-{}.sqlite", uuid::Uuid::new_v4()));
+        let database = Database::new(path.to_str().unwrap()).unwrap().0;
+        database.migrate().await.unwrap();
+        TempDatabase { database, path }
+    }
+
+    /// A server sharing `database` and the backend of `bus`, listening for events from the others
+    fn start_server(bus: SessionBus, database: &Database) -> AppState {
+        let app_state = AppState {
+            rate_limiter: RateLimitHolder {
+                twitch: Arc::new(Mutex::new(interval(Duration::from_secs(5)))),
+                streamlabs: Arc::new(Mutex::new(interval(Duration::from_secs(5)))),
+                new_user: Arc::new(Mutex::new(interval(Duration::from_secs(1)))),
+            },
+            connection_table: Arc::new(Mutex::new(HashMap::new())),
+            oauth_states: OAuthStates::new(Some("secret"), database.clone()),
+            session_bus: bus,
+        };
+
+        tokio::spawn(listen(
+            app_state.clone(),
+            database.clone(),
+            reqwest::Client::new(),
+        ));
+        app_state
+    }
+
+    /// Attach a client to a session on a server, returning what the server sends it
+    async fn attach(
+        app_state: &AppState,
+        state_token: &str,
+        held: bool,
+    ) -> mpsc::Receiver<Message> {
+        let (tx, rx) = mpsc::channel(8);
+        let device = DeviceInfo {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: "Test device".into(),
+            role: DeviceRole::default(),
+        };
+        let context = ClientContext {
+            addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            state_token: Some(state_token.to_string()),
+            twitch: None,
+            streamlabs: None,
+            device: device.clone(),
+            holds_session: held,
+        };
+
+        app_state.connection_table.lock().await.insert(
+            state_token.to_string(),
+            ClientConnection {
+                state_token: state_token.to_string(),
+                sender: vec![tx.clone()],
+                context: Arc::new(Mutex::new(context)),
+                devices: vec![server::AttachedDevice {
+                    info: device,
+                    sender: tx,
+                }],
+                twitch_connection: None,
+                streamlabs_connection: None,
+                reward_updates: Arc::new(Mutex::new(RewardUpdateQueue::default())),
+                commands: Arc::new(Mutex::new(CommandRegistry::default())),
+                held,
+                session_bus: app_state.session_bus.clone(),
+            },
+        );
+        rx
+    }
+
+    async fn receive(rx: &mut mpsc::Receiver<Message>) -> ServerMessage {
+        let Some(Message::Text(text)) = timeout(RECEIVE_TIMEOUT, rx.recv()).await.unwrap() else {
+            panic!("Expected a text message");
+        };
+        serde_json::from_str(text.as_str()).unwrap()
+    }
+
+    fn notify(title: &str) -> ServerMessage {
+        ServerMessage::Notify(Notify {
+            title: title.into(),
+            message: "From the holder".into(),
+        })
+    }
+
+    #[tokio::test]
+    async fn events_reach_sessions_on_other_servers() {
+        let database = database().await;
+        let bus = SessionBus::new(None, None).unwrap();
+        let first = start_server(bus.node(), &database);
+        let second = start_server(bus.node(), &database);
+        // Let both servers subscribe before anything is published
+        tokio::task::yield_now().await;
+
+        let mut on_first = attach(&first, "session", true).await;
+        let mut on_second = attach(&second, "session", false).await;
+
+        first
+            .session_bus
+            .publish(SessionEvent::Send {
+                state_token: "session".into(),
+                message: ServerMessage::Notify(Notify {
+                    title: "Hello".into(),
+                    message: "From the first server".into(),
+                }),
+            })
+            .await
+            .unwrap();
+
+        let Some(Message::Text(text)) = timeout(RECEIVE_TIMEOUT, on_second.recv()).await.unwrap()
+        else {
+            panic!("Expected a text message");
+        };
+        let ServerMessage::Notify(notify) = serde_json::from_str(text.as_str()).unwrap() else {
+            panic!("Expected a notification");
+        };
+        assert_eq!(notify.title, "Hello");
+
+        // The publisher applies events to its own sessions itself, the bus doesn't echo them back
+        assert!(on_first.try_recv().is_err());
+
+        second
+            .session_bus
+            .publish(SessionEvent::Disconnect {
+                state_token: "session".into(),
+                reason: "Bye".into(),
+            })
+            .await
+            .unwrap();
+
+        let message = timeout(RECEIVE_TIMEOUT, on_first.recv()).await.unwrap();
+        assert!(matches!(message, Some(Message::Close(_))));
+    }
+
+    #[tokio::test]
+    async fn only_one_server_holds_a_session() {
+        let database = database().await;
+        let bus = SessionBus::new(None, None).unwrap();
+        let first = bus.node();
+        let second = bus.node();
+
+        assert!(first.claim(&database, "session").await.unwrap());
+        assert!(!second.claim(&database, "session").await.unwrap());
+        // Renewing a claim works for the holder
+        assert!(first.claim(&database, "session").await.unwrap());
+        // Other sessions are unaffected
+        assert!(second.claim(&database, "other").await.unwrap());
+
+        // Only the holder can release a claim
+        second.release(&database, "session").await.unwrap();
+        assert!(!second.claim(&database, "session").await.unwrap());
+
+        first.release(&database, "session").await.unwrap();
+        assert!(second.claim(&database, "session").await.unwrap());
+        assert!(!first.claim(&database, "session").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn expired_claims_can_be_taken_over() {
+        let database = database().await;
+        let now = unix_now();
+
+        assert!(
+            database
+                .claim_session("session", "first", now, now + 1)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !database
+                .claim_session("session", "second", now, now + 60)
+                .await
+                .unwrap()
+        );
+        assert!(
+            database
+                .claim_session("session", "second", now + 1, now + 61)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn a_lone_server_claims_nothing() {
+        let database = database().await;
+        let now = unix_now();
+        // Left behind by the server before a restart
+        database
+            .claim_session("session", "previous", now, now + 60)
+            .await
+            .unwrap();
+
+        let bus = SessionBus::new(None, None).unwrap();
+        assert!(bus.claim(&database, "session").await.unwrap());
+        bus.release(&database, "session").await.unwrap();
+        assert!(
+            !database
+                .claim_session("session", "other", now, now + 60)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn restarted_servers_clear_their_claims() {
+        let database = database().await;
+        let now = unix_now();
+        for (state, node, expires_at) in [
+            ("mine", "restarted", now + 60),
+            ("expired", "gone", now),
+            ("theirs", "running", now + 60),
+        ] {
+            assert!(
+                database
+                    .claim_session(state, node, now, expires_at)
+                    .await
+                    .unwrap()
+            );
+        }
+
+        assert_eq!(
+            database
+                .clear_session_claims("restarted", now)
+                .await
+                .unwrap(),
+            2
+        );
+        assert!(
+            database
+                .claim_session("mine", "other", now, now + 60)
+                .await
+                .unwrap()
+        );
+        assert!(
+            database
+                .claim_session("expired", "other", now, now + 60)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !database
+                .claim_session("theirs", "other", now, now + 60)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn events_are_forwarded_from_the_holder() {
+        let database = database().await;
+        let bus = SessionBus::new(None, None).unwrap();
+        let holder = start_server(bus.node(), &database);
+        let other = start_server(bus.node(), &database);
+        tokio::task::yield_now().await;
+
+        assert!(
+            holder
+                .session_bus
+                .claim(&database, "session")
+                .await
+                .unwrap()
+        );
+        assert!(!other.session_bus.claim(&database, "session").await.unwrap());
+
+        let mut on_holder = attach(&holder, "session", true).await;
+        let mut on_other = attach(&other, "session", false).await;
+
+        let connection = holder.connection_table.lock().await["session"].clone();
+        send_all_message(notify("Event"), &connection)
+            .await
+            .unwrap();
+
+        for rx in [&mut on_holder, &mut on_other] {
+            let ServerMessage::Notify(notify) = receive(rx).await else {
+                panic!("Expected a notification");
+            };
+            assert_eq!(notify.title, "Event");
+        }
+    }
+
+    #[tokio::test]
+    async fn chat_commands_reach_the_holder() {
+        let database = database().await;
+        let bus = SessionBus::new(None, None).unwrap();
+        let holder = start_server(bus.node(), &database);
+        let other = start_server(bus.node(), &database);
+        tokio::task::yield_now().await;
+
+        let _on_holder = attach(&holder, "session", true).await;
+        let _on_other = attach(&other, "session", false).await;
+
+        other
+            .session_bus
+            .publish(SessionEvent::ChatCommands {
+                state_token: "session".into(),
+                prefix: "?".into(),
+                commands: vec![ChatCommand {
+                    name: "hug".into(),
+                    aliases: Vec::new(),
+                    permission: ChatRole::Everyone,
+                    user_cooldown_seconds: 0,
+                    global_cooldown_seconds: 0,
+                }],
+            })
+            .await
+            .unwrap();
+
+        let commands = holder.connection_table.lock().await["session"]
+            .commands
+            .clone();
+        timeout(RECEIVE_TIMEOUT, async {
+            while commands
+                .lock()
+                .await
+                .parse("?hug", "1", ChatRole::Everyone)
+                .is_none()
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
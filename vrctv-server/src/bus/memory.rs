@@ -0,0 +1,53 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use log::warn;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::bus::BusBackend;
+
+/// How many payloads a slow subscriber can fall behind before it misses some
+const CAPACITY: usize = 256;
+
+/// Keeps events inside this process, for a single server or several started in one process
+pub struct MemoryBus {
+    sender: broadcast::Sender<String>,
+}
+
+impl MemoryBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Self { sender }
+    }
+}
+
+#[async_trait]
+impl BusBackend for MemoryBus {
+    async fn publish(&self, payload: String) -> Result<()> {
+        // Nobody listening just means there's no other server to tell
+        let _ = self.sender.send(payload);
+        Ok(())
+    }
+
+    async fn subscribe(&self) -> Result<mpsc::UnboundedReceiver<String>> {
+        let mut receiver = self.sender.subscribe();
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(payload) => {
+                        if tx.send(payload).is_err() {
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Session bus subscriber missed {} events", missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+
+        Ok(rx)
+    }
+}
@@ -0,0 +1,112 @@
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use deadpool_postgres::{Config, Pool, Runtime};
+use futures_util::{StreamExt, stream};
+use log::{error, info};
+use postgres_native_tls::MakeTlsConnector;
+use tokio::sync::mpsc;
+use tokio_postgres::AsyncMessage;
+
+use crate::bus::BusBackend;
+
+const CHANNEL: &str = "vrctv_sessions";
+
+/// How long to wait before listening again after losing the connection
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Shares events between servers with Postgres LISTEN/NOTIFY
+/// Notifications are limited to 8000 bytes and are missed while the listener reconnects
+pub struct PostgresBus {
+    url: String,
+    tls: MakeTlsConnector,
+    pool: Pool,
+}
+
+impl PostgresBus {
+    pub fn new(url: &str) -> Result<Self> {
+        let tls = MakeTlsConnector::new(native_tls::TlsConnector::new()?);
+
+        let config = Config {
+            url: Some(url.to_string()),
+            ..Default::default()
+        };
+        let pool = config.create_pool(Some(Runtime::Tokio1), tls.clone())?;
+
+        Ok(Self {
+            url: url.to_string(),
+            tls,
+            pool,
+        })
+    }
+}
+
+#[async_trait]
+impl BusBackend for PostgresBus {
+    async fn publish(&self, payload: String) -> Result<()> {
+        self.pool
+            .get()
+            .await?
+            .execute("SELECT pg_notify($1, $2)", &[&CHANNEL, &payload])
+            .await?;
+        Ok(())
+    }
+
+    async fn subscribe(&self) -> Result<mpsc::UnboundedReceiver<String>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let url = self.url.clone();
+        let tls = self.tls.clone();
+
+        tokio::spawn(async move {
+            loop {
+                match listen(&url, tls.clone(), &tx).await {
+                    // The subscriber went away
+                    Ok(()) => break,
+                    Err(e) => {
+                        error!("Session bus listener failed, reconnecting: {}", e);
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                    }
+                }
+            }
+        });
+
+        Ok(rx)
+    }
+}
+
+/// Forward notifications until the connection is lost or `tx` is closed
+async fn listen(
+    url: &str,
+    tls: MakeTlsConnector,
+    tx: &mpsc::UnboundedSender<String>,
+) -> Result<()> {
+    let (client, mut connection) = tokio_postgres::connect(url, tls).await?;
+
+    // The connection has to be polled for the client to work, notifications come out of it too
+    let (notification_tx, mut notifications) = mpsc::unbounded_channel();
+    let driver = tokio::spawn(async move {
+        let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(message) = messages.next().await {
+            if let AsyncMessage::Notification(notification) = message? {
+                let _ = notification_tx.send(notification.payload().to_string());
+            }
+        }
+        Ok::<_, tokio_postgres::Error>(())
+    });
+
+    client.batch_execute(&format!("LISTEN {}", CHANNEL)).await?;
+    info!("Listening for session events on Postgres");
+
+    while let Some(payload) = notifications.recv().await {
+        if tx.send(payload).is_err() {
+            driver.abort();
+            return Ok(());
+        }
+    }
+
+    match driver.await? {
+        Ok(()) => Err(anyhow!("Postgres closed the connection")),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -6,6 +6,8 @@ use tokio::sync::OnceCell;
 struct ServerConfig {
     host: String,
     port: u16,
+    session_bus_url: Option<String>,
+    session_bus_node_id: Option<String>,
 }
 
 #[derive(Debug)]
@@ -18,6 +20,9 @@ struct AppConfig {
     twitch_oauth: OAuthConfig,
     streamlabs_oauth: OAuthConfig,
     client_version: String,
+    redemption_auto_refund_minutes: Option<u64>,
+    oauth_state_secret: Option<String>,
+    admin_token: Option<String>,
 }
 
 #[derive(Debug)]
@@ -66,6 +71,17 @@ impl Config {
         self.server.port
     }
 
+    /// Where servers share session events, events stay in this process if unset
+    pub fn session_bus_url(&self) -> Option<&str> {
+        self.server.session_bus_url.as_deref()
+    }
+
+    /// The name this server holds sessions under, which has to stay the same across restarts so
+    /// it can take its sessions back. Defaults to the host name
+    pub fn session_bus_node_id(&self) -> Option<&str> {
+        self.server.session_bus_node_id.as_deref()
+    }
+
     pub fn twitch_oauth(&self) -> &OAuthConfig {
         &self.app.twitch_oauth
     }
@@ -77,6 +93,21 @@ impl Config {
     pub fn client_version(&self) -> &str {
         &self.app.client_version
     }
+
+    /// How long a redemption may stay unfulfilled before it is refunded, if enabled
+    pub fn redemption_auto_refund_minutes(&self) -> Option<u64> {
+        self.app.redemption_auto_refund_minutes
+    }
+
+    /// The key used to sign OAuth state, a random one is generated on startup if unset
+    pub fn oauth_state_secret(&self) -> Option<&str> {
+        self.app.oauth_state_secret.as_deref()
+    }
+
+    /// The bearer token for the admin API, which is disabled if unset
+    pub fn admin_token(&self) -> Option<&str> {
+        self.app.admin_token.as_deref()
+    }
 }
 
 pub static CONFIG: OnceCell<Config> = OnceCell::const_new();
@@ -90,6 +121,11 @@ async fn init_config() -> Config {
             .unwrap_or_else(|_| String::from("3000"))
             .parse::<u16>()
             .unwrap(),
+        session_bus_url: env::var("SESSION_BUS_URL").ok().filter(|u| !u.is_empty()),
+        session_bus_node_id: env::var("SESSION_BUS_NODE_ID")
+            .or_else(|_| env::var("HOSTNAME"))
+            .ok()
+            .filter(|n| !n.is_empty()),
     };
 
     let database_config = DatabaseConfig {
@@ -110,6 +146,14 @@ async fn init_config() -> Config {
             secret: env::var("STREAMLABS_SECRET").expect("STREAMLABS_SECRET must be set"),
         },
         client_version: env::var("CLIENT_VERSION").expect("CLIENT_VERSION must be set"),
+        redemption_auto_refund_minutes: env::var("REDEMPTION_AUTO_REFUND_MINUTES")
+            .ok()
+            .map(|m| {
+                m.parse::<u64>()
+                    .expect("REDEMPTION_AUTO_REFUND_MINUTES must be a number")
+            }),
+        oauth_state_secret: env::var("OAUTH_STATE_SECRET").ok(),
+        admin_token: env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty()),
     };
 
     Config {
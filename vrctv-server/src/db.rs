@@ -1,20 +1,116 @@
+use std::{ops::Deref, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
 use axum::Extension;
-use r2d2::{Error, Pool, PooledConnection};
-use r2d2_sqlite::SqliteConnectionManager;
+
+use crate::{
+    entities::{ActiveKey, ActiveStreamLabsKey, ActiveTwitchKey, StreamlabsUser, TwitchUser},
+    oauth::PendingAuthorization,
+};
+
+mod postgres;
+mod sqlite;
+
+/// Everything the server keeps in its database
+/// Implemented by each backend, so replicas can share one database when it isn't a local file
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Create the tables, or bring ones made by an older version up to date
+    async fn migrate(&self) -> Result<()>;
+
+    /// Check that a connection can be made and used
+    async fn ping(&self) -> Result<()>;
+
+    async fn active_key(&self, state: &str) -> Result<Option<ActiveKey>>;
+
+    /// Does nothing if the state token is already registered
+    async fn insert_active_key(&self, key: &ActiveKey) -> Result<()>;
+
+    /// Does nothing if the user already exists
+    async fn insert_twitch_user(&self, user: &TwitchUser) -> Result<()>;
+
+    async fn twitch_users(&self) -> Result<Vec<TwitchUser>>;
+
+    async fn twitch_key_by_state(&self, state: &str) -> Result<Option<ActiveTwitchKey>>;
+
+    async fn twitch_key_by_user(&self, user: i64) -> Result<Option<ActiveTwitchKey>>;
+
+    /// Store the user's key, replacing the one they already have and bumping its version
+    async fn upsert_twitch_key(&self, key: &ActiveTwitchKey) -> Result<()>;
+
+    async fn delete_twitch_key(&self, id: i64) -> Result<()>;
+
+    /// Does nothing if the user already exists
+    async fn insert_streamlabs_user(&self, user: &StreamlabsUser) -> Result<()>;
+
+    async fn streamlabs_users(&self) -> Result<Vec<StreamlabsUser>>;
+
+    async fn streamlabs_key_by_state(&self, state: &str) -> Result<Option<ActiveStreamLabsKey>>;
+
+    async fn streamlabs_key_by_user(&self, user: i64) -> Result<Option<ActiveStreamLabsKey>>;
+
+    /// Store the user's key, replacing the one they already have and bumping its version
+    async fn upsert_streamlabs_key(&self, key: &ActiveStreamLabsKey) -> Result<()>;
+
+    async fn delete_streamlabs_key(&self, id: i64) -> Result<()>;
+
+    /// Store an authorization flow under its nonce, dropping the ones that expired before `now`
+    async fn insert_pending_authorization(
+        &self,
+        nonce: &str,
+        pending: &PendingAuthorization,
+        now: u64,
+    ) -> Result<()>;
+
+    async fn pending_authorization(&self, nonce: &str) -> Result<Option<PendingAuthorization>>;
+
+    /// Remove an authorization flow, returning it if it was still there
+    async fn take_pending_authorization(&self, nonce: &str)
+    -> Result<Option<PendingAuthorization>>;
+
+    /// Make `node` the server holding a session until `expires_at`, unless another server holds
+    /// it and its claim hasn't expired by `now`
+    /// Returns whether `node` holds the session, a claim it already had is extended
+    async fn claim_session(
+        &self,
+        state: &str,
+        node: &str,
+        now: u64,
+        expires_at: u64,
+    ) -> Result<bool>;
+
+    /// Give up a session, if `node` still holds it
+    async fn release_session(&self, state: &str, node: &str) -> Result<()>;
+
+    /// Drop every claim held by `node` and every claim that expired by `now`, returning how many
+    async fn clear_session_claims(&self, node: &str, now: u64) -> Result<u64>;
+}
 
 #[derive(Clone)]
 pub struct Database {
-    pool: Pool<SqliteConnectionManager>,
+    storage: Arc<dyn Storage>,
 }
 
 impl Database {
-    pub fn new(path: &str) -> Result<Extension<Self>, Error> {
-        let manager = SqliteConnectionManager::file(path);
-        let pool = Pool::new(manager)?;
-        Ok(Extension(Self { pool }))
+    /// Open the database at `url`
+    /// `postgres://` and `postgresql://` urls use Postgres, anything else is a SQLite file path
+    pub fn new(url: &str) -> Result<Extension<Self>> {
+        let storage: Arc<dyn Storage> =
+            if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+                Arc::new(postgres::PostgresStorage::new(url)?)
+            } else {
+                Arc::new(sqlite::SqliteStorage::new(url)?)
+            };
+
+        Ok(Extension(Self { storage }))
     }
+}
+
+impl Deref for Database {
+    type Target = dyn Storage;
 
-    pub fn connection(&self) -> Result<PooledConnection<SqliteConnectionManager>, Error> {
-        Ok(self.pool.get()?)
+    fn deref(&self) -> &Self::Target {
+        self.storage.as_ref()
     }
 }
@@ -0,0 +1,433 @@
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use deadpool_postgres::{Config, Object, Pool, Runtime};
+use postgres_native_tls::MakeTlsConnector;
+use tokio_postgres::Row;
+
+use crate::{
+    db::Storage,
+    entities::{ActiveKey, ActiveStreamLabsKey, ActiveTwitchKey, StreamlabsUser, TwitchUser},
+    oauth::PendingAuthorization,
+};
+
+/// Held while migrating so replicas starting together don't race to create the tables
+const MIGRATION_LOCK: i64 = 0x7672_6374_7600;
+
+/// A Postgres database that several servers can share
+pub struct PostgresStorage {
+    pool: Pool,
+}
+
+impl PostgresStorage {
+    /// Connections use TLS when the server offers it, `sslmode` in the url can require it
+    pub fn new(url: &str) -> Result<Self> {
+        let tls = MakeTlsConnector::new(native_tls::TlsConnector::new()?);
+
+        let config = Config {
+            url: Some(url.to_string()),
+            ..Default::default()
+        };
+        let pool = config.create_pool(Some(Runtime::Tokio1), tls)?;
+
+        Ok(Self { pool })
+    }
+
+    async fn connection(&self) -> Result<Object> {
+        Ok(self.pool.get().await?)
+    }
+}
+
+fn timestamp(millis: i64) -> Result<chrono::DateTime<chrono::Utc>> {
+    chrono::DateTime::from_timestamp_millis(millis).ok_or_else(|| anyhow!("Invalid timestamp"))
+}
+
+fn twitch_user(row: &Row) -> Result<TwitchUser> {
+    Ok(TwitchUser {
+        id: row.try_get(0)?,
+        joined_at: timestamp(row.try_get(1)?)?,
+    })
+}
+
+fn streamlabs_user(row: &Row) -> Result<StreamlabsUser> {
+    Ok(StreamlabsUser {
+        id: row.try_get(0)?,
+        joined_at: timestamp(row.try_get(1)?)?,
+    })
+}
+
+fn twitch_key(row: &Row) -> Result<ActiveTwitchKey> {
+    Ok(ActiveTwitchKey {
+        id: row.try_get(0)?,
+        authentication: row.try_get(1)?,
+        refresh: row.try_get(2)?,
+        user: row.try_get(3)?,
+        state: row.try_get(4)?,
+        version: row.try_get(5)?,
+        scopes: row.try_get(6)?,
+    })
+}
+
+fn streamlabs_key(row: &Row) -> Result<ActiveStreamLabsKey> {
+    Ok(ActiveStreamLabsKey {
+        id: row.try_get(0)?,
+        authentication: row.try_get(1)?,
+        refresh: row.try_get(2)?,
+        user: row.try_get(3)?,
+        state: row.try_get(4)?,
+        version: row.try_get(5)?,
+    })
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn migrate(&self) -> Result<()> {
+        let mut conn = self.connection().await?;
+        let transaction = conn.transaction().await?;
+
+        transaction
+            .execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK])
+            .await?;
+        // `user` is a reserved word in Postgres, so it is quoted throughout
+        transaction
+            .batch_execute(
+                "
+                CREATE TABLE IF NOT EXISTS twitch_users (
+                    id BIGINT PRIMARY KEY,
+                    joined_at BIGINT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS streamlabs_users (
+                    id BIGINT PRIMARY KEY,
+                    joined_at BIGINT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS active_keys (
+                    state TEXT PRIMARY KEY,
+                    created_at BIGINT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS active_twitch_keys (
+                    id BIGSERIAL PRIMARY KEY,
+                    authentication TEXT NOT NULL,
+                    refresh TEXT NOT NULL,
+                    \"user\" BIGINT NOT NULL UNIQUE REFERENCES twitch_users(id),
+                    state TEXT NOT NULL REFERENCES active_keys(state),
+                    version BIGINT NOT NULL,
+                    scopes TEXT NOT NULL DEFAULT ''
+                );
+                CREATE TABLE IF NOT EXISTS active_stream_labs_keys (
+                    id BIGSERIAL PRIMARY KEY,
+                    authentication TEXT NOT NULL,
+                    refresh TEXT NOT NULL,
+                    \"user\" BIGINT NOT NULL UNIQUE REFERENCES streamlabs_users(id),
+                    state TEXT NOT NULL REFERENCES active_keys(state),
+                    version BIGINT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS pending_authorizations (
+                    nonce TEXT PRIMARY KEY,
+                    data TEXT NOT NULL,
+                    expires_at BIGINT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS session_owners (
+                    state TEXT PRIMARY KEY,
+                    node TEXT NOT NULL,
+                    expires_at BIGINT NOT NULL
+                );
+            ",
+            )
+            .await?;
+
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.connection().await?.execute("SELECT 1", &[]).await?;
+        Ok(())
+    }
+
+    async fn active_key(&self, state: &str) -> Result<Option<ActiveKey>> {
+        let row = self
+            .connection()
+            .await?
+            .query_opt(
+                "SELECT state, created_at FROM active_keys WHERE state = $1",
+                &[&state],
+            )
+            .await?;
+
+        row.map(|row| {
+            Ok(ActiveKey {
+                state: row.try_get(0)?,
+                created_at: timestamp(row.try_get(1)?)?,
+            })
+        })
+        .transpose()
+    }
+
+    async fn insert_active_key(&self, key: &ActiveKey) -> Result<()> {
+        self.connection()
+            .await?
+            .execute(
+                "INSERT INTO active_keys (state, created_at) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                &[&key.state, &key.created_at.timestamp_millis()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_twitch_user(&self, user: &TwitchUser) -> Result<()> {
+        self.connection()
+            .await?
+            .execute(
+                "INSERT INTO twitch_users (id, joined_at) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                &[&user.id, &user.joined_at.timestamp_millis()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn twitch_users(&self) -> Result<Vec<TwitchUser>> {
+        self.connection()
+            .await?
+            .query(
+                "SELECT id, joined_at FROM twitch_users ORDER BY joined_at",
+                &[],
+            )
+            .await?
+            .iter()
+            .map(twitch_user)
+            .collect()
+    }
+
+    async fn twitch_key_by_state(&self, state: &str) -> Result<Option<ActiveTwitchKey>> {
+        self.connection()
+            .await?
+            .query_opt(
+                "SELECT id, authentication, refresh, \"user\", state, version, scopes FROM active_twitch_keys WHERE state = $1",
+                &[&state],
+            )
+            .await?
+            .as_ref()
+            .map(twitch_key)
+            .transpose()
+    }
+
+    async fn twitch_key_by_user(&self, user: i64) -> Result<Option<ActiveTwitchKey>> {
+        self.connection()
+            .await?
+            .query_opt(
+                "SELECT id, authentication, refresh, \"user\", state, version, scopes FROM active_twitch_keys WHERE \"user\" = $1",
+                &[&user],
+            )
+            .await?
+            .as_ref()
+            .map(twitch_key)
+            .transpose()
+    }
+
+    async fn upsert_twitch_key(&self, key: &ActiveTwitchKey) -> Result<()> {
+        self.connection()
+            .await?
+            .execute(
+                "INSERT INTO active_twitch_keys (authentication, refresh, \"user\", state, version, scopes)
+                 VALUES ($1, $2, $3, $4, $5, $6)
+                 ON CONFLICT (\"user\") DO UPDATE SET
+                   authentication = excluded.authentication,
+                   refresh = excluded.refresh,
+                   state = excluded.state,
+                   version = active_twitch_keys.version + 1,
+                   scopes = excluded.scopes",
+                &[
+                    &key.authentication,
+                    &key.refresh,
+                    &key.user,
+                    &key.state,
+                    &key.version,
+                    &key.scopes,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_twitch_key(&self, id: i64) -> Result<()> {
+        self.connection()
+            .await?
+            .execute("DELETE FROM active_twitch_keys WHERE id = $1", &[&id])
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_streamlabs_user(&self, user: &StreamlabsUser) -> Result<()> {
+        self.connection()
+            .await?
+            .execute(
+                "INSERT INTO streamlabs_users (id, joined_at) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                &[&user.id, &user.joined_at.timestamp_millis()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn streamlabs_users(&self) -> Result<Vec<StreamlabsUser>> {
+        self.connection()
+            .await?
+            .query(
+                "SELECT id, joined_at FROM streamlabs_users ORDER BY joined_at",
+                &[],
+            )
+            .await?
+            .iter()
+            .map(streamlabs_user)
+            .collect()
+    }
+
+    async fn streamlabs_key_by_state(&self, state: &str) -> Result<Option<ActiveStreamLabsKey>> {
+        self.connection()
+            .await?
+            .query_opt(
+                "SELECT id, authentication, refresh, \"user\", state, version FROM active_stream_labs_keys WHERE state = $1",
+                &[&state],
+            )
+            .await?
+            .as_ref()
+            .map(streamlabs_key)
+            .transpose()
+    }
+
+    async fn streamlabs_key_by_user(&self, user: i64) -> Result<Option<ActiveStreamLabsKey>> {
+        self.connection()
+            .await?
+            .query_opt(
+                "SELECT id, authentication, refresh, \"user\", state, version FROM active_stream_labs_keys WHERE \"user\" = $1",
+                &[&user],
+            )
+            .await?
+            .as_ref()
+            .map(streamlabs_key)
+            .transpose()
+    }
+
+    async fn upsert_streamlabs_key(&self, key: &ActiveStreamLabsKey) -> Result<()> {
+        self.connection()
+            .await?
+            .execute(
+                "INSERT INTO active_stream_labs_keys (authentication, refresh, \"user\", state, version)
+                 VALUES ($1, $2, $3, $4, $5)
+                 ON CONFLICT (\"user\") DO UPDATE SET
+                   authentication = excluded.authentication,
+                   refresh = excluded.refresh,
+                   state = excluded.state,
+                   version = active_stream_labs_keys.version + 1",
+                &[
+                    &key.authentication,
+                    &key.refresh,
+                    &key.user,
+                    &key.state,
+                    &key.version,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_streamlabs_key(&self, id: i64) -> Result<()> {
+        self.connection()
+            .await?
+            .execute("DELETE FROM active_stream_labs_keys WHERE id = $1", &[&id])
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_pending_authorization(
+        &self,
+        nonce: &str,
+        pending: &PendingAuthorization,
+        now: u64,
+    ) -> Result<()> {
+        let conn = self.connection().await?;
+        conn.execute(
+            "DELETE FROM pending_authorizations WHERE expires_at <= $1",
+            &[&(now as i64)],
+        )
+        .await?;
+        conn.execute(
+            "INSERT INTO pending_authorizations (nonce, data, expires_at) VALUES ($1, $2, $3)",
+            &[
+                &nonce,
+                &serde_json::to_string(pending)?,
+                &(pending.expires_at as i64),
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn pending_authorization(&self, nonce: &str) -> Result<Option<PendingAuthorization>> {
+        self.connection()
+            .await?
+            .query_opt(
+                "SELECT data FROM pending_authorizations WHERE nonce = $1",
+                &[&nonce],
+            )
+            .await?
+            .map(|row| Ok(serde_json::from_str(row.try_get(0)?)?))
+            .transpose()
+    }
+
+    async fn take_pending_authorization(
+        &self,
+        nonce: &str,
+    ) -> Result<Option<PendingAuthorization>> {
+        self.connection()
+            .await?
+            .query_opt(
+                "DELETE FROM pending_authorizations WHERE nonce = $1 RETURNING data",
+                &[&nonce],
+            )
+            .await?
+            .map(|row| Ok(serde_json::from_str(row.try_get(0)?)?))
+            .transpose()
+    }
+
+    async fn claim_session(
+        &self,
+        state: &str,
+        node: &str,
+        now: u64,
+        expires_at: u64,
+    ) -> Result<bool> {
+        let changed = self
+            .connection()
+            .await?
+            .execute(
+                "INSERT INTO session_owners (state, node, expires_at) VALUES ($1, $2, $3)
+                ON CONFLICT (state) DO UPDATE SET node = excluded.node, expires_at = excluded.expires_at
+                WHERE session_owners.node = excluded.node OR session_owners.expires_at <= $4",
+                &[&state, &node, &(expires_at as i64), &(now as i64)],
+            )
+            .await?;
+        Ok(changed > 0)
+    }
+
+    async fn release_session(&self, state: &str, node: &str) -> Result<()> {
+        self.connection()
+            .await?
+            .execute(
+                "DELETE FROM session_owners WHERE state = $1 AND node = $2",
+                &[&state, &node],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn clear_session_claims(&self, node: &str, now: u64) -> Result<u64> {
+        let removed = self
+            .connection()
+            .await?
+            .execute(
+                "DELETE FROM session_owners WHERE node = $1 OR expires_at <= $2",
+                &[&node, &(now as i64)],
+            )
+            .await?;
+        Ok(removed)
+    }
+}
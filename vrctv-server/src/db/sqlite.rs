@@ -0,0 +1,297 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{Connection, OptionalExtension};
+
+use crate::{
+    db::Storage,
+    entities::{ActiveKey, ActiveStreamLabsKey, ActiveTwitchKey, StreamlabsUser, TwitchUser},
+    oauth::PendingAuthorization,
+};
+
+/// How long a connection waits for another to release its lock before giving up
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A local SQLite file, only usable by a single server
+pub struct SqliteStorage {
+    pool: Pool<SqliteConnectionManager>,
+}
+
+impl SqliteStorage {
+    pub fn new(path: &str) -> Result<Self> {
+        // WAL lets readers carry on while a write is in progress
+        let manager = SqliteConnectionManager::file(path).with_init(|conn| {
+            conn.busy_timeout(BUSY_TIMEOUT)?;
+            conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))
+        });
+        let pool = Pool::new(manager)?;
+        Ok(Self { pool })
+    }
+
+    /// Run a query on the blocking thread pool, rusqlite would otherwise stall the executor
+    async fn run<T, F>(&self, query: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = pool.get()?;
+            Ok(query(&conn)?)
+        })
+        .await?
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn migrate(&self) -> Result<()> {
+        self.run(|conn| {
+            conn.execute_batch(
+                "
+            BEGIN;
+            CREATE TABLE IF NOT EXISTS twitch_users (
+                id INTEGER PRIMARY KEY,
+                joined_at TIMESTAMP NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS streamlabs_users (
+                id INTEGER PRIMARY KEY,
+                joined_at TIMESTAMP NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS active_keys (
+                state TEXT PRIMARY KEY,
+                created_at TIMESTAMP NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS active_twitch_keys (
+                id INTEGER PRIMARY KEY,
+                authentication TEXT NOT NULL,
+                refresh TEXT NOT NULL,
+                user INTEGER NOT NULL UNIQUE,
+                state TEXT NOT NULL,
+                version INTEGER NOT NULL,
+                scopes TEXT NOT NULL DEFAULT '',
+                FOREIGN KEY(user) REFERENCES twitch_users(id),
+                FOREIGN KEY(state) REFERENCES active_keys(state)
+            );
+            CREATE TABLE IF NOT EXISTS active_stream_labs_keys (
+                id INTEGER PRIMARY KEY,
+                authentication TEXT NOT NULL,
+                refresh TEXT NOT NULL,
+                user INTEGER NOT NULL UNIQUE,
+                state TEXT NOT NULL,
+                version INTEGER NOT NULL,
+                FOREIGN KEY(user) REFERENCES streamlabs_users(id),
+                FOREIGN KEY(state) REFERENCES active_keys(state)
+            );
+            CREATE TABLE IF NOT EXISTS pending_authorizations (
+                nonce TEXT PRIMARY KEY,
+                data TEXT NOT NULL,
+                expires_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS session_owners (
+                state TEXT PRIMARY KEY,
+                node TEXT NOT NULL,
+                expires_at INTEGER NOT NULL
+            );
+            COMMIT;
+        ",
+            )?;
+
+            // Databases created before scopes were stored are missing the column
+            if conn
+                .prepare("SELECT scopes FROM active_twitch_keys LIMIT 0")
+                .is_err()
+            {
+                conn.execute(
+                    "ALTER TABLE active_twitch_keys ADD COLUMN scopes TEXT NOT NULL DEFAULT ''",
+                    [],
+                )?;
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.run(|conn| conn.query_row("SELECT 1", [], |_| Ok(())))
+            .await
+    }
+
+    async fn active_key(&self, state: &str) -> Result<Option<ActiveKey>> {
+        let state = state.to_string();
+        self.run(move |conn| ActiveKey::get(conn, &state)).await
+    }
+
+    async fn insert_active_key(&self, key: &ActiveKey) -> Result<()> {
+        let key = key.clone();
+        self.run(move |conn| key.insert(conn)).await
+    }
+
+    async fn insert_twitch_user(&self, user: &TwitchUser) -> Result<()> {
+        let user = user.clone();
+        self.run(move |conn| user.insert(conn)).await
+    }
+
+    async fn twitch_users(&self) -> Result<Vec<TwitchUser>> {
+        self.run(TwitchUser::list).await
+    }
+
+    async fn twitch_key_by_state(&self, state: &str) -> Result<Option<ActiveTwitchKey>> {
+        let state = state.to_string();
+        self.run(move |conn| ActiveTwitchKey::get_by_active_key(conn, &state))
+            .await
+    }
+
+    async fn twitch_key_by_user(&self, user: i64) -> Result<Option<ActiveTwitchKey>> {
+        self.run(move |conn| ActiveTwitchKey::get_by_user(conn, user))
+            .await
+    }
+
+    async fn upsert_twitch_key(&self, key: &ActiveTwitchKey) -> Result<()> {
+        let key = key.clone();
+        self.run(move |conn| key.upsert(conn)).await
+    }
+
+    async fn delete_twitch_key(&self, id: i64) -> Result<()> {
+        self.run(move |conn| ActiveTwitchKey::delete(conn, id))
+            .await
+    }
+
+    async fn insert_streamlabs_user(&self, user: &StreamlabsUser) -> Result<()> {
+        let user = user.clone();
+        self.run(move |conn| user.insert(conn)).await
+    }
+
+    async fn streamlabs_users(&self) -> Result<Vec<StreamlabsUser>> {
+        self.run(StreamlabsUser::list).await
+    }
+
+    async fn streamlabs_key_by_state(&self, state: &str) -> Result<Option<ActiveStreamLabsKey>> {
+        let state = state.to_string();
+        self.run(move |conn| ActiveStreamLabsKey::get_by_active_key(conn, &state))
+            .await
+    }
+
+    async fn streamlabs_key_by_user(&self, user: i64) -> Result<Option<ActiveStreamLabsKey>> {
+        self.run(move |conn| ActiveStreamLabsKey::get_by_user(conn, user))
+            .await
+    }
+
+    async fn upsert_streamlabs_key(&self, key: &ActiveStreamLabsKey) -> Result<()> {
+        let key = key.clone();
+        self.run(move |conn| key.upsert(conn)).await
+    }
+
+    async fn delete_streamlabs_key(&self, id: i64) -> Result<()> {
+        self.run(move |conn| ActiveStreamLabsKey::delete(conn, id))
+            .await
+    }
+
+    async fn insert_pending_authorization(
+        &self,
+        nonce: &str,
+        pending: &PendingAuthorization,
+        now: u64,
+    ) -> Result<()> {
+        let nonce = nonce.to_string();
+        let data = serde_json::to_string(pending)?;
+        let expires_at = pending.expires_at as i64;
+        self.run(move |conn| {
+            conn.execute(
+                "DELETE FROM pending_authorizations WHERE expires_at <= ?1",
+                [now as i64],
+            )?;
+            conn.execute(
+                "INSERT INTO pending_authorizations (nonce, data, expires_at) VALUES (?1, ?2, ?3)",
+                (nonce, data, expires_at),
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn pending_authorization(&self, nonce: &str) -> Result<Option<PendingAuthorization>> {
+        let nonce = nonce.to_string();
+        let data = self
+            .run(move |conn| {
+                conn.query_row(
+                    "SELECT data FROM pending_authorizations WHERE nonce = ?1",
+                    [nonce],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()
+            })
+            .await?;
+
+        Ok(data.map(|d| serde_json::from_str(&d)).transpose()?)
+    }
+
+    async fn take_pending_authorization(
+        &self,
+        nonce: &str,
+    ) -> Result<Option<PendingAuthorization>> {
+        let nonce = nonce.to_string();
+        let data = self
+            .run(move |conn| {
+                conn.query_row(
+                    "DELETE FROM pending_authorizations WHERE nonce = ?1 RETURNING data",
+                    [nonce],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()
+            })
+            .await?;
+
+        Ok(data.map(|d| serde_json::from_str(&d)).transpose()?)
+    }
+
+    async fn claim_session(
+        &self,
+        state: &str,
+        node: &str,
+        now: u64,
+        expires_at: u64,
+    ) -> Result<bool> {
+        let state = state.to_string();
+        let node = node.to_string();
+        self.run(move |conn| {
+            let changed = conn.execute(
+                "INSERT INTO session_owners (state, node, expires_at) VALUES (?1, ?2, ?3)
+                ON CONFLICT(state) DO UPDATE SET node = excluded.node, expires_at = excluded.expires_at
+                WHERE session_owners.node = excluded.node OR session_owners.expires_at <= ?4",
+                (state, node, expires_at as i64, now as i64),
+            )?;
+            Ok(changed > 0)
+        })
+        .await
+    }
+
+    async fn release_session(&self, state: &str, node: &str) -> Result<()> {
+        let state = state.to_string();
+        let node = node.to_string();
+        self.run(move |conn| {
+            conn.execute(
+                "DELETE FROM session_owners WHERE state = ?1 AND node = ?2",
+                (state, node),
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn clear_session_claims(&self, node: &str, now: u64) -> Result<u64> {
+        let node = node.to_string();
+        self.run(move |conn| {
+            let removed = conn.execute(
+                "DELETE FROM session_owners WHERE node = ?1 OR expires_at <= ?2",
+                (node, now as i64),
+            )?;
+            Ok(removed as u64)
+        })
+        .await
+    }
+}
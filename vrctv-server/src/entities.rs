@@ -2,6 +2,7 @@
 
 use rusqlite::Connection;
 
+#[derive(Clone)]
 pub struct TwitchUser {
     pub id: i64,
     pub joined_at: chrono::DateTime<chrono::Utc>,
@@ -27,6 +28,17 @@ impl TwitchUser {
         }
     }
 
+    pub fn list(conn: &Connection) -> rusqlite::Result<Vec<Self>> {
+        let mut stmt = conn.prepare("SELECT id, joined_at FROM twitch_users ORDER BY joined_at")?;
+        let rows = stmt.query_map([], |row| {
+            Ok(Self {
+                id: row.get(0)?,
+                joined_at: chrono::DateTime::from_timestamp_millis(row.get(1)?).unwrap(),
+            })
+        })?;
+        rows.collect()
+    }
+
     pub fn insert(&self, conn: &Connection) -> rusqlite::Result<()> {
         conn.execute(
             "INSERT OR IGNORE INTO twitch_users (id, joined_at) VALUES (?1, ?2)",
@@ -41,6 +53,7 @@ impl TwitchUser {
     }
 }
 
+#[derive(Clone)]
 pub struct StreamlabsUser {
     pub id: i64,
     pub joined_at: chrono::DateTime<chrono::Utc>,
@@ -66,6 +79,18 @@ impl StreamlabsUser {
         }
     }
 
+    pub fn list(conn: &Connection) -> rusqlite::Result<Vec<Self>> {
+        let mut stmt =
+            conn.prepare("SELECT id, joined_at FROM streamlabs_users ORDER BY joined_at")?;
+        let rows = stmt.query_map([], |row| {
+            Ok(Self {
+                id: row.get(0)?,
+                joined_at: chrono::DateTime::from_timestamp_millis(row.get(1)?).unwrap(),
+            })
+        })?;
+        rows.collect()
+    }
+
     pub fn insert(&self, conn: &Connection) -> rusqlite::Result<()> {
         conn.execute(
             "INSERT OR IGNORE INTO streamlabs_users (id, joined_at) VALUES (?1, ?2)",
@@ -80,6 +105,7 @@ impl StreamlabsUser {
     }
 }
 
+#[derive(Clone)]
 pub struct ActiveKey {
     pub state: String,
     pub created_at: chrono::DateTime<chrono::Utc>,
@@ -121,6 +147,7 @@ impl ActiveKey {
     }
 }
 
+#[derive(Clone)]
 pub struct ActiveTwitchKey {
     pub id: i64,
     pub authentication: String,
@@ -128,6 +155,8 @@ pub struct ActiveTwitchKey {
     pub user: i64,
     pub state: String,
     pub version: i64,
+    /// The space separated scopes the user granted
+    pub scopes: String,
 }
 
 impl ActiveTwitchKey {
@@ -137,6 +166,7 @@ impl ActiveTwitchKey {
         user: i64,
         state: String,
         version: i64,
+        scopes: String,
     ) -> Self {
         Self {
             id: 0,
@@ -145,11 +175,12 @@ impl ActiveTwitchKey {
             user,
             state,
             version,
+            scopes,
         }
     }
 
     pub fn get(conn: &Connection, id: i64) -> rusqlite::Result<Option<Self>> {
-        let mut stmt = conn.prepare("SELECT id, authentication, refresh, user, state, version FROM active_twitch_keys WHERE id = ?1")?;
+        let mut stmt = conn.prepare("SELECT id, authentication, refresh, user, state, version, scopes FROM active_twitch_keys WHERE id = ?1")?;
         let mut rows = stmt.query([id])?;
         if let Some(row) = rows.next()? {
             Ok(Some(Self {
@@ -159,6 +190,25 @@ impl ActiveTwitchKey {
                 user: row.get(3)?,
                 state: row.get(4)?,
                 version: row.get(5)?,
+                scopes: row.get(6)?,
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn get_by_user(conn: &Connection, user: i64) -> rusqlite::Result<Option<Self>> {
+        let mut stmt = conn.prepare("SELECT id, authentication, refresh, user, state, version, scopes FROM active_twitch_keys WHERE user = ?1")?;
+        let mut rows = stmt.query([user])?;
+        if let Some(row) = rows.next()? {
+            Ok(Some(Self {
+                id: row.get(0)?,
+                authentication: row.get(1)?,
+                refresh: row.get(2)?,
+                user: row.get(3)?,
+                state: row.get(4)?,
+                version: row.get(5)?,
+                scopes: row.get(6)?,
             }))
         } else {
             Ok(None)
@@ -166,7 +216,7 @@ impl ActiveTwitchKey {
     }
 
     pub fn get_by_active_key(conn: &Connection, state: &str) -> rusqlite::Result<Option<Self>> {
-        let mut stmt = conn.prepare("SELECT id, authentication, refresh, user, state, version FROM active_twitch_keys WHERE state = ?1")?;
+        let mut stmt = conn.prepare("SELECT id, authentication, refresh, user, state, version, scopes FROM active_twitch_keys WHERE state = ?1")?;
         let mut rows = stmt.query([state])?;
         if let Some(row) = rows.next()? {
             Ok(Some(Self {
@@ -176,6 +226,7 @@ impl ActiveTwitchKey {
                 user: row.get(3)?,
                 state: row.get(4)?,
                 version: row.get(5)?,
+                scopes: row.get(6)?,
             }))
         } else {
             Ok(None)
@@ -184,8 +235,8 @@ impl ActiveTwitchKey {
 
     pub fn insert(&mut self, conn: &Connection) -> rusqlite::Result<()> {
         conn.execute(
-            "INSERT INTO active_twitch_keys (authentication, refresh, user, state, version) VALUES (?1, ?2, ?3, ?4, ?5)",
-            (self.authentication.clone(), self.refresh.clone(), self.user, self.state.clone(), self.version),
+            "INSERT INTO active_twitch_keys (authentication, refresh, user, state, version, scopes) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            (self.authentication.clone(), self.refresh.clone(), self.user, self.state.clone(), self.version, self.scopes.clone()),
         )?;
         self.id = conn.last_insert_rowid();
         Ok(())
@@ -196,15 +247,39 @@ impl ActiveTwitchKey {
         Ok(())
     }
 
+    /// Insert the key, or replace the user's existing key and bump its version
+    pub fn upsert(&self, conn: &Connection) -> rusqlite::Result<()> {
+        conn.execute(
+            "INSERT INTO active_twitch_keys (authentication, refresh, user, state, version, scopes)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT(user) DO UPDATE SET
+               authentication = excluded.authentication,
+               refresh = excluded.refresh,
+               state = excluded.state,
+               version = active_twitch_keys.version + 1,
+               scopes = excluded.scopes",
+            (
+                self.authentication.clone(),
+                self.refresh.clone(),
+                self.user,
+                self.state.clone(),
+                self.version,
+                self.scopes.clone(),
+            ),
+        )?;
+        Ok(())
+    }
+
     pub fn update(&self, conn: &Connection) -> rusqlite::Result<()> {
         conn.execute(
-            "UPDATE active_twitch_keys SET authentication = ?1, refresh = ?2, user = ?3, state = ?4, version = ?5 WHERE id = ?6",
-            (self.authentication.clone(), self.refresh.clone(), self.user, self.state.clone(), self.version, self.id),
+            "UPDATE active_twitch_keys SET authentication = ?1, refresh = ?2, user = ?3, state = ?4, version = ?5, scopes = ?6 WHERE id = ?7",
+            (self.authentication.clone(), self.refresh.clone(), self.user, self.state.clone(), self.version, self.scopes.clone(), self.id),
         )?;
         Ok(())
     }
 }
 
+#[derive(Clone)]
 pub struct ActiveStreamLabsKey {
     pub id: i64,
     pub authentication: String,
@@ -249,6 +324,23 @@ impl ActiveStreamLabsKey {
         }
     }
 
+    pub fn get_by_user(conn: &Connection, user: i64) -> rusqlite::Result<Option<Self>> {
+        let mut stmt = conn.prepare("SELECT id, authentication, refresh, user, state, version FROM active_stream_labs_keys WHERE user = ?1")?;
+        let mut rows = stmt.query([user])?;
+        if let Some(row) = rows.next()? {
+            Ok(Some(Self {
+                id: row.get(0)?,
+                authentication: row.get(1)?,
+                refresh: row.get(2)?,
+                user: row.get(3)?,
+                state: row.get(4)?,
+                version: row.get(5)?,
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
     pub fn get_by_active_key(conn: &Connection, state: &str) -> rusqlite::Result<Option<Self>> {
         let mut stmt = conn.prepare("SELECT id, authentication, refresh, user, state, version FROM active_stream_labs_keys WHERE state = ?1")?;
         let mut rows = stmt.query([state])?;
@@ -280,6 +372,27 @@ impl ActiveStreamLabsKey {
         Ok(())
     }
 
+    /// Insert the key, or replace the user's existing key and bump its version
+    pub fn upsert(&self, conn: &Connection) -> rusqlite::Result<()> {
+        conn.execute(
+            "INSERT INTO active_stream_labs_keys (authentication, refresh, user, state, version)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT(user) DO UPDATE SET
+               authentication = excluded.authentication,
+               refresh = excluded.refresh,
+               state = excluded.state,
+               version = active_stream_labs_keys.version + 1",
+            (
+                self.authentication.clone(),
+                self.refresh.clone(),
+                self.user,
+                self.state.clone(),
+                self.version,
+            ),
+        )?;
+        Ok(())
+    }
+
     pub fn update(&self, conn: &Connection) -> rusqlite::Result<()> {
         conn.execute(
             "UPDATE active_stream_labs_keys SET authentication = ?1, refresh = ?2, user = ?3, state = ?4, version = ?5 WHERE id = ?6",
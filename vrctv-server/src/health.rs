@@ -0,0 +1,35 @@
+use axum::{Extension, http::StatusCode, response::IntoResponse};
+
+use crate::{config, db::Database};
+
+/// Check that a database connection can be taken from the pool and used
+pub async fn check_database(database: &Database) -> Result<(), String> {
+    database.ping().await.map_err(|e| e.to_string())
+}
+
+/// Liveness probe, the server is up if it can answer
+pub async fn healthz() -> impl IntoResponse {
+    (StatusCode::OK, "ok")
+}
+
+/// Readiness probe, the server can take clients once the config is loaded and the database is
+/// reachable
+pub async fn readyz(Extension(database): Extension<Database>) -> impl IntoResponse {
+    let config_ok = config::CONFIG.get().is_some();
+    let database_ok = check_database(&database).await;
+
+    let body = format!(
+        "config: {}\ndatabase: {}\n",
+        if config_ok { "ok" } else { "not loaded" },
+        match &database_ok {
+            Ok(()) => "ok".to_string(),
+            Err(e) => format!("unreachable ({})", e),
+        }
+    );
+
+    if config_ok && database_ok.is_ok() {
+        (StatusCode::OK, body)
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, body)
+    }
+}
@@ -3,13 +3,13 @@ use std::{collections::HashMap, env, net::SocketAddr, sync::Arc, time::Duration}
 use axum::{
     Extension, Router,
     extract::{ConnectInfo, Path, State, ws::WebSocketUpgrade},
-    http::HeaderValue,
+    http::{HeaderValue, header},
     response::{Html, IntoResponse, Redirect},
     routing::{any, get},
 };
 use axum_extra::{TypedHeader, headers};
 use listenfd::ListenFd;
-use log::{debug, info};
+use log::{debug, error, info};
 use reqwest::StatusCode;
 use tokio::{
     net::TcpListener,
@@ -21,15 +21,25 @@ use tower_http::timeout::TimeoutLayer;
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use twitch_api::client::ClientDefault;
+use vrctv_common::AuthProvider;
 
 use crate::{
+    bus::SessionBus,
     db::Database,
+    oauth::OAuthStates,
+    pages::{AuthResult, auth_page},
     server::{ClientConnection, handle_client},
 };
 
+mod admin;
+mod bus;
 mod config;
 mod db;
 mod entities;
+mod health;
+mod metrics;
+mod oauth;
+mod pages;
 mod server;
 mod streamlabs;
 mod twitch;
@@ -45,10 +55,23 @@ pub struct RateLimitHolder {
 pub struct AppState {
     pub rate_limiter: RateLimitHolder,
     pub connection_table: Arc<Mutex<HashMap<String, ClientConnection>>>,
+    pub oauth_states: OAuthStates,
+    pub session_bus: SessionBus,
 }
 
 #[tokio::main]
 async fn main() {
+    // `vrctv-server admin ...` manages a running server instead of starting one
+    let args = env::args().collect::<Vec<_>>();
+    if args.get(1).map(String::as_str) == Some("admin") {
+        dotenv::dotenv().ok();
+        if let Err(e) = admin::cli::run(&args[2..]).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     // Tracing debugging/logging setup
     tracing_subscriber::registry()
         .with(
@@ -65,7 +88,9 @@ async fn main() {
 
     // build our application with a route
     let config = config::config().await;
-    let app = app(&config);
+    let session_bus = SessionBus::new(config.session_bus_url(), config.session_bus_node_id())
+        .expect("Failed to connect to the session bus");
+    let app = app(&config, session_bus).await;
 
     let mut listenfd = ListenFd::from_env();
     let listener = match listenfd.take_tcp_listener(0).unwrap() {
@@ -119,66 +144,56 @@ async fn shutdown_signal() {
     }
 }
 
-fn app(config: &config::Config) -> Router {
+/// Build a server, servers sharing `session_bus` can serve each other's sessions
+async fn app(config: &config::Config, session_bus: SessionBus) -> Router {
     let connection_table = Arc::new(Mutex::new(HashMap::new()));
 
     // Setup the database
-    let db = Database::new(config.db_url()).unwrap();
-    {
-        let conn = db.connection().unwrap();
-
-        conn.execute_batch(
-            "
-            BEGIN;
-            CREATE TABLE IF NOT EXISTS twitch_users (
-                id INTEGER PRIMARY KEY,
-                joined_at TIMESTAMP NOT NULL
-            );
-            CREATE TABLE IF NOT EXISTS streamlabs_users (
-                id INTEGER PRIMARY KEY,
-                joined_at TIMESTAMP NOT NULL
-            );
-            CREATE TABLE IF NOT EXISTS active_keys (
-                state TEXT PRIMARY KEY,
-                created_at TIMESTAMP NOT NULL
-            );
-            CREATE TABLE IF NOT EXISTS active_twitch_keys (
-                id INTEGER PRIMARY KEY,
-                authentication TEXT NOT NULL,
-                refresh TEXT NOT NULL,
-                user INTEGER NOT NULL UNIQUE,
-                state TEXT NOT NULL,
-                version INTEGER NOT NULL,
-                FOREIGN KEY(user) REFERENCES twitch_users(id),
-                FOREIGN KEY(state) REFERENCES active_keys(state)
-            );
-            CREATE TABLE IF NOT EXISTS active_stream_labs_keys (
-                id INTEGER PRIMARY KEY,
-                authentication TEXT NOT NULL,
-                refresh TEXT NOT NULL,
-                user INTEGER NOT NULL UNIQUE,
-                state TEXT NOT NULL,
-                version INTEGER NOT NULL,
-                FOREIGN KEY(user) REFERENCES streamlabs_users(id),
-                FOREIGN KEY(state) REFERENCES active_keys(state)
-            );
-            COMMIT;
-        ",
-        )
-        .unwrap();
+    let db = Database::new(config.db_url()).expect("Failed to open the database");
+    db.migrate().await.expect("Failed to migrate the database");
+    if let Err(e) = session_bus.clear_claims(&db.0).await {
+        error!("Failed to clear stale session claims: {}", e);
     }
 
     let http_client =
         reqwest::Client::default_client_with_name(Some(HeaderValue::from_static("vrctv-server")))
             .expect("Could not create default client");
 
+    // Every server has to sign states with the same key once sessions are spread over several
+    assert!(
+        config.session_bus_url().is_none() || config.oauth_state_secret().is_some(),
+        "OAUTH_STATE_SECRET must be set when SESSION_BUS_URL is"
+    );
+
+    let app_state = AppState {
+        rate_limiter: RateLimitHolder {
+            twitch: Arc::new(Mutex::new(interval(Duration::from_secs(5)))),
+            streamlabs: Arc::new(Mutex::new(interval(Duration::from_secs(5)))),
+            new_user: Arc::new(Mutex::new(interval(Duration::from_secs(1)))),
+        },
+        connection_table: connection_table.clone(),
+        oauth_states: OAuthStates::new(config.oauth_state_secret(), db.0.clone()),
+        session_bus,
+    };
+
+    tokio::spawn(bus::listen(
+        app_state.clone(),
+        db.0.clone(),
+        http_client.clone(),
+    ));
+    tokio::spawn(bus::hold_sessions(app_state.clone(), db.0.clone()));
+
     Router::new()
         .route("/twitch/auth/{state}", get(twitch_redirect))
         .route("/twitch/callback", get(twitch::auth_callback))
         .route("/streamlabs/auth/{state}", get(streamlabs_redirect))
         .route("/streamlabs/callback", get(streamlabs::auth_callback))
         .route("/", get(handler))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route("/metrics", get(metrics::metrics_handler))
         .route("/ws", any(ws_handler))
+        .nest("/admin", admin::router())
         .layer((
             TraceLayer::new_for_http(),
             // Graceful shutdown will wait for outstanding requests to complete. Add a timeout so
@@ -187,48 +202,91 @@ fn app(config: &config::Config) -> Router {
             db,
         ))
         .layer(Extension(http_client))
-        .with_state(AppState {
-            rate_limiter: RateLimitHolder {
-                twitch: Arc::new(Mutex::new(interval(Duration::from_secs(5)))),
-                streamlabs: Arc::new(Mutex::new(interval(Duration::from_secs(5)))),
-                new_user: Arc::new(Mutex::new(interval(Duration::from_secs(1)))),
-            },
-            connection_table: connection_table.clone(),
-        })
+        .with_state(app_state)
 }
 
-async fn handler() -> Html<&'static str> {
-    Html("<h1>Hello!</h1>")
+/// Status page showing the server version and whether it is healthy
+async fn handler(
+    Extension(database): Extension<Database>,
+    State(app_state): State<AppState>,
+) -> impl IntoResponse {
+    let database_ok = health::check_database(&database).await;
+    let clients = app_state.connection_table.lock().await.len();
+
+    let details = format!(
+        "Database: {}\nConnected sessions: {}",
+        match &database_ok {
+            Ok(()) => "ok".to_string(),
+            Err(e) => format!("unreachable ({})", e),
+        },
+        clients
+    );
+
+    pages::status_page(database_ok.is_ok(), &details)
 }
 
-async fn twitch_redirect(Path(state): Path<String>) -> impl IntoResponse {
-    let config = config::config().await;
+/// Send the browser on to the provider, remembering it with a cookie so the callback can only be
+/// completed by the same browser
+async fn oauth_redirect(
+    app_state: &AppState,
+    state: &str,
+    provider: AuthProvider,
+) -> Result<impl IntoResponse + use<>, (StatusCode, Html<String>)> {
+    let pending = app_state
+        .oauth_states
+        .get(state)
+        .await
+        .ok()
+        .filter(|p| p.provider == provider)
+        .ok_or_else(|| {
+            auth_page(
+                provider,
+                AuthResult::InvalidRequest("This link has expired or was already used".to_string()),
+            )
+        })?;
 
-    // URL encode the scopes
-    let scopes = config.twitch_oauth().scopes().replace(' ', "%20");
+    let url = match provider {
+        AuthProvider::Twitch => {
+            twitch::authorize_url(&pending.scopes, state, pending.force_verify).await
+        }
+        AuthProvider::Streamlabs => {
+            let code_verifier = pending.code_verifier.as_deref().unwrap_or_default();
+            streamlabs::authorize_url(state, &oauth::code_challenge(code_verifier)).await
+        }
+    }
+    .map_err(|e| auth_page(provider, AuthResult::InternalError(e)))?;
 
-    Redirect::temporary(&format!(
-        "https://id.twitch.tv/oauth2/authorize?response_type=code&client_id={}&redirect_uri={}&scope={}&state={}",
-        config.twitch_oauth().client(),
-        config.twitch_oauth().redirect(),
-        scopes,
-        state
+    let config = config::config().await;
+    let callback = match provider {
+        AuthProvider::Twitch => config.twitch_oauth().redirect(),
+        AuthProvider::Streamlabs => config.streamlabs_oauth().redirect(),
+    };
+
+    Ok((
+        [(
+            header::SET_COOKIE,
+            oauth::csrf_cookie(
+                provider,
+                &pending.csrf_token,
+                callback.starts_with("https://"),
+            ),
+        )],
+        Redirect::temporary(url.as_str()),
     ))
 }
 
-async fn streamlabs_redirect(Path(state): Path<String>) -> impl IntoResponse {
-    let config = config::config().await;
-
-    // URL encode the scopes
-    let scopes = config.streamlabs_oauth().scopes().replace(' ', "%20");
+async fn twitch_redirect(
+    Path(state): Path<String>,
+    State(app_state): State<AppState>,
+) -> impl IntoResponse {
+    oauth_redirect(&app_state, &state, AuthProvider::Twitch).await
+}
 
-    Redirect::temporary(&format!(
-        "https://streamlabs.com/api/v2.0/authorize?response_type=code&client_id={}&redirect_uri={}&scope={}&state={}",
-        config.streamlabs_oauth().client(),
-        config.streamlabs_oauth().redirect(),
-        scopes,
-        state
-    ))
+async fn streamlabs_redirect(
+    Path(state): Path<String>,
+    State(app_state): State<AppState>,
+) -> impl IntoResponse {
+    oauth_redirect(&app_state, &state, AuthProvider::Streamlabs).await
 }
 
 async fn ws_handler(
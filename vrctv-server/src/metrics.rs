@@ -0,0 +1,256 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use axum::{extract::State, http::header, response::IntoResponse};
+use vrctv_common::{ServerMessage, TwitchEventSource};
+
+use crate::AppState;
+
+/// Upper bounds of the Helix latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// Counters collected while the server runs, gauges are read from the connection table when
+/// scraped
+#[derive(Debug, Default)]
+struct Metrics {
+    /// By source and event type
+    events_forwarded: BTreeMap<(&'static str, String), u64>,
+    /// By request name
+    helix_latency: BTreeMap<&'static str, Histogram>,
+    /// By HTTP status, or `none` if the request never got a response
+    helix_errors: BTreeMap<String, u64>,
+    /// By provider and whether the refresh worked
+    token_refreshes: BTreeMap<(&'static str, bool), u64>,
+}
+
+static METRICS: LazyLock<Mutex<Metrics>> = LazyLock::new(Default::default);
+
+fn metrics() -> std::sync::MutexGuard<'static, Metrics> {
+    // The counters are always left consistent, so a panic elsewhere doesn't matter
+    METRICS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Count the events in a message sent to the clients
+pub fn record_forwarded(msg: &ServerMessage) {
+    let mut metrics = metrics();
+
+    match msg {
+        ServerMessage::TwitchEvent(event) => {
+            *metrics
+                .events_forwarded
+                .entry(("twitch", twitch_event_type(&event.event).to_string()))
+                .or_default() += 1;
+        }
+        ServerMessage::StreamLabsEvent(events) => {
+            for event in &events.events {
+                *metrics
+                    .events_forwarded
+                    .entry(("streamlabs", event.type_.clone()))
+                    .or_default() += 1;
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Record how long a Helix request took
+pub fn record_helix_latency(request: &'static str, duration: Duration) {
+    metrics()
+        .helix_latency
+        .entry(request)
+        .or_default()
+        .observe(duration.as_secs_f64());
+}
+
+/// Count a failed Helix request
+pub fn record_helix_error(status: Option<u16>) {
+    *metrics()
+        .helix_errors
+        .entry(status.map_or_else(|| "none".to_string(), |s| s.to_string()))
+        .or_default() += 1;
+}
+
+/// Count a token refresh for a provider
+pub fn record_token_refresh(provider: &'static str, success: bool) {
+    *metrics()
+        .token_refreshes
+        .entry((provider, success))
+        .or_default() += 1;
+}
+
+fn twitch_event_type(event: &TwitchEventSource) -> &'static str {
+    match event {
+        TwitchEventSource::ChannelPoints { .. } => "ChannelPoints",
+        TwitchEventSource::BitDonation { .. } => "BitDonation",
+        TwitchEventSource::Whisper { .. } => "Whisper",
+        TwitchEventSource::Message { .. } => "Message",
+        TwitchEventSource::Command { .. } => "Command",
+    }
+}
+
+/// Prometheus metrics in the text exposition format
+pub async fn metrics_handler(State(app_state): State<AppState>) -> impl IntoResponse {
+    let mut out = String::new();
+
+    // Don't hold the metrics lock while waiting on the connection table
+    let (clients, eventsub, streamlabs, senders) = {
+        let table = app_state.connection_table.lock().await;
+
+        // Grouped by sender count, state tokens are credentials so they can't be labels
+        let mut senders: BTreeMap<usize, u64> = BTreeMap::new();
+        for connection in table.values() {
+            *senders.entry(connection.sender.len()).or_default() += 1;
+        }
+
+        (
+            table.values().map(|c| c.sender.len()).sum::<usize>(),
+            table
+                .values()
+                .filter(|c| c.twitch_connection.is_some())
+                .count(),
+            table
+                .values()
+                .filter(|c| c.streamlabs_connection.is_some())
+                .count(),
+            senders,
+        )
+    };
+
+    let metrics = metrics();
+
+    let _ = writeln!(
+        out,
+        "# HELP vrctv_connected_clients Client websockets attached to a state token"
+    );
+    let _ = writeln!(out, "# TYPE vrctv_connected_clients gauge");
+    let _ = writeln!(out, "vrctv_connected_clients {}", clients);
+
+    let _ = writeln!(
+        out,
+        "# HELP vrctv_sessions_by_senders State tokens grouped by their number of connected clients"
+    );
+    let _ = writeln!(out, "# TYPE vrctv_sessions_by_senders gauge");
+    for (count, sessions) in &senders {
+        let _ = writeln!(
+            out,
+            "vrctv_sessions_by_senders{{senders=\"{}\"}} {}",
+            count, sessions
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP vrctv_eventsub_connections Open Twitch EventSub websockets"
+    );
+    let _ = writeln!(out, "# TYPE vrctv_eventsub_connections gauge");
+    let _ = writeln!(out, "vrctv_eventsub_connections {}", eventsub);
+
+    let _ = writeln!(
+        out,
+        "# HELP vrctv_streamlabs_connections Open Streamlabs socket connections"
+    );
+    let _ = writeln!(out, "# TYPE vrctv_streamlabs_connections gauge");
+    let _ = writeln!(out, "vrctv_streamlabs_connections {}", streamlabs);
+
+    let _ = writeln!(
+        out,
+        "# HELP vrctv_events_forwarded_total Events forwarded to clients"
+    );
+    let _ = writeln!(out, "# TYPE vrctv_events_forwarded_total counter");
+    for ((source, kind), count) in &metrics.events_forwarded {
+        let _ = writeln!(
+            out,
+            "vrctv_events_forwarded_total{{source=\"{}\",type=\"{}\"}} {}",
+            source,
+            escape_label(kind),
+            count
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP vrctv_helix_request_duration_seconds Time taken by Helix requests"
+    );
+    let _ = writeln!(out, "# TYPE vrctv_helix_request_duration_seconds histogram");
+    for (request, histogram) in &metrics.helix_latency {
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+            let _ = writeln!(
+                out,
+                "vrctv_helix_request_duration_seconds_bucket{{request=\"{}\",le=\"{}\"}} {}",
+                request, bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "vrctv_helix_request_duration_seconds_bucket{{request=\"{}\",le=\"+Inf\"}} {}",
+            request, histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "vrctv_helix_request_duration_seconds_sum{{request=\"{}\"}} {}",
+            request, histogram.sum
+        );
+        let _ = writeln!(
+            out,
+            "vrctv_helix_request_duration_seconds_count{{request=\"{}\"}} {}",
+            request, histogram.count
+        );
+    }
+
+    let _ = writeln!(out, "# HELP vrctv_helix_errors_total Failed Helix requests");
+    let _ = writeln!(out, "# TYPE vrctv_helix_errors_total counter");
+    for (status, count) in &metrics.helix_errors {
+        let _ = writeln!(
+            out,
+            "vrctv_helix_errors_total{{status=\"{}\"}} {}",
+            status, count
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP vrctv_token_refreshes_total OAuth token refreshes"
+    );
+    let _ = writeln!(out, "# TYPE vrctv_token_refreshes_total counter");
+    for ((provider, success), count) in &metrics.token_refreshes {
+        let _ = writeln!(
+            out,
+            "vrctv_token_refreshes_total{{provider=\"{}\",result=\"{}\"}} {}",
+            provider,
+            if *success { "success" } else { "failure" },
+            count
+        );
+    }
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], out)
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,219 @@
+use std::{
+    collections::BTreeSet,
+    fmt,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use log::warn;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use vrctv_common::AuthProvider;
+
+use crate::db::Database;
+
+/// How long an issued OAuth state can be used for
+pub const STATE_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// An authorization flow started by a websocket session, waiting for the provider's callback
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingAuthorization {
+    /// The state token of the session the tokens will be bound to
+    pub state_token: String,
+    pub provider: AuthProvider,
+    /// The Twitch scopes to ask for, empty for Streamlabs
+    pub scopes: BTreeSet<String>,
+    /// Show the consent screen again, used when asking for extra scopes
+    pub force_verify: bool,
+    /// The PKCE verifier, sent along with the authorization code
+    pub code_verifier: Option<String>,
+    /// Set as a cookie when the browser is sent to the provider, checked again in the callback
+    pub csrf_token: String,
+    /// Unix time in seconds
+    pub expires_at: u64,
+}
+
+/// Issues and checks the OAuth state given to the providers
+/// The state is signed and kept in the database, so it can't be guessed or reused and the
+/// callback can land on any server sharing the database and secret
+#[derive(Clone)]
+pub struct OAuthStates {
+    key: Arc<Vec<u8>>,
+    database: Database,
+}
+
+impl fmt::Debug for OAuthStates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OAuthStates").finish_non_exhaustive()
+    }
+}
+
+impl OAuthStates {
+    /// Use the configured secret as signing key, or a random one if there is none
+    /// A random key only works while there is a single server, the others can't check its states
+    pub fn new(secret: Option<&str>, database: Database) -> Self {
+        let key = match secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => {
+                warn!(
+                    "OAUTH_STATE_SECRET is not set, OAuth callbacks will only work on this server"
+                );
+                random_bytes(32)
+            }
+        };
+
+        Self {
+            key: Arc::new(key),
+            database,
+        }
+    }
+
+    /// Start an authorization flow for a session
+    /// Returns the signed state to send to the provider
+    pub async fn issue(
+        &self,
+        state_token: String,
+        provider: AuthProvider,
+        scopes: BTreeSet<String>,
+        force_verify: bool,
+    ) -> Result<String, String> {
+        let expires_at = unix_now() + STATE_LIFETIME.as_secs();
+        let nonce = URL_SAFE_NO_PAD.encode(random_bytes(24));
+        let payload = format!("{}.{}", nonce, expires_at);
+        let state = format!(
+            "{}.{}",
+            payload,
+            URL_SAFE_NO_PAD.encode(self.sign(&payload))
+        );
+
+        let pending = PendingAuthorization {
+            state_token,
+            provider,
+            scopes,
+            force_verify,
+            code_verifier: match provider {
+                AuthProvider::Streamlabs => Some(URL_SAFE_NO_PAD.encode(random_bytes(32))),
+                AuthProvider::Twitch => None,
+            },
+            csrf_token: URL_SAFE_NO_PAD.encode(random_bytes(24)),
+            expires_at,
+        };
+
+        self.database
+            .insert_pending_authorization(&nonce, &pending, unix_now())
+            .await
+            .map_err(|e| format!("Database error: {}", e))?;
+
+        Ok(state)
+    }
+
+    /// Look up a pending authorization without using it up, for the redirect to the provider
+    pub async fn get(&self, state: &str) -> Result<PendingAuthorization, String> {
+        let nonce = self.verify(state)?;
+        self.database
+            .pending_authorization(nonce)
+            .await
+            .map_err(|e| format!("Database error: {}", e))?
+            .ok_or_else(|| "Unknown or already used state".to_string())
+    }
+
+    /// Use up a pending authorization in the provider's callback, each state can only be used once
+    /// `csrf_token` is the value of the cookie set by the redirect, so the callback has to come
+    /// from the browser that started the flow
+    pub async fn take(
+        &self,
+        state: &str,
+        provider: AuthProvider,
+        csrf_token: Option<&str>,
+    ) -> Result<PendingAuthorization, String> {
+        let nonce = self.verify(state)?;
+        let pending = self
+            .database
+            .take_pending_authorization(nonce)
+            .await
+            .map_err(|e| format!("Database error: {}", e))?
+            .ok_or("Unknown or already used state")?;
+
+        if pending.provider != provider {
+            return Err("State was issued for a different provider".into());
+        }
+        if csrf_token != Some(pending.csrf_token.as_str()) {
+            return Err("Authorization was started from a different browser".into());
+        }
+
+        Ok(pending)
+    }
+
+    /// Check the signature and expiry of a state
+    /// Returns the nonce identifying the pending authorization
+    fn verify<'a>(&self, state: &'a str) -> Result<&'a str, String> {
+        let (payload, signature) = state.rsplit_once('.').ok_or("Malformed state")?;
+        let (nonce, expires_at) = payload.split_once('.').ok_or("Malformed state")?;
+
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| "Malformed state")?;
+        self.mac(payload)
+            .verify_slice(&signature)
+            .map_err(|_| "Invalid state signature")?;
+
+        let expires_at: u64 = expires_at.parse().map_err(|_| "Malformed state")?;
+        if expires_at <= unix_now() {
+            return Err("State has expired, please try again".into());
+        }
+
+        Ok(nonce)
+    }
+
+    fn sign(&self, payload: &str) -> Vec<u8> {
+        self.mac(payload).finalize().into_bytes().to_vec()
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any size");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+/// The S256 PKCE challenge for a verifier
+pub fn code_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+/// The cookie holding the CSRF token while the browser is at the provider
+pub fn csrf_cookie_name(provider: AuthProvider) -> &'static str {
+    match provider {
+        AuthProvider::Twitch => "vrctv_oauth_twitch",
+        AuthProvider::Streamlabs => "vrctv_oauth_streamlabs",
+    }
+}
+
+/// Build the Set-Cookie value for the CSRF token
+/// `secure` keeps the cookie off plain http, it should be set whenever the callback is https
+pub fn csrf_cookie(provider: AuthProvider, csrf_token: &str, secure: bool) -> String {
+    format!(
+        "{}={}; Max-Age={}; Path=/; HttpOnly; SameSite=Lax{}",
+        csrf_cookie_name(provider),
+        csrf_token,
+        STATE_LIFETIME.as_secs(),
+        if secure { "; Secure" } else { "" }
+    )
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    rand::rng().fill_bytes(&mut bytes);
+    bytes
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+
+use axum::{http::StatusCode, response::Html};
+use log::error;
+use vrctv_common::{AuthProvider, template::render};
+
+const PAGE_TEMPLATE: &str = include_str!("../templates/page.html");
+
+const ACCENT_SUCCESS: &str = "#16a34a";
+const ACCENT_ERROR: &str = "#dc2626";
+const ACCENT_INFO: &str = "#9146ff";
+
+/// How an OAuth callback ended, shown to the user in the browser
+#[derive(Debug)]
+pub enum AuthResult {
+    /// The account was connected
+    Success { account: String },
+    /// The provider sent back an error, such as the user denying access
+    ProviderError { error: String, description: String },
+    /// The user didn't grant every scope the server needs
+    ScopeMismatch { missing: Vec<String> },
+    /// The callback was missing parameters or had an invalid state
+    InvalidRequest(String),
+    /// Something went wrong on our side, the details are logged but not shown
+    InternalError(String),
+}
+
+/// Render the page shown at the end of an OAuth flow
+pub fn auth_page(provider: AuthProvider, result: AuthResult) -> (StatusCode, Html<String>) {
+    let name = provider_name(provider);
+
+    let (status, title, message, details) = match &result {
+        AuthResult::Success { account } => (
+            StatusCode::OK,
+            format!("Connected to {}", name),
+            format!(
+                "Signed in as {}. You can close this tab and return to VRCTV.",
+                account
+            ),
+            String::new(),
+        ),
+        AuthResult::ProviderError { error, description } => (
+            StatusCode::BAD_REQUEST,
+            format!("{} didn't connect", name),
+            format!(
+                "{} returned an error. You can try again from the app.",
+                name
+            ),
+            format!("{}: {}", error, description),
+        ),
+        AuthResult::ScopeMismatch { missing } => (
+            StatusCode::BAD_REQUEST,
+            "Missing permissions".to_string(),
+            format!(
+                "VRCTV needs every requested {} permission to work. Please try again and accept all of them.",
+                name
+            ),
+            format!("Missing: {}", missing.join(", ")),
+        ),
+        AuthResult::InvalidRequest(e) => (
+            StatusCode::BAD_REQUEST,
+            "Invalid sign in link".to_string(),
+            "This sign in link is invalid or has already been used. Please start again from the app."
+                .to_string(),
+            e.clone(),
+        ),
+        AuthResult::InternalError(e) => {
+            // The details can hold database or token errors, so they only go to the log
+            error!("Failed to connect {}: {}", name, e);
+
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong".to_string(),
+                format!(
+                    "We couldn't finish connecting to {}. Please try again later.",
+                    name
+                ),
+                String::new(),
+            )
+        }
+    };
+
+    let success = matches!(result, AuthResult::Success { .. });
+    let deep_link = format!(
+        "vrctv://auth/{}/{}",
+        name.to_lowercase(),
+        if success { "success" } else { "error" }
+    );
+
+    (
+        status,
+        page(
+            &title,
+            &message,
+            &details,
+            if success {
+                ACCENT_SUCCESS
+            } else {
+                ACCENT_ERROR
+            },
+            &deep_link,
+            success,
+        ),
+    )
+}
+
+/// Render the status page served at `/`
+pub fn status_page(healthy: bool, details: &str) -> (StatusCode, Html<String>) {
+    let (status, title, message, accent) = if healthy {
+        (
+            StatusCode::OK,
+            "VRCTV server is running",
+            "Everything is working. Connect from the VRCTV desktop app.",
+            ACCENT_INFO,
+        )
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "VRCTV server is degraded",
+            "Some parts of the server aren't working right now.",
+            ACCENT_ERROR,
+        )
+    };
+
+    (
+        status,
+        page(title, message, details, accent, "vrctv://", false),
+    )
+}
+
+fn page(
+    title: &str,
+    message: &str,
+    details: &str,
+    accent: &str,
+    deep_link: &str,
+    auto_close: bool,
+) -> Html<String> {
+    let variables = HashMap::from([
+        ("title".to_string(), escape_html(title)),
+        ("message".to_string(), escape_html(message)),
+        ("details".to_string(), escape_html(details)),
+        ("accent".to_string(), accent.to_string()),
+        ("deep_link".to_string(), escape_html(deep_link)),
+        ("auto_close".to_string(), auto_close.to_string()),
+        ("version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+    ]);
+
+    Html(render(PAGE_TEMPLATE, &variables))
+}
+
+fn provider_name(provider: AuthProvider) -> &'static str {
+    match provider {
+        AuthProvider::Twitch => "Twitch",
+        AuthProvider::Streamlabs => "Streamlabs",
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -1,32 +1,52 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::BTreeSet,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use axum::extract::ws::{Message, WebSocket};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
 use futures_util::{SinkExt, StreamExt};
 use log::{debug, error, info};
 use rust_socketio::Payload as SocketioPayload;
 use serde_json::Value;
-use tokio::sync::{
-    Mutex,
-    mpsc::{self, Sender},
+use sha2::{Digest, Sha256};
+use tokio::{
+    sync::{
+        Mutex,
+        mpsc::{self, Sender},
+    },
+    time::interval,
 };
 use twitch_api::{
     HelixClient, TWITCH_EVENTSUB_WEBSOCKET_URL,
-    twitch_oauth2::{self, AccessToken, ClientId, ClientSecret, RefreshToken},
+    twitch_oauth2::{self, AccessToken, ClientId, ClientSecret, RefreshToken, TwitchToken},
 };
 use vrctv_common::{
-    ClientMessage, CodeRequest, ConnectRequest, ConnectResponse, ErrorMessage, ServerMessage,
-    StreamLabsEvent, StreamLabsEvents, TaskResponse,
+    AuthProvider, AuthorizeRequest, AuthorizeResponse, ClientMessage, CodeRequest, ConnectRequest,
+    ConnectResponse, DeviceInfo, DeviceList, DeviceRole, ErrorMessage, PROTOCOL_VERSION,
+    RegisterDevice, ServerMessage, SetDeviceRole, StreamLabsEvent, StreamLabsEvents, TaskResponse,
 };
 
 use crate::{
     AppState,
+    bus::{SessionBus, SessionEvent},
     config::config,
     db::Database,
     entities::{ActiveKey, ActiveStreamLabsKey, ActiveTwitchKey},
+    metrics,
     streamlabs::{self, socket::SocketioConnection},
     twitch::{
-        events::{handle_event, handle_twitch_trigger},
+        self,
+        commands::CommandRegistry,
+        events::{
+            handle_event, handle_twitch_trigger, trigger_is_read_only, trigger_latency_name,
+            trigger_name, trigger_request_id,
+        },
         eventsub::EventSubWebsocket,
+        redemptions::refund_stale_redemptions,
+        rewards::{REWARD_UPDATE_INTERVAL, RewardUpdateQueue, flush_reward_updates},
     },
 };
 
@@ -36,15 +56,45 @@ pub struct ClientContext {
     pub state_token: Option<String>,
     pub twitch: Option<twitch_oauth2::UserToken>,
     pub streamlabs: Option<streamlabs::UserToken>,
+    /// What this websocket registered itself as, shown to the other devices on the state token
+    pub device: DeviceInfo,
+    /// Whether this server holds the session, otherwise another server connects to Twitch and
+    /// Streamlabs for it and passes the events on
+    pub holds_session: bool,
+}
+
+/// Name given to devices that didn't register one
+const DEFAULT_DEVICE_NAME: &str = "Unnamed device";
+const MAX_DEVICE_NAME_LENGTH: usize = 64;
+const MAX_DEVICE_KEY_LENGTH: usize = 128;
+
+/// A websocket sharing a state token, with its own sender so it can be told its own id
+#[derive(Clone, Debug)]
+pub struct AttachedDevice {
+    pub info: DeviceInfo,
+    pub sender: Sender<Message>,
 }
 
 #[derive(Clone, Debug)]
 pub struct ClientConnection {
+    pub state_token: String,
     pub sender: Vec<Sender<Message>>,
     pub context: Arc<Mutex<ClientContext>>,
+    /// Every device on this state token, in the order they attached
+    pub devices: Vec<AttachedDevice>,
 
     pub twitch_connection: Option<Arc<Mutex<EventSubWebsocket>>>,
     pub streamlabs_connection: Option<Arc<Mutex<SocketioConnection>>>,
+
+    /// Reward changes shared by every client on this state token, sent to Twitch in batches
+    pub reward_updates: Arc<Mutex<RewardUpdateQueue>>,
+    /// Chat commands configured by the clients on this state token
+    pub commands: Arc<Mutex<CommandRegistry>>,
+
+    /// Whether this server holds the session and has the Twitch and Streamlabs connections for it
+    pub held: bool,
+    /// Where events are passed on to the clients of the session on other servers
+    pub session_bus: SessionBus,
 }
 
 impl ClientConnection {
@@ -61,6 +111,33 @@ impl ClientConnection {
         Ok(())
     }
 
+    /// Tell every device which devices share the state token
+    pub async fn send_devices(&self) -> Result<(), String> {
+        let devices = self
+            .devices
+            .iter()
+            .map(|d| d.info.clone())
+            .collect::<Vec<_>>();
+
+        for device in &self.devices {
+            let msg = ServerMessage::Devices(DeviceList {
+                own_id: device.info.id.clone(),
+                devices: devices.clone(),
+            });
+            send_message(msg, &device.sender).await?;
+        }
+        Ok(())
+    }
+
+    /// The role of a device on this state token, devices that aren't attached are viewers
+    pub fn device_role(&self, device_id: &str) -> DeviceRole {
+        self.devices
+            .iter()
+            .find(|d| d.info.id == device_id)
+            .map(|d| d.info.role)
+            .unwrap_or_default()
+    }
+
     pub async fn get_connect_message(&self) -> ServerMessage {
         let context = self.context.lock().await;
         ServerMessage::ConnectResponse(ConnectResponse {
@@ -82,10 +159,81 @@ impl ClientConnection {
             has_streamlabs: context.streamlabs.is_some(),
             streamlabs_id: context.streamlabs.as_ref().map(|s| s.user_id.to_string()),
             streamlabs_name: context.streamlabs.as_ref().map(|s| s.login.clone()),
+            twitch_scopes: twitch_scopes(context.twitch.as_ref()),
         })
     }
 }
 
+/// Turn a stored Twitch key back into a token, refreshing it if it expired
+pub async fn twitch_token_from_key(
+    http_client: &reqwest::Client,
+    key: ActiveTwitchKey,
+) -> Result<twitch_oauth2::UserToken, String> {
+    let config = config().await;
+
+    let stored = AccessToken::new(key.authentication);
+    let token = twitch_oauth2::UserToken::from_existing_or_refresh_token(
+        http_client,
+        stored.clone(),
+        RefreshToken::new(key.refresh),
+        ClientId::new(config.twitch_oauth().client().to_string()),
+        Some(ClientSecret::new(
+            config.twitch_oauth().secret().to_string(),
+        )),
+    )
+    .await;
+
+    // A different access token means the stored one had expired and was refreshed
+    match &token {
+        Ok(token) if token.access_token != stored => metrics::record_token_refresh("twitch", true),
+        Ok(_) => {}
+        Err(_) => metrics::record_token_refresh("twitch", false),
+    }
+
+    token.map_err(|e| format!("Twitch Validation Error: {}", e))
+}
+
+/// Turn a stored Streamlabs key back into a token, refreshing it if it expired
+pub async fn streamlabs_token_from_key(
+    http_client: &reqwest::Client,
+    key: ActiveStreamLabsKey,
+) -> Result<streamlabs::UserToken, String> {
+    let config = config().await;
+
+    streamlabs::UserToken::from_existing_or_refresh_token(
+        http_client,
+        config.streamlabs_oauth().redirect().to_string(),
+        key.authentication,
+        key.refresh,
+        config.streamlabs_oauth().client().to_string(),
+        config.streamlabs_oauth().secret().to_string(),
+    )
+    .await
+    .map_err(|e| format!("Streamlabs Validation Error: {}", e))
+}
+
+/// The scopes granted to a Twitch token, as sent in the connect response
+fn twitch_scopes(token: Option<&twitch_oauth2::UserToken>) -> Vec<String> {
+    token
+        .map(|t| t.scopes().iter().map(|s| s.to_string()).collect())
+        .unwrap_or_default()
+}
+
+/// Put a token used without holding the context lock back, but only if it was refreshed meanwhile
+async fn store_refreshed_token(
+    client_context: &Mutex<ClientContext>,
+    before: &AccessToken,
+    token: twitch_oauth2::UserToken,
+) {
+    if token.access_token == *before {
+        return;
+    }
+
+    if let Some(twitch) = client_context.lock().await.twitch.as_mut() {
+        *twitch = token;
+    }
+}
+
 /// Actual websocket statemachine (one will be spawned per connection)
 pub async fn handle_client(
     db: Database,
@@ -99,34 +247,52 @@ pub async fn handle_client(
         state_token: None,
         twitch: None,
         streamlabs: None,
+        device: DeviceInfo {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: DEFAULT_DEVICE_NAME.into(),
+            role: DeviceRole::default(),
+        },
+        holds_session: true,
     }));
     let mut t_connection = None;
     let mut sl_connection = None;
+    let mut leader = false;
     let (transmitter, mut receiver) = mpsc::unbounded_channel::<SocketioPayload>();
     let (mut tx, mut rx) = socket.split();
 
     let (table_tx, mut table_rx) = mpsc::channel::<Message>(32);
 
+    // Only the socket holding the Twitch connection runs the refund check, so clients sharing a
+    // state token don't refund the same redemption twice. The refund interval ticks on every
+    // socket, so the others notice within a minute when that one leaves. Reward updates are queued
+    // on the server the client is connected to, so the oldest socket on each server flushes them
+    let auto_refund_minutes = config().await.redemption_auto_refund_minutes();
+    let mut refund_interval = interval(Duration::from_secs(60));
+    let mut reward_update_interval = interval(REWARD_UPDATE_INTERVAL);
+
     loop {
         if let Some(state_token) = { client_context.lock().await.state_token.clone() } {
             // if we have a state token, we can register the connection in the connection table
             // In a block so we drop the lock as soon as possible
-            {
+            let joined = {
                 let mut table = app_state.connection_table.lock().await;
+                let device = client_context.lock().await.device.clone();
+                let held = client_context.lock().await.holds_session;
                 if !table.contains_key(&state_token) {
+                    // Only the server holding the session connects, the others get its events
                     let twitch_connection = match &client_context.lock().await.twitch {
-                        Some(token) => Some(Arc::new(Mutex::new(EventSubWebsocket {
+                        Some(token) if held => Some(Arc::new(Mutex::new(EventSubWebsocket {
                             session_id: None,
                             connect_url: TWITCH_EVENTSUB_WEBSOCKET_URL.clone(),
                             connection: None,
                             token: token.clone(),
                             client: HelixClient::with_client(http_client.clone()),
                         }))),
-                        None => None,
+                        _ => None,
                     };
                     t_connection = twitch_connection.clone();
                     let streamlabs_connection = match &client_context.lock().await.streamlabs {
-                        Some(token) => Some(Arc::new(Mutex::new({
+                        Some(token) if held => Some(Arc::new(Mutex::new({
                             let conn = SocketioConnection::get_connection(
                                 transmitter.clone(),
                                 token.socket_token.as_str(),
@@ -143,20 +309,28 @@ pub async fn handle_client(
                                 }
                             }
                         }))),
-                        None => None,
+                        _ => None,
                     };
                     sl_connection = streamlabs_connection.clone();
 
                     // Add the sender to the connection table
-                    table.insert(
-                        state_token,
-                        ClientConnection {
-                            sender: vec![table_tx.clone()],
-                            context: client_context.clone(),
-                            twitch_connection,
-                            streamlabs_connection,
-                        },
-                    );
+                    let client = ClientConnection {
+                        state_token: state_token.clone(),
+                        sender: vec![table_tx.clone()],
+                        context: client_context.clone(),
+                        devices: vec![AttachedDevice {
+                            info: device,
+                            sender: table_tx.clone(),
+                        }],
+                        twitch_connection,
+                        streamlabs_connection,
+                        reward_updates: Arc::new(Mutex::new(RewardUpdateQueue::default())),
+                        commands: Arc::new(Mutex::new(CommandRegistry::default())),
+                        held,
+                        session_bus: app_state.session_bus.clone(),
+                    };
+                    table.insert(state_token.clone(), client.clone());
+                    Some(client)
                 } else {
                     // Add the sender to the existing connection
                     if let Some(client) = table.get_mut(&state_token) {
@@ -172,7 +346,43 @@ pub async fn handle_client(
                             client.sender.push(table_tx.clone());
                         }
                     }
+
+                    match table.get_mut(&state_token) {
+                        Some(client)
+                            if !client
+                                .devices
+                                .iter()
+                                .any(|d| d.sender.same_channel(&table_tx)) =>
+                        {
+                            client.devices.push(AttachedDevice {
+                                info: device,
+                                sender: table_tx.clone(),
+                            });
+                            Some(client.clone())
+                        }
+                        _ => None,
+                    }
+                }
+            };
+
+            // The oldest socket on the state token reads the shared Twitch connection and runs the
+            // background work for it, so it is handed on when that socket leaves
+            (leader, t_connection) = {
+                let table = app_state.connection_table.lock().await;
+                match table
+                    .get(&state_token)
+                    .filter(|c| c.sender.first().is_some_and(|s| s.same_channel(&table_tx)))
+                {
+                    Some(c) => (true, c.twitch_connection.clone()),
+                    None => (false, None),
                 }
+            };
+
+            // Sent outside the lock, the channels may be full
+            if let Some(client) = joined
+                && let Err(e) = client.send_devices().await
+            {
+                error!("Error sending device list for {}: {}", who, e);
             }
         }
 
@@ -200,11 +410,17 @@ pub async fn handle_client(
             }
             Some(msg) = table_rx.recv() => {
                 debug!("Sending message to {}: {:?}", who, msg);
+                // Close frames come from the admin API, don't wait for the client to answer
+                let closing = matches!(msg, Message::Close(_));
                 // send message to client
                 if let Err(e) = tx.send(msg).await {
                     error!("Error sending message to {}: {}", who, e);
                     break;
                 }
+                if closing {
+                    info!("Closed connection from {}", who);
+                    break;
+                }
             }
             Some(streamlabs_message) = async {
                 if let Some(conn) = sl_connection.clone() {
@@ -309,6 +525,55 @@ pub async fn handle_client(
                     }
                 }
             }
+            _ = refund_interval.tick() => {
+                if t_connection.is_none() || auto_refund_minutes.is_none() {
+                    continue;
+                }
+
+                let max_age = chrono::Duration::minutes(auto_refund_minutes.unwrap_or_default() as i64);
+                // The lock isn't held across the Helix calls, so other messages aren't stalled
+                let twitch = client_context.lock().await.twitch.clone();
+
+                if let Some(mut twitch) = twitch {
+                    let before = twitch.access_token.clone();
+                    let result = refund_stale_redemptions(&http_client, &mut twitch, max_age).await;
+                    store_refreshed_token(&client_context, &before, twitch).await;
+
+                    match result {
+                        Ok(0) => {}
+                        Ok(refunded) => info!("Refunded {} stale redemptions for {}", refunded, who),
+                        Err(e) => error!("Error refunding stale redemptions for {}: {}", who, e),
+                    }
+                }
+            }
+            _ = reward_update_interval.tick(), if leader => {
+                let reward_updates = {
+                    let table = app_state.connection_table.lock().await;
+                    if let Some(client) = &client_context.lock().await.state_token {
+                        table.get(client).map(|c| c.reward_updates.clone())
+                    } else {
+                        None
+                    }
+                };
+
+                if let Some(reward_updates) = reward_updates {
+                    if reward_updates.lock().await.is_empty() {
+                        continue;
+                    }
+
+                    let twitch = client_context.lock().await.twitch.clone();
+                    if let Some(mut twitch) = twitch {
+                        let before = twitch.access_token.clone();
+                        let result = flush_reward_updates(&http_client, &mut twitch, &reward_updates).await;
+                        store_refreshed_token(&client_context, &before, twitch).await;
+
+                        if let Err(e) = result {
+                            error!("Error updating custom rewards for {}: {}", who, e);
+                            let _ = send_error(e, "twitch", &table_tx, -1).await;
+                        }
+                    }
+                }
+            }
             else => {
                 // both streams closed
                 break;
@@ -320,11 +585,17 @@ pub async fn handle_client(
     if let Some(state_token) = &client_context.lock().await.state_token {
         let mut table = app_state.connection_table.lock().await;
         let mut should_remove = false;
+        let mut remaining = None;
 
         if let Some(client) = table.get_mut(state_token) {
             client.sender.retain(|s| !s.same_channel(&table_tx));
+            // By sender, a reconnected device may already have taken over the id
+            client.devices.retain(|d| !d.sender.same_channel(&table_tx));
 
             should_remove = client.sender.is_empty();
+            if !should_remove {
+                remaining = Some(client.clone());
+            }
         }
 
         if should_remove {
@@ -348,6 +619,19 @@ pub async fn handle_client(
                 }
             }
         }
+        drop(table);
+
+        // Another server can take the session now that none of its clients are here
+        if should_remove && let Err(e) = app_state.session_bus.release(&db, state_token).await {
+            error!("Failed to release session {}: {}", state_token, e);
+        }
+
+        // Let the devices that are left know this one is gone
+        if let Some(client) = remaining
+            && let Err(e) = client.send_devices().await
+        {
+            error!("Error sending device list for {}: {}", who, e);
+        }
     }
 
     // returning from the handler closes the websocket connection
@@ -363,8 +647,74 @@ pub async fn send_message(msg: ServerMessage, tx: &Sender<Message>) -> Result<()
     tx.send(encoded_text).await.map_err(|e| e.to_string())
 }
 
+/// Send a Twitch or Streamlabs event to every client of the session, including the ones connected
+/// to other servers
 pub async fn send_all_message(msg: ServerMessage, conn: &ClientConnection) -> Result<(), String> {
-    conn.send(msg).await.map_err(|e| e.to_string())
+    metrics::record_forwarded(&msg);
+    conn.send(msg.clone()).await.map_err(|e| e.to_string())?;
+
+    if let Err(e) = conn
+        .session_bus
+        .publish(SessionEvent::Send {
+            state_token: conn.state_token.clone(),
+            message: msg,
+        })
+        .await
+    {
+        error!("Failed to forward event for {}: {}", conn.state_token, e);
+    }
+    Ok(())
+}
+
+/// The role a device has on its state token. The connection table is the source of truth, as
+/// controllers can change the roles of other devices
+async fn device_role(app_state: &AppState, context: &ClientContext) -> DeviceRole {
+    let table = app_state.connection_table.lock().await;
+    context
+        .state_token
+        .as_ref()
+        .and_then(|s| table.get(s))
+        .map(|c| c.device_role(&context.device.id))
+        .unwrap_or_default()
+}
+
+/// Trim a device name and fall back to the default one if nothing is left
+fn device_name(name: &str) -> String {
+    let name = name.trim();
+    if name.is_empty() {
+        DEFAULT_DEVICE_NAME.into()
+    } else {
+        name.chars().take(MAX_DEVICE_NAME_LENGTH).collect()
+    }
+}
+
+/// Devices are known by a hash of their key, so the ids shown to the other devices can't be used
+/// to take over their role
+fn device_id_from_key(key: &str) -> String {
+    URL_SAFE_NO_PAD.encode(&Sha256::digest(key.as_bytes())[..16])
+}
+
+/// Refuse clients too old for this server, telling them to update instead of failing later
+async fn protocol_supported(
+    protocol_version: Option<u32>,
+    tx: &Sender<Message>,
+) -> Result<bool, String> {
+    if protocol_version.unwrap_or(1) >= PROTOCOL_VERSION {
+        return Ok(true);
+    }
+
+    info!(
+        "Refusing client with protocol version {:?}",
+        protocol_version
+    );
+    send_error(
+        "This version of VRCTV is too old for this server, update it to connect",
+        "version",
+        tx,
+        -1,
+    )
+    .await?;
+    Ok(false)
 }
 
 /// Returns an error to the client
@@ -383,6 +733,16 @@ pub async fn send_error<E: std::fmt::Display>(
     send_message(error_response, tx).await
 }
 
+/// Tell the client the database couldn't be used, the socket stays open so it can try again
+async fn send_database_error<E: std::fmt::Display>(
+    error: E,
+    tx: &Sender<Message>,
+) -> Result<bool, String> {
+    error!("Database error: {}", error);
+    send_error(format!("Database error: {}", error), "database", tx, -1).await?;
+    Ok(true)
+}
+
 pub async fn send_task_response(
     success: bool,
     message: Option<String>,
@@ -430,17 +790,25 @@ pub async fn handle_message(
             let mut context = context.lock().await;
             let client_msg: ClientMessage = serde_json::from_str(text.as_str())
                 .map_err(|e| format!("Failed to parse message: {}", e))?;
-            let conn = connection
-                .connection()
-                .map_err(|e| format!("Database connection error: {}", e))?;
 
             match client_msg {
-                ClientMessage::CodeRequest(CodeRequest { client_version }) => {
+                ClientMessage::CodeRequest(CodeRequest {
+                    client_version,
+                    protocol_version,
+                }) => {
+                    if !protocol_supported(protocol_version, tx).await? {
+                        return Ok(true);
+                    }
+
                     // Wait for rate limiter
                     let _ = app_state.rate_limiter.new_user.lock().await;
 
                     // Generate a new state token
                     let state_token = uuid::Uuid::new_v4().to_string();
+                    match app_state.session_bus.claim(connection, &state_token).await {
+                        Ok(claimed) => context.holds_session = claimed,
+                        Err(e) => return send_database_error(e, tx).await,
+                    }
                     context.state_token = Some(state_token.clone());
 
                     // Send the state token back to the client
@@ -483,22 +851,30 @@ pub async fn handle_message(
                 ClientMessage::Connect(ConnectRequest {
                     state_token,
                     client_version,
+                    protocol_version,
                 }) => {
+                    if !protocol_supported(protocol_version, tx).await? {
+                        return Ok(true);
+                    }
+
                     context.state_token = Some(state_token.clone());
 
                     // Register the connection if it doesn't already exist
-                    let existing = {
-                        ActiveKey::get(&conn, &state_token)
-                            .map_err(|e| format!("Database error: {}", e))?
-                            .is_some()
+                    let existing = match connection.active_key(&state_token).await {
+                        Ok(key) => key.is_some(),
+                        Err(e) => {
+                            context.state_token = None;
+                            return send_database_error(e, tx).await;
+                        }
                     };
 
                     if !existing {
                         // Insert the new active key
                         let active_key = ActiveKey::new(state_token.clone());
-                        active_key
-                            .insert(&conn)
-                            .map_err(|e| format!("Database error: {}", e))?;
+                        if let Err(e) = connection.insert_active_key(&active_key).await {
+                            context.state_token = None;
+                            return send_database_error(e, tx).await;
+                        }
                     }
 
                     let existing_connection = {
@@ -509,6 +885,26 @@ pub async fn handle_message(
                         }
                     };
 
+                    // Only one server talks to Twitch and Streamlabs for a session, the others get
+                    // the events from it over the session bus
+                    if existing_connection.is_none() {
+                        match app_state.session_bus.claim(connection, &state_token).await {
+                            Ok(claimed) => {
+                                if !claimed {
+                                    info!(
+                                        "Session {} is held by another server, forwarding its events",
+                                        state_token
+                                    );
+                                }
+                                context.holds_session = claimed;
+                            }
+                            Err(e) => {
+                                context.state_token = None;
+                                return send_database_error(e, tx).await;
+                            }
+                        }
+                    }
+
                     if let Some(existing_connection) = existing_connection {
                         // If we already have a connection, copy over the tokens
                         info!("Existing connection found for state token: {}", state_token);
@@ -519,24 +915,19 @@ pub async fn handle_message(
                         // Otherwise, we will check the database for existing connections
 
                         // Check twitch connection if it exists
-                        let twitch_user = ActiveTwitchKey::get_by_active_key(&conn, &state_token)
-                            .map_err(|e| format!("Database error: {}", e))?;
+                        let twitch_user = match connection.twitch_key_by_state(&state_token).await {
+                            Ok(key) => key,
+                            Err(e) => {
+                                context.state_token = None;
+                                return send_database_error(e, tx).await;
+                            }
+                        };
                         if let Some(twitch_user) = twitch_user {
                             // Check the connection table for an existing connection
                             // Wait for rate limiter
                             let _ = app_state.rate_limiter.twitch.lock().await;
 
-                            let token = twitch_oauth2::UserToken::from_existing_or_refresh_token(
-                                http_client,
-                                AccessToken::new(twitch_user.authentication),
-                                RefreshToken::new(twitch_user.refresh),
-                                ClientId::new(config.twitch_oauth().client().to_string()),
-                                Some(ClientSecret::new(
-                                    config.twitch_oauth().secret().to_string(),
-                                )),
-                            )
-                            .await
-                            .map_err(|e| format!("Twitch Validation Error: {}", e))?;
+                            let token = twitch_token_from_key(http_client, twitch_user).await?;
 
                             info!("Twitch user connected: {}", token.login);
                             context.twitch = Some(token);
@@ -545,22 +936,20 @@ pub async fn handle_message(
                         }
 
                         // Check streamlabs connection if it exists
-                        if let Some(streamlabs_user) =
-                            ActiveStreamLabsKey::get_by_active_key(&conn, &state_token)
-                                .map_err(|e| format!("Database error: {}", e))?
-                        {
+                        let streamlabs_user =
+                            match connection.streamlabs_key_by_state(&state_token).await {
+                                Ok(key) => key,
+                                Err(e) => {
+                                    context.state_token = None;
+                                    return send_database_error(e, tx).await;
+                                }
+                            };
+                        if let Some(streamlabs_user) = streamlabs_user {
                             // Wait for rate limiter
                             let _ = app_state.rate_limiter.streamlabs.lock().await;
 
-                            let token = streamlabs::UserToken::from_existing_or_refresh_token(
-                                &http_client,
-                                config.streamlabs_oauth().redirect().to_string(),
-                                streamlabs_user.authentication,
-                                streamlabs_user.refresh,
-                                config.streamlabs_oauth().client().to_string(),
-                                config.streamlabs_oauth().secret().to_string(),
-                            )
-                            .await;
+                            let token =
+                                streamlabs_token_from_key(http_client, streamlabs_user).await;
 
                             if let Ok(token) = token {
                                 info!("Streamlabs user connected: {}", token.login);
@@ -589,6 +978,7 @@ pub async fn handle_message(
                         has_streamlabs: context.streamlabs.is_some(),
                         streamlabs_id: context.streamlabs.as_ref().map(|s| s.user_id.to_string()),
                         streamlabs_name: context.streamlabs.as_ref().map(|s| s.login.clone()),
+                        twitch_scopes: twitch_scopes(context.twitch.as_ref()),
                     });
 
                     let response_text =
@@ -624,17 +1014,236 @@ pub async fn handle_message(
                         send_message(warning_message, tx).await?;
                     }
                 }
+                ClientMessage::Authorize(AuthorizeRequest { provider, scopes }) => {
+                    let state_token = context
+                        .state_token
+                        .clone()
+                        .ok_or("Connect before authorizing")?;
+
+                    if device_role(app_state, &context).await == DeviceRole::Viewer {
+                        send_error(
+                            "This device is a viewer and can't authorize accounts",
+                            "authorize",
+                            tx,
+                            -1,
+                        )
+                        .await?;
+                        return Ok(true);
+                    }
+
+                    let (scopes, force_verify) = match provider {
+                        AuthProvider::Twitch => {
+                            match twitch::requested_scopes(
+                                config,
+                                connection,
+                                &state_token,
+                                &scopes,
+                            )
+                            .await
+                            {
+                                Ok(scopes) => scopes,
+                                Err(e) => {
+                                    send_error(e, "authorize", tx, -1).await?;
+                                    return Ok(true);
+                                }
+                            }
+                        }
+                        AuthProvider::Streamlabs => (BTreeSet::new(), false),
+                    };
+
+                    let state = match app_state
+                        .oauth_states
+                        .issue(state_token, provider, scopes, force_verify)
+                        .await
+                    {
+                        Ok(state) => state,
+                        Err(e) => {
+                            send_error(e, "authorize", tx, -1).await?;
+                            return Ok(true);
+                        }
+                    };
+
+                    send_message(
+                        ServerMessage::AuthorizeResponse(AuthorizeResponse { provider, state }),
+                        tx,
+                    )
+                    .await?;
+                }
                 ClientMessage::TwitchTrigger(trigger_request) => {
+                    if device_role(app_state, &context).await == DeviceRole::Viewer
+                        && !trigger_is_read_only(&trigger_request)
+                    {
+                        send_task_response(
+                            false,
+                            Some(format!(
+                                "This device is a viewer and can't use {}",
+                                trigger_name(&trigger_request)
+                            )),
+                            tx,
+                            trigger_request_id(&trigger_request),
+                        )
+                        .await?;
+                        return Ok(true);
+                    }
+
                     if context.twitch.is_none() {
                         return Err("Twitch not connected".into());
                     }
 
+                    let connection = {
+                        let table = app_state.connection_table.lock().await;
+                        context
+                            .state_token
+                            .as_ref()
+                            .and_then(|s| table.get(s))
+                            .cloned()
+                            .ok_or("Connection not registered")?
+                    };
+
                     let twitch = context.twitch.as_mut().unwrap();
-                    if handle_twitch_trigger(&http_client, twitch, trigger_request.clone(), tx)
-                        .await?
-                    {
+                    let request_name = trigger_latency_name(&trigger_request);
+
+                    let started = Instant::now();
+                    let refreshed = handle_twitch_trigger(
+                        http_client,
+                        twitch,
+                        trigger_request.clone(),
+                        tx,
+                        &connection,
+                    )
+                    .await?;
+                    if let Some(request_name) = request_name {
+                        metrics::record_helix_latency(request_name, started.elapsed());
+                    }
+
+                    if refreshed {
                         // Token was refreshed, retry once
-                        handle_twitch_trigger(&http_client, twitch, trigger_request, tx).await?;
+                        let started = Instant::now();
+                        handle_twitch_trigger(
+                            http_client,
+                            twitch,
+                            trigger_request,
+                            tx,
+                            &connection,
+                        )
+                        .await?;
+                        if let Some(request_name) = request_name {
+                            metrics::record_helix_latency(request_name, started.elapsed());
+                        }
+                    }
+                }
+                ClientMessage::RegisterDevice(RegisterDevice { name, role, key }) => {
+                    let key = key.trim();
+                    if key.is_empty() || key.len() > MAX_DEVICE_KEY_LENGTH {
+                        send_error(
+                            format!(
+                                "Device keys have to be 1 to {} characters",
+                                MAX_DEVICE_KEY_LENGTH
+                            ),
+                            "device",
+                            tx,
+                            -1,
+                        )
+                        .await?;
+                        return Ok(true);
+                    }
+                    context.device.id = device_id_from_key(key);
+                    context.device.name = device_name(&name);
+
+                    // Devices that haven't connected yet are announced once they join a state token
+                    let (connection, granted) = {
+                        let mut table = app_state.connection_table.lock().await;
+                        match context.state_token.as_ref().and_then(|s| table.get_mut(s)) {
+                            Some(c) if c.devices.iter().any(|d| d.sender.same_channel(tx)) => {
+                                // A reconnecting device replaces the socket it left behind, and
+                                // keeps its role even if that socket hasn't timed out yet
+                                let own = |d: &AttachedDevice| d.sender.same_channel(tx);
+                                let was_controller = c.devices.iter().any(|d| {
+                                    (own(d) || d.info.id == context.device.id)
+                                        && d.info.role == DeviceRole::Controller
+                                });
+                                c.devices
+                                    .retain(|d| own(d) || d.info.id != context.device.id);
+
+                                // Controller is only self-assigned by the first controller of a
+                                // state token, after that an existing controller has to grant it
+                                let has_controller = c
+                                    .devices
+                                    .iter()
+                                    .any(|d| !own(d) && d.info.role == DeviceRole::Controller);
+                                context.device.role = match role {
+                                    DeviceRole::Controller if has_controller && !was_controller => {
+                                        DeviceRole::Viewer
+                                    }
+                                    role => role,
+                                };
+
+                                if let Some(device) = c.devices.iter_mut().find(|d| own(d)) {
+                                    device.info = context.device.clone();
+                                }
+                                (Some(c.clone()), context.device.role == role)
+                            }
+                            _ => {
+                                context.device.role = DeviceRole::Viewer;
+                                (None, role == DeviceRole::Viewer)
+                            }
+                        }
+                    };
+                    info!(
+                        "Device {} registered as {} ({:?})",
+                        context.device.id, context.device.name, context.device.role
+                    );
+
+                    if let Some(connection) = connection
+                        && let Err(e) = connection.send_devices().await
+                    {
+                        error!("Error sending device list: {}", e);
+                    }
+
+                    if !granted {
+                        send_error(
+                            "Only a controller can make this device a controller",
+                            "device",
+                            tx,
+                            -1,
+                        )
+                        .await?;
+                    }
+                }
+                ClientMessage::SetDeviceRole(SetDeviceRole { device_id, role }) => {
+                    let result = {
+                        let mut table = app_state.connection_table.lock().await;
+                        match context.state_token.as_ref().and_then(|s| table.get_mut(s)) {
+                            Some(c)
+                                if c.device_role(&context.device.id) == DeviceRole::Controller =>
+                            {
+                                match c.devices.iter_mut().find(|d| d.info.id == device_id) {
+                                    Some(device) => {
+                                        device.info.role = role;
+                                        if device_id == context.device.id {
+                                            context.device.role = role;
+                                        }
+                                        Ok(c.clone())
+                                    }
+                                    None => Err("That device isn't on this connection"),
+                                }
+                            }
+                            Some(_) => Err("Only a controller can change the roles of devices"),
+                            None => Err("Connect before changing the roles of devices"),
+                        }
+                    };
+
+                    match result {
+                        Ok(connection) => {
+                            info!(
+                                "Device {} set {} to {:?}",
+                                context.device.id, device_id, role
+                            );
+                            if let Err(e) = connection.send_devices().await {
+                                error!("Error sending device list: {}", e);
+                            }
+                        }
+                        Err(e) => send_error(e, "device", tx, -1).await?,
                     }
                 }
             }
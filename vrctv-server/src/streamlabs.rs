@@ -1,4 +1,4 @@
-use std::{collections::HashMap};
+use std::collections::HashMap;
 
 use anyhow::Result;
 use axum::{
@@ -6,14 +6,19 @@ use axum::{
     extract::{Query, State},
     response::IntoResponse,
 };
-use log::debug;
-use rusqlite::params;
+use axum_extra::{TypedHeader, headers};
+use log::{debug, error};
+use reqwest::Url;
+use vrctv_common::AuthProvider;
 
 use crate::{
     AppState,
+    bus::SessionEvent,
     config::config,
     db::Database,
-    entities::{ActiveKey, StreamlabsUser},
+    entities::{ActiveKey, ActiveStreamLabsKey, StreamlabsUser},
+    metrics, oauth,
+    pages::{AuthResult, auth_page},
 };
 /// IMPORTANT NOTE: Streamlabs uses OAuth2 tokens that do NOT expire
 pub mod socket;
@@ -128,6 +133,7 @@ impl UserToken {
             .send()
             .await?;
 
+        metrics::record_token_refresh("streamlabs", resp.status().is_success());
         if !resp.status().is_success() {
             return Err(anyhow::anyhow!(
                 "Failed to refresh token: HTTP {}",
@@ -173,9 +179,29 @@ impl UserToken {
     }
 }
 
+/// Build the Streamlabs authorization url, using PKCE with the given challenge
+pub async fn authorize_url(state: &str, code_challenge: &str) -> Result<Url, String> {
+    let config = config().await;
+
+    Url::parse_with_params(
+        "https://streamlabs.com/api/v2.0/authorize",
+        &[
+            ("response_type", "code"),
+            ("client_id", config.streamlabs_oauth().client()),
+            ("redirect_uri", config.streamlabs_oauth().redirect()),
+            ("scope", config.streamlabs_oauth().scopes()),
+            ("state", state),
+            ("code_challenge", code_challenge),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(|e| format!("Failed to build Streamlabs authorization url: {}", e))
+}
+
 pub async fn use_authorization_code(
     http_client: &reqwest::Client,
     code: &str,
+    code_verifier: &str,
 ) -> Result<(String, String), String> {
     let config = config().await;
 
@@ -191,6 +217,7 @@ pub async fn use_authorization_code(
             ("client_secret", &client_secret),
             ("redirect_uri", &callback_url),
             ("code", &code),
+            ("code_verifier", code_verifier),
         ])
         .send()
         .await
@@ -221,6 +248,7 @@ pub async fn use_authorization_code(
 
 pub async fn auth_callback(
     Query(params): Query<HashMap<String, String>>,
+    cookies: Option<TypedHeader<headers::Cookie>>,
     Extension(database): Extension<Database>,
     Extension(http_client): Extension<reqwest::Client>,
     State(app_state): State<AppState>,
@@ -230,40 +258,63 @@ pub async fn auth_callback(
             .get("error_description")
             .map(|s| s.as_str())
             .unwrap_or("No description provided");
-        return (
-            axum::http::StatusCode::BAD_REQUEST,
-            format!("Streamlabs returned an error: {}: {}", error, description),
+        return auth_page(
+            AuthProvider::Streamlabs,
+            AuthResult::ProviderError {
+                error: error.clone(),
+                description: description.to_string(),
+            },
         );
     }
 
     let code = if let Some(code) = params.get("code") {
         code
     } else {
-        return (
-            axum::http::StatusCode::BAD_REQUEST,
-            "Missing code parameter".to_string(),
+        return auth_page(
+            AuthProvider::Streamlabs,
+            AuthResult::InvalidRequest("Missing code parameter".to_string()),
         );
     };
-    let state = if let Some(state) = params.get("state") {
-        state
+    let pending = if let Some(state) = params.get("state") {
+        let csrf_token = cookies
+            .as_ref()
+            .and_then(|c| c.get(oauth::csrf_cookie_name(AuthProvider::Streamlabs)));
+        match app_state
+            .oauth_states
+            .take(state, AuthProvider::Streamlabs, csrf_token)
+            .await
+        {
+            Ok(pending) => pending,
+            Err(e) => {
+                return auth_page(
+                    AuthProvider::Streamlabs,
+                    AuthResult::InvalidRequest(format!("Invalid state: {}", e)),
+                );
+            }
+        }
     } else {
-        return (
-            axum::http::StatusCode::BAD_REQUEST,
-            "Missing state parameter".to_string(),
+        return auth_page(
+            AuthProvider::Streamlabs,
+            AuthResult::InvalidRequest("Missing state parameter".to_string()),
         );
     };
 
-    let conn = database.connection().unwrap();
-
-    let (auth_token, refresh_token) = match use_authorization_code(&http_client, code).await {
-        Ok(token) => token,
-        Err(e) => {
-            return (
-                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Streamlabs Authorization Code Error: {}", e),
-            );
-        }
-    };
+    let state = &pending.state_token;
+    let code_verifier = pending.code_verifier.as_deref().unwrap_or_default();
+
+    let (auth_token, refresh_token) =
+        match use_authorization_code(&http_client, code, code_verifier).await {
+            Ok(token) => token,
+            Err(e) => {
+                return auth_page(
+                    AuthProvider::Streamlabs,
+                    AuthResult::InternalError(format!(
+                        "Streamlabs Authorization Code Error: {}",
+                        e
+                    )),
+                );
+            }
+        };
 
     match UserToken::validate_token(&http_client, &auth_token, &refresh_token).await {
         Ok(streamlabs_user) => {
@@ -271,38 +322,55 @@ pub async fn auth_callback(
 
             // Insert or update the user in the database
             let user = StreamlabsUser::new(streamlabs_user.user_id);
-            match user.insert(&conn) {
+            match database.insert_streamlabs_user(&user).await {
                 Ok(_) => {}
                 Err(e) => {
-                    return (
-                        axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                        format!("Database error: {}", e),
+                    return auth_page(
+                        AuthProvider::Streamlabs,
+                        AuthResult::InternalError(format!("Database error: {}", e)),
                     );
                 }
             }
 
             // Insert the active key
-            ActiveKey::new(state.clone())
-                .insert(&conn)
-                .unwrap_or_else(|e| {
-                    debug!("Failed to insert active key: {}", e);
-                });
-            conn.execute(
-                "INSERT INTO active_stream_labs_keys (authentication, refresh, user, state, version)
-                 VALUES (?, ?, ?, ?, 1)
-                 ON CONFLICT(user) DO UPDATE SET
-                   authentication = excluded.authentication,
-                   refresh = excluded.refresh,
-                   state = excluded.state,
-                   version = active_stream_labs_keys.version + 1;",
-                params![
-                    streamlabs_user.access_token,
-                    streamlabs_user.refresh_token,
+            if let Err(e) = database
+                .insert_active_key(&ActiveKey::new(state.clone()))
+                .await
+            {
+                return auth_page(
+                    AuthProvider::Streamlabs,
+                    AuthResult::InternalError(format!("Database error: {}", e)),
+                );
+            }
+            if let Err(e) = database
+                .upsert_streamlabs_key(&ActiveStreamLabsKey::new(
+                    streamlabs_user.access_token.clone(),
+                    streamlabs_user.refresh_token.clone(),
                     user.id,
-                    state,
-                ],
-            )
-            .unwrap();
+                    state.clone(),
+                    1,
+                ))
+                .await
+            {
+                return auth_page(
+                    AuthProvider::Streamlabs,
+                    AuthResult::InternalError(format!("Database error: {}", e)),
+                );
+            }
+
+            let account = streamlabs_user.login.clone();
+
+            // Clients connected to other servers load the new token themselves
+            if let Err(e) = app_state
+                .session_bus
+                .publish(SessionEvent::Authorized {
+                    state_token: state.clone(),
+                    provider: AuthProvider::Streamlabs,
+                })
+                .await
+            {
+                error!("Failed to publish Streamlabs authorization: {}", e);
+            }
 
             // Notify any waiting client
             let mut table = app_state.connection_table.lock().await;
@@ -311,22 +379,22 @@ pub async fn auth_callback(
 
                 if let Err(e) = client.send(client.get_connect_message().await).await {
                     debug!("Failed to send Streamlabs connect message: {}", e);
-                    return (
-                        axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                        format!("Failed to send Streamlabs connect message: {}", e),
+                    return auth_page(
+                        AuthProvider::Streamlabs,
+                        AuthResult::InternalError(format!(
+                            "Failed to send Streamlabs connect message: {}",
+                            e
+                        )),
                     );
                 }
             }
 
-            (
-                axum::http::StatusCode::OK,
-                "Streamlabs authentication successful! You can close this tab.".to_string(),
-            )
+            auth_page(AuthProvider::Streamlabs, AuthResult::Success { account })
         }
         Err(e) => {
-            return (
-                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Streamlabs Validation Error: {}", e),
+            return auth_page(
+                AuthProvider::Streamlabs,
+                AuthResult::InternalError(format!("Streamlabs Validation Error: {}", e)),
             );
         }
     }
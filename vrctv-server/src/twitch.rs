@@ -1,26 +1,100 @@
-use std::{collections::HashMap};
+use std::collections::{BTreeSet, HashMap};
 
 use axum::{
     Extension,
     extract::{Query, State},
     response::IntoResponse,
 };
-use log::{debug, info};
+use axum_extra::{TypedHeader, headers};
+use log::{debug, error, info};
 use reqwest::Url;
-use rusqlite::params;
 use twitch_api::twitch_oauth2::{
-    ClientSecret, TwitchToken, UserToken, UserTokenBuilder, client::Client, id::TwitchTokenResponse,
+    ClientSecret, Scope, TwitchToken, UserToken, UserTokenBuilder, client::Client,
+    id::TwitchTokenResponse,
 };
+use vrctv_common::AuthProvider;
 
 use crate::{
     AppState,
-    config::config,
+    bus::SessionEvent,
+    config::{Config, config},
     db::Database,
-    entities::{ActiveKey, TwitchUser},
+    entities::{ActiveKey, ActiveTwitchKey, TwitchUser},
+    oauth,
+    pages::{AuthResult, auth_page},
 };
 
+pub mod commands;
 pub mod events;
 pub mod eventsub;
+pub mod redemptions;
+pub mod rewards;
+
+/// Split a space separated scope string into a set, so order and duplicates don't matter
+pub fn parse_scopes(scopes: &str) -> BTreeSet<String> {
+    scopes.split_whitespace().map(String::from).collect()
+}
+
+/// Build the Twitch authorization url for the given scopes
+/// `force_verify` makes Twitch show the consent screen again, even if the user already authorized
+/// the app
+pub async fn authorize_url(
+    scopes: &BTreeSet<String>,
+    state: &str,
+    force_verify: bool,
+) -> Result<Url, String> {
+    let config = config().await;
+
+    Url::parse_with_params(
+        "https://id.twitch.tv/oauth2/authorize",
+        &[
+            ("response_type", "code"),
+            ("client_id", config.twitch_oauth().client()),
+            ("redirect_uri", config.twitch_oauth().redirect()),
+            (
+                "scope",
+                &scopes
+                    .iter()
+                    .map(String::as_str)
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            ("state", state),
+            ("force_verify", if force_verify { "true" } else { "false" }),
+        ],
+    )
+    .map_err(|e| format!("Failed to build Twitch authorization url: {}", e))
+}
+
+/// Work out the scopes to ask for when a session authorizes, along with extra scopes it requested
+/// Twitch replaces the granted scopes on every authorization, so everything is asked for at once
+/// Returns the scopes and whether the consent screen has to be shown again
+pub async fn requested_scopes(
+    config: &Config,
+    database: &Database,
+    state_token: &str,
+    extra: &[String],
+) -> Result<(BTreeSet<String>, bool), String> {
+    if let Some(unknown) = extra
+        .iter()
+        .find(|s| matches!(Scope::parse(s.to_string()), Scope::Other(_)))
+    {
+        return Err(format!("Unknown scope: {}", unknown));
+    }
+
+    let granted = database
+        .twitch_key_by_state(state_token)
+        .await
+        .map_err(|e| format!("Database error: {}", e))?
+        .map(|k| parse_scopes(&k.scopes))
+        .unwrap_or_default();
+
+    let mut scopes = parse_scopes(config.twitch_oauth().scopes());
+    scopes.extend(granted);
+    scopes.extend(extra.iter().cloned());
+
+    Ok((scopes, !extra.is_empty()))
+}
 
 pub async fn use_authorization_code(
     http_client: &reqwest::Client,
@@ -69,6 +143,7 @@ pub async fn use_authorization_code(
 
 pub async fn auth_callback(
     Query(params): Query<HashMap<String, String>>,
+    cookies: Option<TypedHeader<headers::Cookie>>,
     Extension(database): Extension<Database>,
     Extension(http_client): Extension<reqwest::Client>,
     State(app_state): State<AppState>,
@@ -80,57 +155,76 @@ pub async fn auth_callback(
             .get("error_description")
             .map(|s| s.as_str())
             .unwrap_or("No description provided");
-        return (
-            axum::http::StatusCode::BAD_REQUEST,
-            format!("Twitch returned an error: {}: {}", error, description),
+        return auth_page(
+            AuthProvider::Twitch,
+            AuthResult::ProviderError {
+                error: error.clone(),
+                description: description.to_string(),
+            },
         );
     }
 
     let code = if let Some(code) = params.get("code") {
         code
     } else {
-        return (
-            axum::http::StatusCode::BAD_REQUEST,
-            "Missing code parameter".to_string(),
+        return auth_page(
+            AuthProvider::Twitch,
+            AuthResult::InvalidRequest("Missing code parameter".to_string()),
         );
     };
     let state = if let Some(state) = params.get("state") {
-        state
+        let csrf_token = cookies
+            .as_ref()
+            .and_then(|c| c.get(oauth::csrf_cookie_name(AuthProvider::Twitch)));
+        match app_state
+            .oauth_states
+            .take(state, AuthProvider::Twitch, csrf_token)
+            .await
+        {
+            Ok(pending) => pending.state_token,
+            Err(e) => {
+                return auth_page(
+                    AuthProvider::Twitch,
+                    AuthResult::InvalidRequest(format!("Invalid state: {}", e)),
+                );
+            }
+        }
     } else {
-        return (
-            axum::http::StatusCode::BAD_REQUEST,
-            "Missing state parameter".to_string(),
+        return auth_page(
+            AuthProvider::Twitch,
+            AuthResult::InvalidRequest("Missing state parameter".to_string()),
         );
     };
     let scopes = if let Some(scopes) = params.get("scope") {
         scopes
     } else {
-        return (
-            axum::http::StatusCode::BAD_REQUEST,
-            "Missing scope parameter".to_string(),
+        return auth_page(
+            AuthProvider::Twitch,
+            AuthResult::InvalidRequest("Missing scope parameter".to_string()),
         );
     };
 
-    // Check that the scopes match what we expect
-    let expected_scopes = config.twitch_oauth().scopes();
-    if scopes != &expected_scopes {
-        return (
-            axum::http::StatusCode::BAD_REQUEST,
-            format!(
-                "Invalid scopes: expected '{}', got '{}'",
-                expected_scopes, scopes
-            ),
+    // Check that at least the scopes we need were granted, extra scopes are fine
+    let granted_scopes = parse_scopes(scopes);
+    let missing_scopes = parse_scopes(config.twitch_oauth().scopes())
+        .difference(&granted_scopes)
+        .cloned()
+        .collect::<Vec<_>>();
+    if !missing_scopes.is_empty() {
+        return auth_page(
+            AuthProvider::Twitch,
+            AuthResult::ScopeMismatch {
+                missing: missing_scopes,
+            },
         );
     }
 
-    let conn = database.connection().unwrap();
-
     let twitch_user = match use_authorization_code(&http_client, code).await {
         Ok(token) => token,
         Err(e) => {
-            return (
-                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Twitch Token Error: {}", e),
+            return auth_page(
+                AuthProvider::Twitch,
+                AuthResult::InternalError(format!("Twitch Token Error: {}", e)),
             );
         }
     };
@@ -143,72 +237,95 @@ pub async fn auth_callback(
             let user = TwitchUser::new(match twitch_user.user_id.as_str().parse() {
                 Ok(id) => id,
                 Err(_) => {
-                    return (
-                        axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                        "Invalid user ID from Twitch".to_string(),
+                    return auth_page(
+                        AuthProvider::Twitch,
+                        AuthResult::InternalError("Invalid user ID from Twitch".to_string()),
                     );
                 }
             });
-            match user.insert(&conn) {
+            match database.insert_twitch_user(&user).await {
                 Ok(_) => {}
                 Err(e) => {
-                    return (
-                        axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                        format!("Database error: {}", e),
+                    return auth_page(
+                        AuthProvider::Twitch,
+                        AuthResult::InternalError(format!("Database error: {}", e)),
                     );
                 }
             }
 
             // Insert the active key
-            ActiveKey::new(state.clone())
-                .insert(&conn)
-                .unwrap_or_else(|e| {
-                    debug!("Failed to insert active key: {}", e);
-                });
-            conn.execute(
-                "INSERT INTO active_twitch_keys (authentication, refresh, user, state, version)
-                 VALUES (?, ?, ?, ?, 1)
-                 ON CONFLICT(user) DO UPDATE SET
-                   authentication = excluded.authentication,
-                   refresh = excluded.refresh,
-                   state = excluded.state,
-                   version = active_twitch_keys.version + 1;",
-                params![
-                    twitch_user.access_token.clone().secret(),
+            if let Err(e) = database
+                .insert_active_key(&ActiveKey::new(state.clone()))
+                .await
+            {
+                return auth_page(
+                    AuthProvider::Twitch,
+                    AuthResult::InternalError(format!("Database error: {}", e)),
+                );
+            }
+            if let Err(e) = database
+                .upsert_twitch_key(&ActiveTwitchKey::new(
+                    twitch_user.access_token.clone().secret().to_string(),
                     twitch_user
                         .refresh_token
                         .clone()
                         .map(|t| t.secret().to_string())
                         .unwrap_or("".into()),
                     user.id,
-                    state,
-                ],
-            )
-            .unwrap();
+                    state.clone(),
+                    1,
+                    twitch_user
+                        .scopes()
+                        .iter()
+                        .map(|s| s.to_string())
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                ))
+                .await
+            {
+                return auth_page(
+                    AuthProvider::Twitch,
+                    AuthResult::InternalError(format!("Database error: {}", e)),
+                );
+            }
+
+            let account = twitch_user.login.to_string();
+
+            // Clients connected to other servers load the new token themselves
+            if let Err(e) = app_state
+                .session_bus
+                .publish(SessionEvent::Authorized {
+                    state_token: state.clone(),
+                    provider: AuthProvider::Twitch,
+                })
+                .await
+            {
+                error!("Failed to publish Twitch authorization: {}", e);
+            }
 
             // Notify any waiting client
             let mut table = app_state.connection_table.lock().await;
-            if let Some(client) = table.get_mut(state) {
+            if let Some(client) = table.get_mut(&state) {
                 client.context.lock().await.twitch = Some(twitch_user);
 
                 if let Err(e) = client.send(client.get_connect_message().await).await {
                     debug!("Failed to send Twitch connect message: {}", e);
-                    return (
-                        axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                        format!("Failed to send Twitch connect message: {}", e),
+                    return auth_page(
+                        AuthProvider::Twitch,
+                        AuthResult::InternalError(format!(
+                            "Failed to send Twitch connect message: {}",
+                            e
+                        )),
                     );
                 }
             }
 
-            (
-                axum::http::StatusCode::OK,
-                "Twitch authentication successful! You can close this tab.".to_string(),
-            )
+            auth_page(AuthProvider::Twitch, AuthResult::Success { account })
         }
         Err(e) => {
-            return (
-                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Twitch Validation Error: {}", e),
+            return auth_page(
+                AuthProvider::Twitch,
+                AuthResult::InternalError(format!("Twitch Validation Error: {}", e)),
             );
         }
     }
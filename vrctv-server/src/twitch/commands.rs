@@ -0,0 +1,306 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use twitch_api::eventsub::channel::chat::message::Badge;
+use vrctv_common::{ChatCommand, ChatRole, TwitchEventSource};
+
+/// The chat commands configured by the client, along with their cooldowns
+#[derive(Debug)]
+pub struct CommandRegistry {
+    prefix: String,
+    commands: Vec<ChatCommand>,
+    /// When each command can next be used by anyone, by command name
+    global_cooldowns: HashMap<String, Instant>,
+    /// When each command can next be used by a chatter, by command name and user id
+    user_cooldowns: HashMap<(String, String), Instant>,
+}
+
+impl Default for CommandRegistry {
+    fn default() -> Self {
+        Self {
+            prefix: "!".into(),
+            commands: Vec::new(),
+            global_cooldowns: HashMap::new(),
+            user_cooldowns: HashMap::new(),
+        }
+    }
+}
+
+impl CommandRegistry {
+    /// Replace the configured commands, this also resets all cooldowns
+    /// An empty prefix would turn every chat message into a command, so it is refused
+    pub fn configure(&mut self, prefix: String, commands: Vec<ChatCommand>) -> Result<(), String> {
+        if prefix.is_empty() || prefix.contains(char::is_whitespace) {
+            return Err(format!(
+                "The command prefix must not be empty or contain spaces, got {:?}",
+                prefix
+            ));
+        }
+
+        self.prefix = prefix;
+        self.commands = commands;
+        self.global_cooldowns.clear();
+        self.user_cooldowns.clear();
+        Ok(())
+    }
+
+    /// Parse a chat message into a command event
+    /// Returns None if the message isn't a known command, the chatter isn't allowed to use it, or
+    /// it is on cooldown
+    pub fn parse(
+        &mut self,
+        text: &str,
+        user_id: &str,
+        role: ChatRole,
+    ) -> Option<TwitchEventSource> {
+        let text = text.trim_start().strip_prefix(self.prefix.as_str())?;
+        if text.starts_with(char::is_whitespace) {
+            return None;
+        }
+
+        let mut args = split_args(text);
+        if args.is_empty() {
+            return None;
+        }
+        let invoked = args.remove(0);
+
+        let command = self.commands.iter().find(|c| {
+            c.name.eq_ignore_ascii_case(&invoked)
+                || c.aliases.iter().any(|a| a.eq_ignore_ascii_case(&invoked))
+        })?;
+
+        if role < command.permission {
+            return None;
+        }
+
+        let name = command.name.to_lowercase();
+        let now = Instant::now();
+
+        // The broadcaster isn't held back by cooldowns
+        if role != ChatRole::Broadcaster {
+            let user_key = (name.clone(), user_id.to_string());
+
+            if self
+                .global_cooldowns
+                .get(&name)
+                .is_some_and(|until| *until > now)
+                || self
+                    .user_cooldowns
+                    .get(&user_key)
+                    .is_some_and(|until| *until > now)
+            {
+                return None;
+            }
+
+            self.user_cooldowns.retain(|_, until| *until > now);
+            if command.user_cooldown_seconds > 0 {
+                self.user_cooldowns.insert(
+                    user_key,
+                    now + Duration::from_secs(command.user_cooldown_seconds.into()),
+                );
+            }
+        }
+
+        if command.global_cooldown_seconds > 0 {
+            self.global_cooldowns.insert(
+                name,
+                now + Duration::from_secs(command.global_cooldown_seconds.into()),
+            );
+        }
+
+        Some(TwitchEventSource::Command {
+            name: command.name.clone(),
+            args,
+            role,
+        })
+    }
+}
+
+/// Work out the highest role of a chatter from their chat badges
+pub fn chat_role(badges: &[Badge]) -> ChatRole {
+    badges
+        .iter()
+        .map(|b| match b.set_id.as_str() {
+            "broadcaster" => ChatRole::Broadcaster,
+            "moderator" | "lead_moderator" => ChatRole::Moderator,
+            "vip" => ChatRole::Vip,
+            "subscriber" | "founder" => ChatRole::Subscriber,
+            _ => ChatRole::Everyone,
+        })
+        .max()
+        .unwrap_or(ChatRole::Everyone)
+}
+
+/// Split command arguments on whitespace, keeping "quoted arguments" together
+pub fn split_args(input: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    let mut has_arg = false;
+
+    for c in input.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                has_arg = true;
+            }
+            c if c.is_whitespace() && !in_quotes => {
+                if has_arg {
+                    args.push(std::mem::take(&mut current));
+                    has_arg = false;
+                }
+            }
+            c => {
+                current.push(c);
+                has_arg = true;
+            }
+        }
+    }
+
+    if has_arg {
+        args.push(current);
+    }
+
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(name: &str, permission: ChatRole) -> ChatCommand {
+        ChatCommand {
+            name: name.into(),
+            aliases: vec![format!("{}-alias", name)],
+            permission,
+            user_cooldown_seconds: 0,
+            global_cooldown_seconds: 0,
+        }
+    }
+
+    fn registry(commands: Vec<ChatCommand>) -> CommandRegistry {
+        let mut registry = CommandRegistry::default();
+        registry.configure("!".into(), commands).unwrap();
+        registry
+    }
+
+    fn parsed(event: Option<TwitchEventSource>) -> Option<(String, Vec<String>)> {
+        match event? {
+            TwitchEventSource::Command { name, args, .. } => Some((name, args)),
+            other => panic!("Expected a command, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn splits_args_on_whitespace() {
+        assert_eq!(split_args("a  b\tc "), vec!["a", "b", "c"]);
+        assert!(split_args("   ").is_empty());
+    }
+
+    #[test]
+    fn keeps_quoted_args_together() {
+        assert_eq!(
+            split_args(r#"say "hello there" now"#),
+            vec!["say", "hello there", "now"]
+        );
+        assert_eq!(split_args(r#"a "" b"#), vec!["a", "", "b"]);
+        // An unclosed quote runs to the end
+        assert_eq!(split_args(r#"a "b c"#), vec!["a", "b c"]);
+    }
+
+    #[test]
+    fn rejects_empty_prefixes() {
+        let mut registry = CommandRegistry::default();
+        assert!(registry.configure("".into(), Vec::new()).is_err());
+        assert!(registry.configure("! ".into(), Vec::new()).is_err());
+        assert!(registry.configure("?".into(), Vec::new()).is_ok());
+    }
+
+    #[test]
+    fn parses_commands_and_aliases() {
+        let mut registry = registry(vec![command("Hug", ChatRole::Everyone)]);
+
+        assert_eq!(
+            parsed(registry.parse("!hug @someone \"a b\"", "1", ChatRole::Everyone)),
+            Some(("Hug".into(), vec!["@someone".into(), "a b".into()]))
+        );
+        assert_eq!(
+            parsed(registry.parse("  !HUG-alias", "1", ChatRole::Everyone)),
+            Some(("Hug".into(), Vec::new()))
+        );
+    }
+
+    #[test]
+    fn ignores_other_messages() {
+        let mut registry = registry(vec![command("hug", ChatRole::Everyone)]);
+
+        assert!(registry.parse("hug", "1", ChatRole::Everyone).is_none());
+        assert!(registry.parse("! hug", "1", ChatRole::Everyone).is_none());
+        assert!(registry.parse("!", "1", ChatRole::Everyone).is_none());
+        assert!(registry.parse("!slap", "1", ChatRole::Everyone).is_none());
+    }
+
+    #[test]
+    fn checks_the_role_of_the_chatter() {
+        let mut registry = registry(vec![command("skip", ChatRole::Vip)]);
+
+        assert!(registry.parse("!skip", "1", ChatRole::Everyone).is_none());
+        assert!(registry.parse("!skip", "1", ChatRole::Subscriber).is_none());
+        assert!(registry.parse("!skip", "1", ChatRole::Vip).is_some());
+        assert!(registry.parse("!skip", "1", ChatRole::Moderator).is_some());
+    }
+
+    #[test]
+    fn takes_the_highest_role_from_badges() {
+        let badges = serde_json::from_value::<Vec<Badge>>(serde_json::json!([
+            { "set_id": "subscriber", "id": "12", "info": "14" },
+            { "set_id": "moderator", "id": "1", "info": "" },
+            { "set_id": "bits", "id": "100", "info": "" },
+        ]))
+        .unwrap();
+
+        assert_eq!(chat_role(&badges), ChatRole::Moderator);
+        assert_eq!(chat_role(&badges[2..]), ChatRole::Everyone);
+        assert_eq!(chat_role(&[]), ChatRole::Everyone);
+    }
+
+    #[test]
+    fn holds_chatters_back_by_the_user_cooldown() {
+        let mut registry = registry(vec![ChatCommand {
+            user_cooldown_seconds: 60,
+            ..command("hug", ChatRole::Everyone)
+        }]);
+
+        assert!(registry.parse("!hug", "1", ChatRole::Everyone).is_some());
+        assert!(registry.parse("!hug", "1", ChatRole::Everyone).is_none());
+        assert!(registry.parse("!hug", "2", ChatRole::Everyone).is_some());
+    }
+
+    #[test]
+    fn holds_everyone_back_by_the_global_cooldown() {
+        let mut registry = registry(vec![ChatCommand {
+            global_cooldown_seconds: 60,
+            ..command("hug", ChatRole::Everyone)
+        }]);
+
+        assert!(registry.parse("!hug", "1", ChatRole::Everyone).is_some());
+        assert!(registry.parse("!hug", "2", ChatRole::Moderator).is_none());
+        // The broadcaster isn't held back
+        assert!(registry.parse("!hug", "3", ChatRole::Broadcaster).is_some());
+    }
+
+    #[test]
+    fn resets_cooldowns_when_configured() {
+        let hug = ChatCommand {
+            global_cooldown_seconds: 60,
+            ..command("hug", ChatRole::Everyone)
+        };
+        let mut registry = registry(vec![hug.clone()]);
+
+        assert!(registry.parse("!hug", "1", ChatRole::Everyone).is_some());
+        registry.configure("!".into(), vec![hug]).unwrap();
+        assert!(registry.parse("!hug", "1", ChatRole::Everyone).is_some());
+    }
+}
@@ -1,3 +1,5 @@
+use std::convert::Infallible;
+
 use axum::extract::ws::Message;
 use log::{error, info};
 use reqwest::Error;
@@ -6,23 +8,36 @@ use twitch_api::{
     HelixClient,
     eventsub::{self, Event, Payload, channel::chat::Fragment},
     helix::{
-        ClientRequestError,
+        self, ClientRequestError, EmptyBody, HelixRequestDeleteError, HelixRequestGetError,
+        HelixRequestPatchError, HelixRequestPostError, HelixRequestPutError,
+        chat::{
+            SendAShoutoutRequest, SendChatAnnouncementBody, SendChatAnnouncementRequest,
+            SendChatMessageResponse, UpdateChatSettingsBody, UpdateChatSettingsRequest,
+        },
         points::{
             CreateCustomRewardBody, CreateCustomRewardRequest, CustomRewardRedemptionStatus,
             DeleteCustomRewardRequest, GetCustomRewardRequest, UpdateCustomRewardBody,
             UpdateCustomRewardRequest, UpdateRedemptionStatusBody, UpdateRedemptionStatusRequest,
         },
     },
-    twitch_oauth2::{ClientId, ClientSecret, UserToken},
+    twitch_oauth2::{ClientId, ClientSecret, Scope, TwitchToken, UserToken},
 };
 use vrctv_common::{
-    CustomRewardResponse, Notify, ServerMessage, TwitchEvent, TwitchEventSource,
-    TwitchTriggerRequest,
+    AnnouncementColor, ChatBadge, ChatFragment, ChatReply, ChatRole, CustomRewardResponse, Notify,
+    RedemptionResponse, ServerMessage, TwitchEvent, TwitchEventSource, TwitchTriggerRequest,
+    template,
 };
 
 use crate::{
+    bus::SessionEvent,
     config::config,
+    metrics,
     server::{ClientConnection, send_all_message, send_error, send_message, send_task_response},
+    twitch::{
+        commands::chat_role,
+        redemptions::{get_unfulfilled_redemptions, update_redemption_statuses},
+        rewards::RewardChange,
+    },
 };
 
 /// Handle Twitch token errors, such as refreshing the token if it has expired
@@ -33,6 +48,7 @@ pub async fn handle_token_error(
     token: &mut UserToken,
 ) -> Result<bool, String> {
     let config = config().await;
+    metrics::record_helix_error(error_status(error));
 
     if let ClientRequestError::RequestError(e) = &error {
         if e.status() == Some(reqwest::StatusCode::UNAUTHORIZED) {
@@ -49,6 +65,7 @@ pub async fn handle_token_error(
                 ClientSecret::new(client_secret),
             )
             .await;
+            metrics::record_token_refresh("twitch", new_token.is_ok());
             *token = new_token.map_err(|e| {
                 error!("Failed to refresh Twitch token: {}", e);
                 "Failed to refresh Twitch token".to_string()
@@ -63,6 +80,112 @@ pub async fn handle_token_error(
     }
 }
 
+/// The HTTP status of a failed Helix request, if it got a response
+fn error_status(error: &ClientRequestError<Error>) -> Option<u16> {
+    match error {
+        ClientRequestError::RequestError(e) => e.status().map(|s| s.as_u16()),
+        ClientRequestError::HelixRequestGetError(HelixRequestGetError::Error {
+            status, ..
+        }) => Some(status.as_u16()),
+        ClientRequestError::HelixRequestPutError(HelixRequestPutError::Error {
+            status, ..
+        }) => Some(status.as_u16()),
+        ClientRequestError::HelixRequestPostError(HelixRequestPostError::Error {
+            status, ..
+        }) => Some(status.as_u16()),
+        ClientRequestError::HelixRequestPatchError(HelixRequestPatchError::Error {
+            status,
+            ..
+        }) => Some(status.as_u16()),
+        ClientRequestError::HelixRequestDeleteError(HelixRequestDeleteError::Error {
+            status,
+            ..
+        }) => Some(status.as_u16()),
+        _ => None,
+    }
+}
+
+/// A short name for a trigger request, used as a metrics label
+pub fn trigger_name(request: &TwitchTriggerRequest) -> &'static str {
+    match request {
+        TwitchTriggerRequest::ChannelPointsFulfill { .. } => "ChannelPointsFulfill",
+        TwitchTriggerRequest::ChannelPointsCancel { .. } => "ChannelPointsCancel",
+        TwitchTriggerRequest::ChannelPointsFulfillMany { .. } => "ChannelPointsFulfillMany",
+        TwitchTriggerRequest::ChannelPointsCancelMany { .. } => "ChannelPointsCancelMany",
+        TwitchTriggerRequest::GetUnfulfilledRedemptions { .. } => "GetUnfulfilledRedemptions",
+        TwitchTriggerRequest::UpdateCustomRewards { .. } => "UpdateCustomRewards",
+        TwitchTriggerRequest::GetCustomRewards { .. } => "GetCustomRewards",
+        TwitchTriggerRequest::SetRewardPaused { .. } => "SetRewardPaused",
+        TwitchTriggerRequest::SetRewardEnabled { .. } => "SetRewardEnabled",
+        TwitchTriggerRequest::SetRewardCost { .. } => "SetRewardCost",
+        TwitchTriggerRequest::AdjustRewardCost { .. } => "AdjustRewardCost",
+        TwitchTriggerRequest::SendChatMessage { .. } => "SendChatMessage",
+        TwitchTriggerRequest::ReplyToMessage { .. } => "ReplyToMessage",
+        TwitchTriggerRequest::SendAnnouncement { .. } => "SendAnnouncement",
+        TwitchTriggerRequest::Shoutout { .. } => "Shoutout",
+        TwitchTriggerRequest::SetChatCommands { .. } => "SetChatCommands",
+        TwitchTriggerRequest::TimeoutUser { .. } => "TimeoutUser",
+        TwitchTriggerRequest::BanUser { .. } => "BanUser",
+        TwitchTriggerRequest::UnbanUser { .. } => "UnbanUser",
+        TwitchTriggerRequest::DeleteChatMessage { .. } => "DeleteChatMessage",
+        TwitchTriggerRequest::SetEmoteOnly { .. } => "SetEmoteOnly",
+        TwitchTriggerRequest::SetSlowMode { .. } => "SetSlowMode",
+    }
+}
+
+/// The label to time a trigger request under, None if it doesn't call Helix itself
+/// Reward changes are only queued, chat commands stay on the server and the bulk redemption
+/// updates time each of their requests
+pub fn trigger_latency_name(request: &TwitchTriggerRequest) -> Option<&'static str> {
+    match request {
+        TwitchTriggerRequest::SetRewardPaused { .. }
+        | TwitchTriggerRequest::SetRewardEnabled { .. }
+        | TwitchTriggerRequest::SetRewardCost { .. }
+        | TwitchTriggerRequest::AdjustRewardCost { .. }
+        | TwitchTriggerRequest::SetChatCommands { .. }
+        | TwitchTriggerRequest::ChannelPointsFulfillMany { .. }
+        | TwitchTriggerRequest::ChannelPointsCancelMany { .. } => None,
+        _ => Some(trigger_name(request)),
+    }
+}
+
+/// The request id of a trigger request, to answer it without handling it
+pub fn trigger_request_id(request: &TwitchTriggerRequest) -> i32 {
+    match request {
+        TwitchTriggerRequest::ChannelPointsFulfill { request_id, .. }
+        | TwitchTriggerRequest::ChannelPointsCancel { request_id, .. }
+        | TwitchTriggerRequest::ChannelPointsFulfillMany { request_id, .. }
+        | TwitchTriggerRequest::ChannelPointsCancelMany { request_id, .. }
+        | TwitchTriggerRequest::GetUnfulfilledRedemptions { request_id, .. }
+        | TwitchTriggerRequest::UpdateCustomRewards { request_id, .. }
+        | TwitchTriggerRequest::GetCustomRewards { request_id, .. }
+        | TwitchTriggerRequest::SetRewardPaused { request_id, .. }
+        | TwitchTriggerRequest::SetRewardEnabled { request_id, .. }
+        | TwitchTriggerRequest::SetRewardCost { request_id, .. }
+        | TwitchTriggerRequest::AdjustRewardCost { request_id, .. }
+        | TwitchTriggerRequest::SendChatMessage { request_id, .. }
+        | TwitchTriggerRequest::ReplyToMessage { request_id, .. }
+        | TwitchTriggerRequest::SendAnnouncement { request_id, .. }
+        | TwitchTriggerRequest::Shoutout { request_id, .. }
+        | TwitchTriggerRequest::SetChatCommands { request_id, .. }
+        | TwitchTriggerRequest::TimeoutUser { request_id, .. }
+        | TwitchTriggerRequest::BanUser { request_id, .. }
+        | TwitchTriggerRequest::UnbanUser { request_id, .. }
+        | TwitchTriggerRequest::DeleteChatMessage { request_id, .. }
+        | TwitchTriggerRequest::SetEmoteOnly { request_id, .. }
+        | TwitchTriggerRequest::SetSlowMode { request_id, .. } => *request_id,
+    }
+}
+
+/// Whether a trigger request only reads from Twitch, the only kind viewer devices may send
+pub fn trigger_is_read_only(request: &TwitchTriggerRequest) -> bool {
+    matches!(
+        request,
+        TwitchTriggerRequest::GetUnfulfilledRedemptions { .. }
+            | TwitchTriggerRequest::GetCustomRewards { .. }
+    )
+}
+
 /// Handle Twitch trigger requests
 /// Returns Ok(true) if the token was refreshed and the caller should retry, Ok(false) otherwise
 pub async fn handle_twitch_trigger(
@@ -70,6 +193,7 @@ pub async fn handle_twitch_trigger(
     twitch: &mut UserToken,
     trigger_request: TwitchTriggerRequest,
     tx: &Sender<Message>,
+    connection: &ClientConnection,
 ) -> Result<bool, String> {
     info!("Handling Twitch trigger request: {:?}", trigger_request);
 
@@ -129,6 +253,106 @@ pub async fn handle_twitch_trigger(
                 }
             }
         }
+        TwitchTriggerRequest::ChannelPointsFulfillMany {
+            request_id,
+            reward_id,
+            redemption_ids,
+        } => {
+            let failures = update_redemption_statuses(
+                http_client,
+                twitch,
+                &reward_id,
+                &redemption_ids,
+                CustomRewardRedemptionStatus::Fulfilled,
+            )
+            .await?;
+
+            if failures.is_empty() {
+                let _ = send_task_response(true, None, tx, request_id).await;
+            } else {
+                let _ = send_error(
+                    describe_failures("fulfill", redemption_ids.len(), &failures),
+                    "twitch_fullfill_redemption",
+                    tx,
+                    request_id,
+                )
+                .await;
+            }
+        }
+        TwitchTriggerRequest::ChannelPointsCancelMany {
+            request_id,
+            reward_id,
+            redemption_ids,
+        } => {
+            let failures = update_redemption_statuses(
+                http_client,
+                twitch,
+                &reward_id,
+                &redemption_ids,
+                CustomRewardRedemptionStatus::Canceled,
+            )
+            .await?;
+
+            if failures.is_empty() {
+                let _ = send_task_response(true, None, tx, request_id).await;
+            } else {
+                let _ = send_error(
+                    describe_failures("cancel", redemption_ids.len(), &failures),
+                    "twitch_cancel_redemption",
+                    tx,
+                    request_id,
+                )
+                .await;
+            }
+        }
+        TwitchTriggerRequest::GetUnfulfilledRedemptions {
+            request_id,
+            reward_id,
+            after,
+            first,
+        } => {
+            match get_unfulfilled_redemptions(
+                http_client,
+                twitch,
+                &reward_id,
+                after,
+                first.map(|f| f as usize),
+            )
+            .await
+            {
+                Ok(d) => {
+                    info!("Successfully fetched unfulfilled redemptions: {:?}", d);
+
+                    let msg = ServerMessage::Redemptions {
+                        reward_id,
+                        redemptions: d
+                            .data
+                            .iter()
+                            .map(|r| RedemptionResponse {
+                                id: r.id.to_string(),
+                                reward_id: r.reward.id.to_string(),
+                                user_id: r.user_id.to_string(),
+                                user_name: r.user_name.to_string(),
+                                user_input: r.user_input.clone(),
+                                redeemed_at: r.redeemed_at.to_string(),
+                            })
+                            .collect(),
+                        cursor: d.pagination.map(|c| c.take()),
+                    };
+
+                    let _ = send_message(msg, tx).await;
+                    let _ = send_task_response(true, None, tx, request_id).await;
+                }
+                Err(e) => {
+                    if handle_token_error(http_client, &e, twitch).await? {
+                        return Ok(true);
+                    } else {
+                        error!("Failed to fetch unfulfilled redemptions: {}", e);
+                        let _ = send_error(e, "twitch_get_redemptions", tx, request_id).await;
+                    }
+                }
+            }
+        }
         TwitchTriggerRequest::UpdateCustomRewards {
             request_id,
             rewards,
@@ -302,11 +526,562 @@ pub async fn handle_twitch_trigger(
                 }
             }
         }
+        TwitchTriggerRequest::SetRewardPaused {
+            request_id,
+            reward_id,
+            is_paused,
+        } => {
+            connection.reward_updates.lock().await.push(
+                reward_id,
+                RewardChange::Paused(is_paused),
+                request_id,
+                tx.clone(),
+            );
+        }
+        TwitchTriggerRequest::SetRewardEnabled {
+            request_id,
+            reward_id,
+            is_enabled,
+        } => {
+            connection.reward_updates.lock().await.push(
+                reward_id,
+                RewardChange::Enabled(is_enabled),
+                request_id,
+                tx.clone(),
+            );
+        }
+        TwitchTriggerRequest::SetRewardCost {
+            request_id,
+            reward_id,
+            cost,
+        } => {
+            connection.reward_updates.lock().await.push(
+                reward_id,
+                RewardChange::Cost(cost),
+                request_id,
+                tx.clone(),
+            );
+        }
+        TwitchTriggerRequest::AdjustRewardCost {
+            request_id,
+            reward_id,
+            delta,
+        } => {
+            connection.reward_updates.lock().await.push(
+                reward_id,
+                RewardChange::CostDelta(delta),
+                request_id,
+                tx.clone(),
+            );
+        }
+        TwitchTriggerRequest::SendChatMessage {
+            request_id,
+            message,
+            variables,
+        } => {
+            let message = template::render(&message, &variables);
+
+            match client
+                .send_chat_message(&twitch.user_id, &twitch.user_id, message.as_str(), twitch)
+                .await
+            {
+                Ok(d) => report_chat_message(d, tx, request_id).await,
+                Err(e) => {
+                    if handle_token_error(http_client, &e, twitch).await? {
+                        return Ok(true);
+                    } else {
+                        error!("Failed to send chat message: {}", e);
+                        let _ = send_error(e, "twitch_send_chat_message", tx, request_id).await;
+                    }
+                }
+            }
+        }
+        TwitchTriggerRequest::ReplyToMessage {
+            request_id,
+            message_id,
+            message,
+            variables,
+        } => {
+            let message = template::render(&message, &variables);
+
+            match client
+                .send_chat_message_reply(
+                    &twitch.user_id,
+                    &twitch.user_id,
+                    message_id.as_str(),
+                    message.as_str(),
+                    twitch,
+                )
+                .await
+            {
+                Ok(d) => report_chat_message(d, tx, request_id).await,
+                Err(e) => {
+                    if handle_token_error(http_client, &e, twitch).await? {
+                        return Ok(true);
+                    } else {
+                        error!("Failed to reply to chat message: {}", e);
+                        let _ = send_error(e, "twitch_send_chat_message", tx, request_id).await;
+                    }
+                }
+            }
+        }
+        TwitchTriggerRequest::SendAnnouncement {
+            request_id,
+            message,
+            color,
+            variables,
+        } => {
+            let message = template::render(&message, &variables);
+            let request = SendChatAnnouncementRequest::new(&twitch.user_id, &twitch.user_id);
+            let body = SendChatAnnouncementBody::new(
+                message,
+                announcement_color(color.unwrap_or(AnnouncementColor::Primary)),
+            )
+            .map_err(|e: Infallible| e.to_string())?;
+
+            match client.req_post(request, body, twitch).await {
+                Ok(_) => {
+                    info!("Successfully sent announcement");
+                    let _ = send_task_response(true, None, tx, request_id).await;
+                }
+                Err(e) => {
+                    if handle_token_error(http_client, &e, twitch).await? {
+                        return Ok(true);
+                    } else {
+                        error!("Failed to send announcement: {}", e);
+                        let _ = send_error(e, "twitch_send_announcement", tx, request_id).await;
+                    }
+                }
+            }
+        }
+        TwitchTriggerRequest::Shoutout {
+            request_id,
+            to_user,
+        } => {
+            let user = match client
+                .get_user_from_login(to_user.trim_start_matches('@'), twitch)
+                .await
+            {
+                Ok(Some(user)) => user,
+                Ok(None) => {
+                    let _ = send_error(
+                        format!("No Twitch user named {}", to_user),
+                        "twitch_shoutout",
+                        tx,
+                        request_id,
+                    )
+                    .await;
+                    return Ok(false);
+                }
+                Err(e) => {
+                    if handle_token_error(http_client, &e, twitch).await? {
+                        return Ok(true);
+                    } else {
+                        error!("Failed to look up shoutout target: {}", e);
+                        let _ = send_error(e, "twitch_shoutout", tx, request_id).await;
+                        return Ok(false);
+                    }
+                }
+            };
+
+            let request = SendAShoutoutRequest::new(&twitch.user_id, &user.id, &twitch.user_id);
+
+            match client.req_post(request, EmptyBody, twitch).await {
+                Ok(_) => {
+                    info!("Successfully sent shoutout to {}", user.login);
+                    let _ = send_task_response(true, None, tx, request_id).await;
+                }
+                Err(e) => {
+                    if handle_token_error(http_client, &e, twitch).await? {
+                        return Ok(true);
+                    } else {
+                        error!("Failed to send shoutout: {}", e);
+                        let _ = send_error(e, "twitch_shoutout", tx, request_id).await;
+                    }
+                }
+            }
+        }
+        TwitchTriggerRequest::SetChatCommands {
+            request_id,
+            prefix,
+            commands,
+        } => {
+            info!("Registering {} chat commands", commands.len());
+            let configured = connection
+                .commands
+                .lock()
+                .await
+                .configure(prefix.clone(), commands.clone());
+            match configured {
+                Ok(()) => {
+                    // The chat may be read by another server holding the session
+                    if let Err(e) = connection
+                        .session_bus
+                        .publish(SessionEvent::ChatCommands {
+                            state_token: connection.state_token.clone(),
+                            prefix,
+                            commands,
+                        })
+                        .await
+                    {
+                        error!("Failed to publish chat commands: {}", e);
+                    }
+                    let _ = send_task_response(true, None, tx, request_id).await;
+                }
+                Err(e) => {
+                    let _ = send_error(e, "twitch_chat_commands", tx, request_id).await;
+                }
+            }
+        }
+        TwitchTriggerRequest::TimeoutUser {
+            request_id,
+            user_login,
+            duration_seconds,
+            reason,
+        } => {
+            if !TIMEOUT_SECONDS.contains(&duration_seconds) {
+                let e = format!(
+                    "Timeouts must last between {} and {} seconds, got {}",
+                    TIMEOUT_SECONDS.start(),
+                    TIMEOUT_SECONDS.end(),
+                    duration_seconds
+                );
+                let _ = send_error(e, "twitch_moderation", tx, request_id).await;
+                return Ok(false);
+            }
+
+            return moderate_user(
+                http_client,
+                twitch,
+                tx,
+                request_id,
+                &user_login,
+                UserModeration::Ban {
+                    reason: reason.unwrap_or_default(),
+                    duration: Some(duration_seconds),
+                },
+            )
+            .await;
+        }
+        TwitchTriggerRequest::BanUser {
+            request_id,
+            user_login,
+            reason,
+        } => {
+            return moderate_user(
+                http_client,
+                twitch,
+                tx,
+                request_id,
+                &user_login,
+                UserModeration::Ban {
+                    reason: reason.unwrap_or_default(),
+                    duration: None,
+                },
+            )
+            .await;
+        }
+        TwitchTriggerRequest::UnbanUser {
+            request_id,
+            user_login,
+        } => {
+            return moderate_user(
+                http_client,
+                twitch,
+                tx,
+                request_id,
+                &user_login,
+                UserModeration::Unban,
+            )
+            .await;
+        }
+        TwitchTriggerRequest::DeleteChatMessage {
+            request_id,
+            message_id,
+        } => {
+            if let Err(e) = require_scope(twitch, Scope::ModeratorManageChatMessages) {
+                let _ = send_error(e, "twitch_missing_scope", tx, request_id).await;
+                return Ok(false);
+            }
+
+            match client
+                .delete_chat_message(
+                    &twitch.user_id,
+                    &twitch.user_id,
+                    message_id.as_str(),
+                    twitch,
+                )
+                .await
+            {
+                Ok(_) => {
+                    info!("Successfully deleted chat message {}", message_id);
+                    let _ = send_task_response(true, None, tx, request_id).await;
+                }
+                Err(e) => {
+                    if handle_token_error(http_client, &e, twitch).await? {
+                        return Ok(true);
+                    } else {
+                        error!("Failed to delete chat message: {}", e);
+                        let _ = send_error(e, "twitch_delete_chat_message", tx, request_id).await;
+                    }
+                }
+            }
+        }
+        TwitchTriggerRequest::SetEmoteOnly {
+            request_id,
+            enabled,
+        } => {
+            let mut body = UpdateChatSettingsBody::default();
+            body.emote_mode = Some(enabled);
+
+            return update_chat_settings(http_client, twitch, tx, request_id, body).await;
+        }
+        TwitchTriggerRequest::SetSlowMode {
+            request_id,
+            wait_seconds,
+        } => {
+            if let Some(wait) = wait_seconds.filter(|w| !SLOW_MODE_SECONDS.contains(w)) {
+                let e = format!(
+                    "Slow mode wait time must be between {} and {} seconds, got {}",
+                    SLOW_MODE_SECONDS.start(),
+                    SLOW_MODE_SECONDS.end(),
+                    wait
+                );
+                let _ = send_error(e, "twitch_update_chat_settings", tx, request_id).await;
+                return Ok(false);
+            }
+
+            let mut body = UpdateChatSettingsBody::default();
+            body.slow_mode = Some(wait_seconds.is_some());
+            body.slow_mode_wait_time = wait_seconds.map(|w| w as u64);
+
+            return update_chat_settings(http_client, twitch, tx, request_id, body).await;
+        }
+    }
+
+    Ok(false)
+}
+
+/// Twitch timeouts last from 1 second to 2 weeks
+const TIMEOUT_SECONDS: std::ops::RangeInclusive<u32> = 1..=1_209_600;
+/// The slow mode wait times Twitch accepts
+const SLOW_MODE_SECONDS: std::ops::RangeInclusive<u32> = 3..=120;
+
+/// Check that the user granted a scope, so we can give a clearer error than Twitch does
+fn require_scope(twitch: &UserToken, scope: Scope) -> Result<(), String> {
+    if twitch.scopes().contains(&scope) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Missing Twitch permission '{}', reconnect your Twitch account to grant it",
+            scope
+        ))
+    }
+}
+
+enum UserModeration {
+    /// A ban, or a timeout if there's a duration
+    Ban {
+        reason: String,
+        duration: Option<u32>,
+    },
+    Unban,
+}
+
+/// Ban, time out or unban a user by their login name
+/// Returns Ok(true) if the token was refreshed and the caller should retry, Ok(false) otherwise
+async fn moderate_user(
+    http_client: &reqwest::Client,
+    twitch: &mut UserToken,
+    tx: &Sender<Message>,
+    request_id: i32,
+    user_login: &str,
+    action: UserModeration,
+) -> Result<bool, String> {
+    if let Err(e) = require_scope(twitch, Scope::ModeratorManageBannedUsers) {
+        let _ = send_error(e, "twitch_missing_scope", tx, request_id).await;
+        return Ok(false);
+    }
+
+    let client: HelixClient<reqwest::Client> = HelixClient::with_client(http_client.clone());
+    let user = match client
+        .get_user_from_login(user_login.trim_start_matches('@'), &*twitch)
+        .await
+    {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            let _ = send_error(
+                format!("No Twitch user named {}", user_login),
+                "twitch_moderation",
+                tx,
+                request_id,
+            )
+            .await;
+            return Ok(false);
+        }
+        Err(e) => {
+            if handle_token_error(http_client, &e, twitch).await? {
+                return Ok(true);
+            }
+            error!("Failed to look up user to moderate: {}", e);
+            let _ = send_error(e, "twitch_moderation", tx, request_id).await;
+            return Ok(false);
+        }
+    };
+
+    let result = match &action {
+        UserModeration::Ban { reason, duration } => client
+            .ban_user(
+                &user.id,
+                reason.as_str(),
+                *duration,
+                &twitch.user_id,
+                &twitch.user_id,
+                &*twitch,
+            )
+            .await
+            .map(|_| ()),
+        UserModeration::Unban => client
+            .unban_user(&user.id, &twitch.user_id, &twitch.user_id, &*twitch)
+            .await
+            .map(|_| ()),
+    };
+
+    match result {
+        Ok(()) => {
+            info!("Successfully moderated user {}", user.login);
+            let _ = send_task_response(true, None, tx, request_id).await;
+        }
+        Err(e) => {
+            if handle_token_error(http_client, &e, twitch).await? {
+                return Ok(true);
+            }
+            error!("Failed to moderate user {}: {}", user.login, e);
+            let _ = send_error(e, "twitch_moderation", tx, request_id).await;
+        }
     }
 
     Ok(false)
 }
 
+/// Change the chat settings of the broadcaster's channel
+/// Returns Ok(true) if the token was refreshed and the caller should retry, Ok(false) otherwise
+async fn update_chat_settings(
+    http_client: &reqwest::Client,
+    twitch: &mut UserToken,
+    tx: &Sender<Message>,
+    request_id: i32,
+    body: UpdateChatSettingsBody,
+) -> Result<bool, String> {
+    if let Err(e) = require_scope(twitch, Scope::ModeratorManageChatSettings) {
+        let _ = send_error(e, "twitch_missing_scope", tx, request_id).await;
+        return Ok(false);
+    }
+
+    let client: HelixClient<reqwest::Client> = HelixClient::with_client(http_client.clone());
+    let request = UpdateChatSettingsRequest::new(&twitch.user_id, &twitch.user_id);
+
+    match client.req_patch(request, body, &*twitch).await {
+        Ok(_) => {
+            info!("Successfully updated chat settings");
+            let _ = send_task_response(true, None, tx, request_id).await;
+        }
+        Err(e) => {
+            if handle_token_error(http_client, &e, twitch).await? {
+                return Ok(true);
+            }
+            error!("Failed to update chat settings: {}", e);
+            let _ = send_error(e, "twitch_update_chat_settings", tx, request_id).await;
+        }
+    }
+
+    Ok(false)
+}
+
+/// Report a sent chat message back to the client, Twitch can accept the request but still drop
+/// the message (e.g. because of AutoMod or slow mode)
+async fn report_chat_message(
+    response: SendChatMessageResponse,
+    tx: &Sender<Message>,
+    request_id: i32,
+) {
+    if response.is_sent {
+        info!("Successfully sent chat message: {:?}", response.message_id);
+        let _ = send_task_response(
+            true,
+            response.message_id.map(|id| id.take()),
+            tx,
+            request_id,
+        )
+        .await;
+    } else {
+        let reason = response
+            .drop_reason
+            .map(|r| r.message)
+            .unwrap_or_else(|| "No reason given".into());
+        error!("Chat message was dropped: {}", reason);
+        let _ = send_error(
+            format!("Chat message was dropped: {}", reason),
+            "twitch_send_chat_message",
+            tx,
+            request_id,
+        )
+        .await;
+    }
+}
+
+fn announcement_color(color: AnnouncementColor) -> helix::chat::AnnouncementColor {
+    match color {
+        AnnouncementColor::Blue => helix::chat::AnnouncementColor::Blue,
+        AnnouncementColor::Green => helix::chat::AnnouncementColor::Green,
+        AnnouncementColor::Orange => helix::chat::AnnouncementColor::Orange,
+        AnnouncementColor::Purple => helix::chat::AnnouncementColor::Purple,
+        AnnouncementColor::Primary => helix::chat::AnnouncementColor::Primary,
+    }
+}
+
+fn chat_fragment(fragment: &Fragment) -> ChatFragment {
+    match fragment {
+        Fragment::Emote { text, emote } => ChatFragment::Emote {
+            text: text.clone(),
+            id: emote.id.to_string(),
+            emote_set_id: emote.emote_set_id.to_string(),
+            owner_id: emote.owner_id.to_string(),
+            format: emote.format.iter().map(|f| f.to_string()).collect(),
+        },
+        Fragment::Cheermote { text, cheermote } => ChatFragment::Cheermote {
+            text: text.clone(),
+            prefix: cheermote.prefix.clone(),
+            bits: cheermote.bits,
+            tier: cheermote.tier,
+        },
+        Fragment::Mention { text, mention } => ChatFragment::Mention {
+            text: text.clone(),
+            user_id: mention.user_id.to_string(),
+            user_name: mention.user_name.to_string(),
+            user_login: mention.user_login.to_string(),
+        },
+        other => ChatFragment::Text {
+            text: other.text().to_string(),
+        },
+    }
+}
+
+/// Build an error message for a bulk redemption update that partially failed
+fn describe_failures(action: &str, total: usize, failures: &[(String, String)]) -> String {
+    format!(
+        "Failed to {} {} of {} redemptions: {}",
+        action,
+        failures.len(),
+        total,
+        failures
+            .iter()
+            .map(|(id, reason)| format!("{} ({})", id, reason))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
 pub async fn handle_event(event: &Event, conn: &ClientConnection) -> Result<(), String> {
     match event {
         Event::ChannelPointsCustomRewardRedemptionAddV1(Payload {
@@ -327,6 +1102,8 @@ pub async fn handle_event(event: &Event, conn: &ClientConnection) -> Result<(),
                     event: TwitchEventSource::ChannelPoints {
                         reward_id: message.reward.id.to_string(),
                         reward_name: message.reward.title.to_string(),
+                        redemption_id: message.id.to_string(),
+                        user_input: message.user_input.clone(),
                     },
                 }),
                 &conn,
@@ -411,6 +1188,12 @@ pub async fn handle_event(event: &Event, conn: &ClientConnection) -> Result<(),
                 }
             };
 
+            let role = if message.chatter_user_id == message.broadcaster_user_id {
+                ChatRole::Broadcaster
+            } else {
+                chat_role(&message.badges)
+            };
+
             send_all_message(
                 ServerMessage::TwitchEvent(TwitchEvent {
                     user_id: message.chatter_user_id.to_string(),
@@ -418,11 +1201,61 @@ pub async fn handle_event(event: &Event, conn: &ClientConnection) -> Result<(),
                     event: TwitchEventSource::Message {
                         sender: message.chatter_user_name.to_string(),
                         message: message.message.text.to_string(),
+                        message_id: message.message_id.to_string(),
+                        role,
+                        badges: message
+                            .badges
+                            .iter()
+                            .map(|b| ChatBadge {
+                                set_id: b.set_id.to_string(),
+                                id: b.id.to_string(),
+                                info: b.info.clone(),
+                            })
+                            .collect(),
+                        color: Some(message.color.to_string()).filter(|c| !c.is_empty()),
+                        fragments: message
+                            .message
+                            .fragments
+                            .iter()
+                            .map(chat_fragment)
+                            .collect(),
+                        cheer: message.cheer.as_ref().map(|c| c.bits as u32),
+                        reply: message.reply.as_ref().map(|r| {
+                            Box::new(ChatReply {
+                                parent_message_id: r.parent_message_id.to_string(),
+                                parent_message_body: r.parent_message_body.clone(),
+                                parent_user_id: r.parent_user_id.to_string(),
+                                parent_user_name: r.parent_user_name.to_string(),
+                                parent_user_login: r.parent_user_login.to_string(),
+                                thread_message_id: r.thread_message_id.to_string(),
+                            })
+                        }),
                     },
                 }),
                 conn,
             )
-            .await
+            .await?;
+
+            let command = conn.commands.lock().await.parse(
+                &message.message.text,
+                message.chatter_user_id.as_str(),
+                role,
+            );
+
+            match command {
+                Some(command) => {
+                    send_all_message(
+                        ServerMessage::TwitchEvent(TwitchEvent {
+                            user_id: message.chatter_user_id.to_string(),
+                            user_name: message.chatter_user_name.to_string(),
+                            event: command,
+                        }),
+                        conn,
+                    )
+                    .await
+                }
+                None => Ok(()),
+            }
         }
         _ => Ok(()),
     }
@@ -0,0 +1,304 @@
+use std::{borrow::Cow, collections::HashSet, time::Instant};
+
+use axum::http;
+use log::{error, info};
+use reqwest::Error;
+use serde::{Deserialize, Serialize};
+use twitch_api::{
+    HelixClient,
+    helix::{
+        ClientRequestError, Cursor, HelixRequestPatchError, Request, RequestPatch, Response,
+        points::{
+            CustomRewardRedemption, CustomRewardRedemptionStatus, GetCustomRewardRedemptionRequest,
+            GetCustomRewardRequest, UpdateRedemptionStatusBody,
+            get_custom_reward_redemption::GetCustomRewardRedemptionSortOrder,
+        },
+    },
+    twitch_oauth2::{self, UserToken},
+};
+
+use crate::{metrics, twitch::events::handle_token_error};
+
+/// Twitch returns at most 50 redemptions per page
+const MAX_PAGE_SIZE: usize = 50;
+
+/// Fetch a page of unfulfilled redemptions for a reward, oldest first
+pub async fn get_unfulfilled_redemptions(
+    http_client: &reqwest::Client,
+    twitch: &UserToken,
+    reward_id: &str,
+    after: Option<String>,
+    first: Option<usize>,
+) -> Result<
+    Response<GetCustomRewardRedemptionRequest<'static>, Vec<CustomRewardRedemption>>,
+    ClientRequestError<Error>,
+> {
+    let client: HelixClient<reqwest::Client> = HelixClient::with_client(http_client.clone());
+
+    let mut request = GetCustomRewardRedemptionRequest::broadcaster_id(twitch.user_id.clone())
+        .reward_id(reward_id.to_string())
+        .status(CustomRewardRedemptionStatus::Unfulfilled)
+        .sort(GetCustomRewardRedemptionSortOrder::Oldest);
+    request.after = after.map(|c| Cow::Owned(Cursor::from(c)));
+    request.first = Some(first.unwrap_or(MAX_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE));
+
+    client.req_get(request, twitch).await
+}
+
+/// Helix updates at most 50 redemptions per call
+const MAX_UPDATE_IDS: usize = 50;
+
+/// `UpdateRedemptionStatusRequest` with several ids, twitch_api only sends one per request
+#[derive(Serialize, Clone, Debug, PartialEq)]
+struct UpdateRedemptionStatusesRequest {
+    broadcaster_id: String,
+    reward_id: String,
+    id: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct UpdatedRedemptions {
+    data: Vec<CustomRewardRedemption>,
+}
+
+impl Request for UpdateRedemptionStatusesRequest {
+    type Response = Vec<CustomRewardRedemption>;
+
+    const PATH: &'static str = "channel_points/custom_rewards/redemptions";
+    const SCOPE: twitch_oauth2::Validator =
+        twitch_oauth2::validator![twitch_oauth2::Scope::ChannelManageRedemptions];
+}
+
+impl RequestPatch for UpdateRedemptionStatusesRequest {
+    type Body = UpdateRedemptionStatusBody;
+
+    fn parse_inner_response(
+        request: Option<Self>,
+        uri: &http::Uri,
+        response: &str,
+        status: http::StatusCode,
+    ) -> Result<Response<Self, Self::Response>, HelixRequestPatchError> {
+        let invalid = |reason| HelixRequestPatchError::InvalidResponse {
+            reason,
+            response: response.to_string(),
+            status,
+            uri: uri.clone(),
+        };
+        if status != http::StatusCode::OK {
+            return Err(invalid("unexpected status code"));
+        }
+
+        let updated: UpdatedRedemptions =
+            serde_json::from_str(response).map_err(|_| invalid("invalid redemptions"))?;
+        Ok(Response::with_data(updated.data, request))
+    }
+}
+
+/// Set the status of several redemptions of the same reward, 50 at a time
+/// Returns the redemptions that could not be updated along with the reason, or Err if the token
+/// could not be refreshed
+pub async fn update_redemption_statuses(
+    http_client: &reqwest::Client,
+    twitch: &mut UserToken,
+    reward_id: &str,
+    redemption_ids: &[String],
+    status: CustomRewardRedemptionStatus,
+) -> Result<Vec<(String, String)>, String> {
+    let client: HelixClient<reqwest::Client> = HelixClient::with_client(http_client.clone());
+    let mut failures = Vec::new();
+
+    for ids in redemption_ids.chunks(MAX_UPDATE_IDS) {
+        let mut refreshed = false;
+
+        loop {
+            let request = UpdateRedemptionStatusesRequest {
+                broadcaster_id: twitch.user_id.to_string(),
+                reward_id: reward_id.to_string(),
+                id: ids.to_vec(),
+            };
+            let body = UpdateRedemptionStatusBody::status(status);
+
+            let started = Instant::now();
+            let response = client.req_patch(request, body, &*twitch).await;
+            metrics::record_helix_latency("UpdateRedemptionStatus", started.elapsed());
+
+            match response {
+                Ok(response) => {
+                    // Redemptions that don't exist or aren't unfulfilled anymore are left out
+                    let updated = response
+                        .data
+                        .iter()
+                        .map(|r| r.id.as_str())
+                        .collect::<HashSet<_>>();
+                    for id in ids {
+                        if !updated.contains(id.as_str()) {
+                            failures.push((id.clone(), "Redemption is not unfulfilled".into()));
+                        }
+                    }
+                    info!("Set {} redemptions to {:?}", response.data.len(), status);
+                }
+                Err(e) => {
+                    // Only try refreshing once per batch, so a bad token can't loop forever
+                    if !refreshed && handle_token_error(http_client, &e, twitch).await? {
+                        refreshed = true;
+                        continue;
+                    }
+
+                    error!("Failed to update {} redemptions: {}", ids.len(), e);
+                    failures.extend(ids.iter().map(|id| (id.clone(), e.to_string())));
+                }
+            }
+
+            break;
+        }
+    }
+
+    Ok(failures)
+}
+
+/// Refund every unfulfilled redemption of the broadcaster's manageable rewards that is older than
+/// `max_age`
+/// Returns the number of redemptions that were refunded
+pub async fn refund_stale_redemptions(
+    http_client: &reqwest::Client,
+    twitch: &mut UserToken,
+    max_age: chrono::Duration,
+) -> Result<usize, String> {
+    let client: HelixClient<reqwest::Client> = HelixClient::with_client(http_client.clone());
+    let cutoff = chrono::Utc::now() - max_age;
+
+    let rewards = {
+        let request = GetCustomRewardRequest::broadcaster_id(twitch.user_id.clone())
+            .only_manageable_rewards(true);
+
+        let started = Instant::now();
+        let response = client.req_get(request.clone(), &*twitch).await;
+        metrics::record_helix_latency("GetCustomRewards", started.elapsed());
+
+        match response {
+            Ok(d) => d.data,
+            Err(e) => {
+                if handle_token_error(http_client, &e, twitch).await? {
+                    let started = Instant::now();
+                    let response = client.req_get(request, &*twitch).await;
+                    metrics::record_helix_latency("GetCustomRewards", started.elapsed());
+
+                    response
+                        .map_err(|e| format!("Failed to fetch custom rewards: {}", e))?
+                        .data
+                } else {
+                    return Err(format!("Failed to fetch custom rewards: {}", e));
+                }
+            }
+        }
+    };
+
+    let mut refunded = 0;
+
+    for reward in rewards {
+        let mut stale = Vec::new();
+        let mut after = None;
+
+        // Redemptions are sorted oldest first, so we can stop at the first one that's too new
+        'pages: loop {
+            let cursor = after.take();
+            let started = Instant::now();
+            let response = get_unfulfilled_redemptions(
+                http_client,
+                twitch,
+                reward.id.as_str(),
+                cursor.clone(),
+                None,
+            )
+            .await;
+            metrics::record_helix_latency("GetUnfulfilledRedemptions", started.elapsed());
+
+            let page = match response {
+                Ok(page) => page,
+                // The token can expire between pages as well, so retry once after refreshing it
+                Err(e) if handle_token_error(http_client, &e, twitch).await? => {
+                    let started = Instant::now();
+                    let response = get_unfulfilled_redemptions(
+                        http_client,
+                        twitch,
+                        reward.id.as_str(),
+                        cursor,
+                        None,
+                    )
+                    .await;
+                    metrics::record_helix_latency("GetUnfulfilledRedemptions", started.elapsed());
+
+                    response.map_err(|e| {
+                        format!("Failed to fetch redemptions for {}: {}", reward.title, e)
+                    })?
+                }
+                Err(e) => {
+                    return Err(format!(
+                        "Failed to fetch redemptions for {}: {}",
+                        reward.title, e
+                    ));
+                }
+            };
+
+            for redemption in page.data {
+                let redeemed_at =
+                    chrono::DateTime::parse_from_rfc3339(redemption.redeemed_at.as_str())
+                        .map_err(|e| format!("Invalid redemption timestamp: {}", e))?;
+
+                if redeemed_at >= cutoff {
+                    break 'pages;
+                }
+
+                stale.push(redemption.id.take());
+            }
+
+            match page.pagination {
+                Some(cursor) => after = Some(cursor.take()),
+                None => break,
+            }
+        }
+
+        if stale.is_empty() {
+            continue;
+        }
+
+        info!(
+            "Refunding {} stale redemptions for reward {}",
+            stale.len(),
+            reward.title
+        );
+        let failures = update_redemption_statuses(
+            http_client,
+            twitch,
+            reward.id.as_str(),
+            &stale,
+            CustomRewardRedemptionStatus::Canceled,
+        )
+        .await?;
+
+        refunded += stale.len() - failures.len();
+    }
+
+    Ok(refunded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_every_id_in_one_request() {
+        let request = UpdateRedemptionStatusesRequest {
+            broadcaster_id: "274637212".into(),
+            reward_id: "92af127c-7326-4483-a52b-b0da0be61c01".into(),
+            id: vec!["17fa2df1".into(), "9a1c2b3d".into()],
+        };
+
+        assert_eq!(
+            request.get_uri().unwrap().to_string(),
+            "https://api.twitch.tv/helix/channel_points/custom_rewards/redemptions?\
+             broadcaster_id=274637212&reward_id=92af127c-7326-4483-a52b-b0da0be61c01&\
+             id=17fa2df1&id=9a1c2b3d"
+        );
+    }
+}
@@ -0,0 +1,198 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use axum::extract::ws::Message;
+use log::{error, info};
+use tokio::sync::{Mutex, mpsc::Sender};
+use twitch_api::{
+    HelixClient,
+    helix::{
+        ClientRequestError,
+        points::{GetCustomRewardRequest, UpdateCustomRewardBody, UpdateCustomRewardRequest},
+    },
+    twitch_oauth2::UserToken,
+    types::RewardId,
+};
+
+use crate::{
+    metrics,
+    server::{send_error, send_task_response},
+    twitch::events::handle_token_error,
+};
+
+/// How often queued reward updates are sent to Twitch, changes made within this window are merged
+pub const REWARD_UPDATE_INTERVAL: Duration = Duration::from_millis(750);
+
+/// A single change to a custom reward, as requested by a rule
+#[derive(Debug, Clone, Copy)]
+pub enum RewardChange {
+    Paused(bool),
+    Enabled(bool),
+    Cost(u32),
+    CostDelta(i32),
+}
+
+#[derive(Debug, Default)]
+struct PendingRewardUpdate {
+    is_paused: Option<bool>,
+    is_enabled: Option<bool>,
+    cost: Option<u32>,
+    /// Only used when no absolute cost has been set, needs the current cost to be applied
+    cost_delta: i64,
+    /// Requests waiting on this update, answered once it has been sent
+    waiting: Vec<(i32, Sender<Message>)>,
+}
+
+impl PendingRewardUpdate {
+    fn apply(&mut self, change: RewardChange) {
+        match change {
+            RewardChange::Paused(paused) => self.is_paused = Some(paused),
+            RewardChange::Enabled(enabled) => self.is_enabled = Some(enabled),
+            RewardChange::Cost(cost) => {
+                self.cost = Some(cost);
+                self.cost_delta = 0;
+            }
+            RewardChange::CostDelta(delta) => match self.cost {
+                Some(cost) => self.cost = Some(adjust_cost(cost as i64, delta as i64)),
+                None => self.cost_delta += delta as i64,
+            },
+        }
+    }
+}
+
+/// Reward updates waiting to be sent, so that rapid toggles from rules only cost one Helix call
+#[derive(Debug, Default)]
+pub struct RewardUpdateQueue {
+    pending: HashMap<String, PendingRewardUpdate>,
+}
+
+impl RewardUpdateQueue {
+    pub fn push(
+        &mut self,
+        reward_id: String,
+        change: RewardChange,
+        request_id: i32,
+        tx: Sender<Message>,
+    ) {
+        let update = self.pending.entry(reward_id).or_default();
+        update.apply(change);
+        update.waiting.push((request_id, tx));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+/// Rewards have to cost at least 1 point
+fn adjust_cost(cost: i64, delta: i64) -> u32 {
+    (cost + delta).clamp(1, u32::MAX as i64) as u32
+}
+
+/// Send every queued reward update to Twitch, one request per reward
+/// Returns Err only if the token could not be refreshed, individual failures are reported to the
+/// requests waiting on them
+pub async fn flush_reward_updates(
+    http_client: &reqwest::Client,
+    twitch: &mut UserToken,
+    queue: &Mutex<RewardUpdateQueue>,
+) -> Result<(), String> {
+    let pending = std::mem::take(&mut queue.lock().await.pending);
+    let client: HelixClient<reqwest::Client> = HelixClient::with_client(http_client.clone());
+
+    let mut pending = pending.into_iter();
+
+    while let Some((reward_id, update)) = pending.next() {
+        let mut refreshed = false;
+
+        let result = loop {
+            match send_reward_update(&client, twitch, &reward_id, &update).await {
+                Ok(()) => break Ok(()),
+                Err(e) => {
+                    if !refreshed {
+                        match handle_token_error(http_client, &e, twitch).await {
+                            Ok(true) => {
+                                refreshed = true;
+                                continue;
+                            }
+                            Ok(false) => {}
+                            Err(token_error) => {
+                                // Nothing else can be sent without a token, so answer everyone
+                                // still waiting instead of leaving their tasks hanging
+                                let unsent = std::iter::once((reward_id, update)).chain(pending);
+                                for (_, update) in unsent {
+                                    for (request_id, tx) in update.waiting {
+                                        let _ = send_error(
+                                            &token_error,
+                                            "twitch_update_custom_reward",
+                                            &tx,
+                                            request_id,
+                                        )
+                                        .await;
+                                    }
+                                }
+                                return Err(token_error);
+                            }
+                        }
+                    }
+
+                    break Err(e);
+                }
+            }
+        };
+
+        match result {
+            Ok(()) => {
+                info!("Successfully updated custom reward {}", reward_id);
+                for (request_id, tx) in update.waiting {
+                    let _ = send_task_response(true, None, &tx, request_id).await;
+                }
+            }
+            Err(e) => {
+                error!("Failed to update custom reward {}: {}", reward_id, e);
+                for (request_id, tx) in update.waiting {
+                    let _ = send_error(&e, "twitch_update_custom_reward", &tx, request_id).await;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn send_reward_update(
+    client: &HelixClient<'static, reqwest::Client>,
+    twitch: &UserToken,
+    reward_id: &str,
+    update: &PendingRewardUpdate,
+) -> Result<(), ClientRequestError<reqwest::Error>> {
+    let mut body = UpdateCustomRewardBody::default();
+    body.is_paused = update.is_paused;
+    body.is_enabled = update.is_enabled;
+    body.cost = update.cost.map(|c| c as usize);
+
+    if body.cost.is_none() && update.cost_delta != 0 {
+        let ids = [RewardId::from(reward_id.to_string())];
+        let request = GetCustomRewardRequest::broadcaster_id(twitch.user_id.clone())
+            .only_manageable_rewards(true)
+            .ids(&ids[..]);
+
+        let started = Instant::now();
+        let response = client.req_get(request, twitch).await;
+        metrics::record_helix_latency("GetCustomRewards", started.elapsed());
+
+        if let Some(current) = response?.first() {
+            body.cost = Some(adjust_cost(current.cost as i64, update.cost_delta) as usize);
+        }
+    }
+
+    let request = UpdateCustomRewardRequest::new(twitch.user_id.clone(), reward_id.to_string());
+    let started = Instant::now();
+    let response = client.req_patch(request, body, twitch).await;
+    metrics::record_helix_latency("UpdateCustomReward", started.elapsed());
+    response?;
+
+    Ok(())
+}